@@ -0,0 +1,132 @@
+//! Compares twap recalculated from window entries on every update with one taken from cumulative prices, along with
+//! configurations that can't use cumulative prices alone: MAD cutoff and time weighted median.
+//!
+//! Run with `cargo bench`.
+
+// Service is a binary, so modules it calculates twap with are included directly, test imports of them are unused.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/accumulator.rs"]
+mod accumulator;
+#[path = "../src/aggregation.rs"]
+mod aggregation;
+#[path = "../src/aggregators.rs"]
+mod aggregators;
+#[path = "../src/attestation.rs"]
+mod attestation;
+#[path = "../src/signing.rs"]
+mod signing;
+#[path = "../src/storage.rs"]
+mod storage;
+
+use aggregation::{AggregationConfiguration, OutlierFilter};
+use aggregators::parse_aggregator;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use starknet::core::types::Felt;
+use std::{collections::BTreeMap, hint::black_box, time::Duration};
+use storage::{SpotEntryEvent, SpotEntryStorage};
+
+const PUBLISHERS: [&str; 3] = ["PRAGMA", "AVNU", "FOURLEAF"];
+
+/// Storage with an update from every publisher every second of `window` before `now`.
+fn storage(window: Duration, now: u64) -> SpotEntryStorage {
+    configured_storage(window, now, AggregationConfiguration::default())
+}
+
+fn configured_storage(window: Duration, now: u64, aggregation: AggregationConfiguration) -> SpotEntryStorage {
+    let capacity = window.as_secs() as usize;
+    let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, capacity, aggregation);
+
+    for timestamp in now - window.as_secs()..now {
+        for publisher in PUBLISHERS {
+            storage.append(event(timestamp, publisher));
+        }
+    }
+
+    storage.update_prices();
+    storage
+}
+
+fn event(timestamp: u64, publisher: &str) -> SpotEntryEvent {
+    SpotEntryEvent {
+        timestamp,
+        price: 100_000 + (timestamp % 1_000) as u128,
+        publisher: Felt::from_bytes_be_slice(publisher.as_bytes()),
+        ..Default::default()
+    }
+}
+
+/// Every iteration is a new second: entries of every publisher are appended and window ending at it is calculated.
+fn twap_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("twap_update");
+
+    for window in [Duration::from_secs(3600), Duration::from_secs(86400)] {
+        let start = 1_000_000;
+        let label = format!("{}s", window.as_secs());
+
+        let mut recalculated = storage(window, start);
+        let mut now = start;
+        group.bench_function(BenchmarkId::new("recalculated", &label), |b| {
+            b.iter(|| {
+                for publisher in PUBLISHERS {
+                    recalculated.append(event(now, publisher));
+                }
+                now += 1;
+                black_box(recalculated.recalculate(window, now - window.as_secs(), now))
+            })
+        });
+
+        let configurations = [
+            ("accumulated", AggregationConfiguration::default()),
+            (
+                "mad_cutoff",
+                AggregationConfiguration {
+                    outliers: OutlierFilter { mad_cutoff: Some(3.0), max_jump: Some(0.5), ..Default::default() },
+                    ..Default::default()
+                },
+            ),
+            (
+                "tw_median",
+                AggregationConfiguration {
+                    aggregators: BTreeMap::from([(window, parse_aggregator("tw-median").unwrap())]),
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        for (name, aggregation) in configurations {
+            let mut accumulated = configured_storage(window, start, aggregation);
+            let mut now = start;
+            group.bench_function(BenchmarkId::new(name, &label), |b| {
+                b.iter(|| {
+                    for publisher in PUBLISHERS {
+                        accumulated.append(event(now, publisher));
+                    }
+                    now += 1;
+                    accumulated.update_prices();
+                    black_box(accumulated.aggregate(window, now - window.as_secs(), now))
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+/// Twap of an arbitrary historical window of a day long series.
+fn historical_window(c: &mut Criterion) {
+    let now = 1_000_000;
+    let storage = storage(Duration::from_secs(86400), now);
+    let window = Duration::from_secs(3600);
+
+    c.bench_function("historical_window/recalculated", |b| {
+        b.iter(|| black_box(storage.recalculate(window, now - 43200, now - 39600)))
+    });
+
+    c.bench_function("historical_window/accumulated", |b| {
+        b.iter(|| black_box(storage.aggregate(window, now - 43200, now - 39600)))
+    });
+}
+
+criterion_group!(benches, twap_update, historical_window);
+criterion_main!(benches);
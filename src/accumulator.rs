@@ -0,0 +1,484 @@
+use crate::{
+    aggregation::{Activity, Interpolation, JumpReference, Rejections, Segment},
+    storage::SpotEntryEvent,
+};
+use num_bigint::BigUint;
+use starknet::core::types::Felt;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Bound,
+};
+
+/// Price of a second along with cumulative price and volume up to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Observation {
+    timestamp: u64,
+    price: u128,
+    volume: u128,
+    /// Doubled area under fixed point prices from the first observation till this one.
+    cumulative: BigUint,
+    /// Volume of observations from the first one till this one inclusive.
+    cumulative_volume: BigUint,
+    /// Prices multiplied by volumes from the first observation till this one inclusive.
+    cumulative_traded: BigUint,
+}
+
+/// Ordered series of prices with cumulative price-time accumulators, the way Uniswap oracle keeps observations.
+/// Area under prices between any two moments is a difference of two checkpoints, so time weighted average of any
+/// window, historical ones included, takes two binary searches instead of a pass over all its prices.
+///
+/// Prices are appended in order of time. Rewriting history is possible by truncating it and appending again, that is
+/// cheap as long as only recent prices change.
+///
+/// Activity quality gates are checked against is accumulated the same way: number of entries, seconds of every
+/// publisher and gaps between seconds with entries that are longer than `max_gap`.
+#[derive(Debug, Clone)]
+pub struct PriceAccumulator {
+    interpolation: Interpolation,
+    observations: VecDeque<Observation>,
+    /// Entries rejected before reduction by second, only seconds with rejections are kept.
+    rejections: BTreeMap<u64, Rejections>,
+    /// Every second with entries along with their number and number of entries from the first second till it.
+    samples: VecDeque<(u64, usize, usize)>,
+    /// Seconds every publisher submitted entries in.
+    publishers: BTreeMap<Felt, VecDeque<u64>>,
+    /// Gaps longer than `max_gap`: the previous second with entries by the second that ends the gap.
+    gaps: BTreeMap<u64, u64>,
+    max_gap: Option<u64>,
+}
+
+impl PriceAccumulator {
+    pub fn new(interpolation: Interpolation, max_gap: Option<u64>, capacity: usize) -> PriceAccumulator {
+        PriceAccumulator {
+            interpolation,
+            observations: VecDeque::with_capacity(capacity),
+            rejections: BTreeMap::new(),
+            samples: VecDeque::with_capacity(capacity),
+            publishers: BTreeMap::new(),
+            gaps: BTreeMap::new(),
+            max_gap,
+        }
+    }
+
+    /// Appends price and volume of the second along with its `entries` and those rejected in it. Prices at or after
+    /// `timestamp` are dropped first, so the series stays ordered. Seconds without accepted entries have no price.
+    pub fn push(
+        &mut self,
+        timestamp: u64,
+        entries: &[SpotEntryEvent],
+        second: Option<(u128, u128)>,
+        rejections: Rejections,
+    ) {
+        self.truncate(timestamp);
+
+        if rejections != Rejections::default() {
+            self.rejections.insert(timestamp, rejections);
+        }
+
+        if !entries.is_empty() {
+            self.record(timestamp, entries);
+        }
+
+        let Some((price, volume)) = second else {
+            return;
+        };
+
+        let traded = BigUint::from(price) * volume;
+        let (cumulative, cumulative_volume, cumulative_traded) = match self.observations.back() {
+            Some(last) => {
+                let previous = (last.timestamp, last.price);
+                let segment = self.interpolation.segment(last.timestamp, timestamp, |moment| {
+                    self.interpolation.value_at(previous, Some((timestamp, price)), moment)
+                });
+
+                (
+                    &last.cumulative + segment.double_area(),
+                    &last.cumulative_volume + volume,
+                    &last.cumulative_traded + traded,
+                )
+            }
+            None => (BigUint::ZERO, BigUint::from(volume), traded),
+        };
+
+        self.observations.push_back(Observation {
+            timestamp,
+            price,
+            volume,
+            cumulative,
+            cumulative_volume,
+            cumulative_traded,
+        });
+    }
+
+    /// Drops prices, rejections and activity at or after `timestamp`.
+    pub fn truncate(&mut self, timestamp: u64) {
+        while self.observations.back().is_some_and(|last| last.timestamp >= timestamp) {
+            self.observations.pop_back();
+        }
+
+        self.rejections.split_off(&timestamp);
+
+        while self.samples.back().is_some_and(|(last, ..)| *last >= timestamp) {
+            self.samples.pop_back();
+        }
+        for seconds in self.publishers.values_mut() {
+            while seconds.back().is_some_and(|last| *last >= timestamp) {
+                seconds.pop_back();
+            }
+        }
+        self.publishers.retain(|_, seconds| !seconds.is_empty());
+        self.gaps.split_off(&timestamp);
+    }
+
+    /// Drops prices, rejections and activity at or before `timestamp`. Checkpoints of remaining prices are left as they
+    /// are, only their differences matter.
+    pub fn clean_older_than(&mut self, timestamp: u64) {
+        while self.observations.front().is_some_and(|first| first.timestamp <= timestamp) {
+            self.observations.pop_front();
+        }
+
+        self.rejections = self.rejections.split_off(&timestamp.saturating_add(1));
+
+        while self.samples.front().is_some_and(|(first, ..)| *first <= timestamp) {
+            self.samples.pop_front();
+        }
+        for seconds in self.publishers.values_mut() {
+            while seconds.front().is_some_and(|first| *first <= timestamp) {
+                seconds.pop_front();
+            }
+        }
+        self.publishers.retain(|_, seconds| !seconds.is_empty());
+        self.gaps = self.gaps.split_off(&timestamp.saturating_add(1));
+    }
+
+    /// Reference jumps of the next second are checked against: the last price, along with seconds after it all entries
+    /// of which were rejected as jumps.
+    pub fn jump_reference(&self) -> JumpReference {
+        self.opening(u64::MAX).1
+    }
+
+    /// Opening price of window that starts at `timestamp`, that is the last price before it along with its timestamp,
+    /// and reference jumps in the window are checked against. Same as `AggregationConfiguration::opening` of entries
+    /// prices were reduced from, without a pass over them.
+    pub fn opening(&self, timestamp: u64) -> (Option<(u64, u128)>, JumpReference) {
+        let last = self.observations.partition_point(|observation| observation.timestamp < timestamp);
+        let last = last.checked_sub(1).map(|last| &self.observations[last]);
+
+        let after = last.map_or(0, |last| last.timestamp.saturating_add(1));
+        let rejected =
+            self.rejections.range(after..timestamp.max(after)).filter(|(_, rejections)| rejections.jump > 0).count();
+
+        (last.map(|last| (last.timestamp, last.price)), JumpReference { price: last.map(|last| last.price), rejected })
+    }
+
+    /// Prices from the last one before `start`, that is the opening price, till the last one at or before `end`.
+    /// Prices after the window are left out, so it is split into segments the same way it was when it ended.
+    pub fn prices(&self, start: u64, end: u64) -> Vec<(u64, u128)> {
+        let first = self.position(start).saturating_sub(1);
+        let last = self.position(end);
+
+        self.observations.range(first..last).map(|observation| (observation.timestamp, observation.price)).collect()
+    }
+
+    /// Segments of window from `start` to `end`.
+    pub fn segments(&self, start: u64, end: u64) -> Vec<Segment> {
+        self.interpolation.segments(&self.prices(start, end), start, end)
+    }
+
+    /// Time weighted average price of window from `start` to `end` as fixed point with 64 fractional bits. Window is
+    /// covered from the first price if it is later than `start`.
+    ///
+    /// Returns `None` if covered part of the window is empty.
+    pub fn twap(&self, start: u64, end: u64) -> Option<BigUint> {
+        let start = start.max(self.observations.front()?.timestamp);
+        if start >= end {
+            return None;
+        }
+
+        // Checkpoints of the first and the last prices inside of the window give area between them, parts before and
+        // after them are single segments. Result is exactly the same as the sum of window segments.
+        let first = self.observations.partition_point(|observation| observation.timestamp < start);
+        let last = self.position(end);
+
+        let double_area = if first < last {
+            let (first, last) = (&self.observations[first], &self.observations[last - 1]);
+
+            &last.cumulative - &first.cumulative +
+                self.double_area(start, first.timestamp) +
+                self.double_area(last.timestamp, end)
+        } else {
+            self.double_area(start, end)
+        };
+
+        Some(double_area / ((end - start) * 2))
+    }
+
+    /// Volume weighted average price of seconds from `start` to `end` inclusive as fixed point with 64 fractional bits.
+    /// Like `twap`, it is a difference of checkpoints of the first and the last prices inside of the window.
+    ///
+    /// Returns `None` if nothing was traded within the window.
+    pub fn vwap(&self, start: u64, end: u64) -> Option<BigUint> {
+        let first = self.observations.partition_point(|observation| observation.timestamp < start);
+        let last = self.position(end);
+        if first >= last {
+            return None;
+        }
+
+        let (first, last) = (&self.observations[first], &self.observations[last - 1]);
+        let volume = &last.cumulative_volume - &first.cumulative_volume + first.volume;
+        if volume == BigUint::ZERO {
+            return None;
+        }
+
+        let traded = &last.cumulative_traded - &first.cumulative_traded + BigUint::from(first.price) * first.volume;
+        Some((traded << 64) / volume)
+    }
+
+    /// Rejections in seconds from `start` to `end` inclusive.
+    pub fn rejections(&self, start: u64, end: u64) -> Rejections {
+        let mut rejections = Rejections::default();
+        for second in self.rejections.range(start..=end).map(|(_, rejections)| rejections) {
+            rejections.merge(second);
+        }
+
+        rejections
+    }
+
+    /// Activity of seconds from `start` to `end` inclusive. Gaps are taken from the ones longer than `max_gap`, so
+    /// shorter ones are not exact.
+    pub fn activity(&self, start: u64, end: u64) -> Activity {
+        let first = self.samples.partition_point(|(timestamp, ..)| *timestamp < start);
+        let last = self.samples.partition_point(|(timestamp, ..)| *timestamp <= end);
+        let within = (first < last).then(|| (&self.samples[first], &self.samples[last - 1]));
+
+        let samples = within.map_or(0, |(first, last)| last.2 - first.2 + first.1);
+
+        let publishers = self
+            .publishers
+            .values()
+            .filter(|seconds| seconds.get(seconds.partition_point(|second| *second < start)).is_some_and(|s| *s <= end))
+            .count();
+
+        let edges = match within {
+            Some((first, last)) => (first.0 - start).max(end.saturating_sub(last.0)),
+            None => end.saturating_sub(start),
+        };
+        let gap = self
+            .gaps
+            .range((Bound::Excluded(start), Bound::Included(end.max(start))))
+            .map(|(timestamp, previous)| timestamp - previous.max(&start))
+            .fold(edges, u64::max);
+
+        Activity { samples, publishers, gap, first: within.map(|(first, _)| first.0), opening: first > 0 }
+    }
+
+    /// Records number of entries of the second, their publishers and gap since the previous second with entries.
+    fn record(&mut self, timestamp: u64, entries: &[SpotEntryEvent]) {
+        let (previous, _, samples) = self.samples.back().copied().unwrap_or((timestamp, 0, 0));
+        self.samples.push_back((timestamp, entries.len(), samples + entries.len()));
+
+        if self.max_gap.is_some_and(|max| timestamp - previous > max) {
+            self.gaps.insert(timestamp, previous);
+        }
+
+        for entry in entries {
+            let seconds = self.publishers.entry(entry.publisher).or_default();
+            if seconds.back() != Some(&timestamp) {
+                seconds.push_back(timestamp);
+            }
+        }
+    }
+
+    /// Number of prices at or before `timestamp`, that is position of the first price after it.
+    fn position(&self, timestamp: u64) -> usize {
+        self.observations.partition_point(|observation| observation.timestamp <= timestamp)
+    }
+
+    /// Doubled area of a segment from `from` to `to` without prices strictly inside of it. `from` should not be
+    /// earlier than the first price. Prices after `to` are not seen, so the previous price holds till it.
+    fn double_area(&self, from: u64, to: u64) -> BigUint {
+        if from >= to {
+            return BigUint::ZERO;
+        }
+
+        let value_at = |timestamp| {
+            let next = self.position(timestamp);
+            let previous = &self.observations[next - 1];
+            let next = self.observations.get(next).filter(|next| next.timestamp <= to);
+            let next = next.map(|next| (next.timestamp, next.price));
+
+            self.interpolation.value_at((previous.timestamp, previous.price), next, timestamp)
+        };
+
+        self.interpolation.segment(from, to, value_at).double_area()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aggregators::{Aggregator, Twap};
+    use rand::prelude::*;
+
+    fn accumulator(interpolation: Interpolation, prices: &[(u64, u128)]) -> PriceAccumulator {
+        let mut accumulator = PriceAccumulator::new(interpolation, None, prices.len());
+        for (timestamp, price) in prices {
+            accumulator.push(*timestamp, &[], Some((*price, 0)), Rejections::default());
+        }
+
+        accumulator
+    }
+
+    #[test]
+    fn twap_matches_sum_of_segments() {
+        let mut rng = rand::rng();
+
+        let mut prices = Vec::new();
+        let mut timestamp = 1_000;
+        for _ in 0..200 {
+            timestamp += rng.random_range(1..60);
+            prices.push((timestamp, rng.random_range(1..1_000_000_000_u128)));
+        }
+
+        for interpolation in [Interpolation::Left, Interpolation::Right, Interpolation::Linear] {
+            let accumulator = accumulator(interpolation, &prices);
+
+            for _ in 0..200 {
+                let start = rng.random_range(0..timestamp + 100);
+                let end = rng.random_range(start + 1..timestamp + 200);
+
+                // Prices after the window are not known when it ends
+                let known: Vec<(u64, u128)> = prices.iter().filter(|(update, _)| *update <= end).copied().collect();
+
+                let expected = Twap.aggregate(&interpolation.segments(&known, start, end));
+                assert_eq!(accumulator.twap(start, end), expected, "{interpolation} [{start}, {end}]");
+                assert_eq!(accumulator.segments(start, end), interpolation.segments(&known, start, end));
+            }
+        }
+    }
+
+    #[test]
+    fn history_is_rewritten_from_truncated_point() {
+        let mut accumulator = accumulator(Interpolation::Left, &[(100, 10), (200, 30), (300, 50)]);
+
+        // Window [100, 400]: 10 * 100 + 30 * 100 + 50 * 100 = 9000 over 300 seconds
+        assert_eq!(accumulator.twap(100, 400), Some(BigUint::from(30_u8) << 64));
+
+        accumulator.push(200, &[], Some((20, 0)), Rejections::default());
+
+        // Price at 300 is dropped with the one at 200: 10 * 100 + 20 * 200 = 5000 over 300 seconds
+        assert_eq!(accumulator.prices(0, 400), vec![(100, 10), (200, 20)]);
+        assert_eq!(accumulator.twap(100, 400), Some((BigUint::from(50_u8) << 64) / 3_u8));
+    }
+
+    #[test]
+    fn cleaning_keeps_checkpoints_of_remaining_prices() {
+        let mut accumulator = accumulator(Interpolation::Linear, &[(100, 10), (200, 30), (300, 50), (400, 10)]);
+        let expected = accumulator.twap(250, 500);
+
+        accumulator.clean_older_than(200);
+
+        assert_eq!(accumulator.prices(0, 1_000), vec![(300, 50), (400, 10)]);
+        // Opening price of the window is gone, so it is covered from 300 now
+        assert_ne!(accumulator.twap(250, 500), expected);
+        assert_eq!(accumulator.twap(300, 500), Some(BigUint::from(20_u8) << 64));
+    }
+
+    #[test]
+    fn vwap_matches_weighted_sum_of_seconds() {
+        let seconds = [(100, 10, 1), (200, 30, 0), (300, 50, 3), (400, 20, 1)];
+        let mut accumulator = PriceAccumulator::new(Interpolation::Left, None, seconds.len());
+        for (timestamp, price, volume) in seconds {
+            accumulator.push(timestamp, &[], Some((price, volume)), Rejections::default());
+        }
+
+        // (10 * 1 + 50 * 3 + 20 * 1) / 5
+        assert_eq!(accumulator.vwap(100, 400), Some(BigUint::from(36_u8) << 64));
+        // Bounds are inclusive, the first second of the window is weighted as well: (50 * 3 + 20 * 1) / 4
+        assert_eq!(accumulator.vwap(300, 1_000), Some((BigUint::from(170_u8) << 64) / 4_u8));
+
+        accumulator.clean_older_than(100);
+        assert_eq!(accumulator.vwap(0, 300), Some(BigUint::from(50_u8) << 64));
+        // Nothing was traded at 200
+        assert_eq!(accumulator.vwap(150, 250), None);
+        assert_eq!(accumulator.vwap(500, 600), None);
+    }
+
+    #[test]
+    fn opening_is_the_last_price_before_window() {
+        let mut accumulator = accumulator(Interpolation::Left, &[(100, 10)]);
+        for timestamp in [150, 160] {
+            accumulator.push(timestamp, &[], None, Rejections { jump: 1, ..Default::default() });
+        }
+        accumulator.push(200, &[], Some((20, 0)), Rejections::default());
+
+        assert_eq!(accumulator.opening(100), (None, JumpReference::default()));
+        // Seconds rejected as jumps before the window are carried into it
+        assert_eq!(accumulator.opening(160), (Some((100, 10)), JumpReference { price: Some(10), rejected: 1 }));
+        assert_eq!(accumulator.opening(200), (Some((100, 10)), JumpReference { price: Some(10), rejected: 2 }));
+        assert_eq!(accumulator.opening(300), (Some((200, 20)), JumpReference { price: Some(20), rejected: 0 }));
+    }
+
+    #[test]
+    fn activity_matches_counted_entries() {
+        let mut rng = rand::rng();
+        let max_gap = 30;
+
+        let mut seconds = Vec::new();
+        let mut timestamp = 1_000;
+        for _ in 0..300 {
+            timestamp += rng.random_range(1..60);
+            let entries: Vec<SpotEntryEvent> = (0..rng.random_range(1..4))
+                .map(|_| SpotEntryEvent {
+                    timestamp,
+                    publisher: Felt::from(rng.random_range(0..5_u8)),
+                    ..Default::default()
+                })
+                .collect();
+            seconds.push((timestamp, entries));
+        }
+
+        let mut accumulator = PriceAccumulator::new(Interpolation::Left, Some(max_gap), seconds.len());
+        for (timestamp, entries) in &seconds {
+            accumulator.push(*timestamp, entries, Some((100, 0)), Rejections::default());
+        }
+        let cleaned = seconds[50].0;
+        accumulator.clean_older_than(cleaned);
+        seconds.retain(|(timestamp, _)| *timestamp > cleaned);
+
+        for _ in 0..300 {
+            let start = rng.random_range(0..timestamp + 100);
+            let end = rng.random_range(start..timestamp + 200);
+
+            let within = seconds.iter().filter(|(timestamp, _)| (start..=end).contains(timestamp));
+            let opening = seconds.first().is_some_and(|(first, _)| *first < start);
+            let expected = Activity::of(within.map(|(timestamp, entries)| (*timestamp, entries)), opening, start, end);
+
+            let activity = accumulator.activity(start, end);
+            assert_eq!(Activity { gap: expected.gap, ..activity.clone() }, expected, "[{start}, {end}]");
+            if expected.gap > max_gap {
+                assert_eq!(activity.gap, expected.gap, "[{start}, {end}]");
+            } else {
+                assert!(activity.gap <= max_gap, "[{start}, {end}]");
+            }
+        }
+
+        // Empty window is a single second
+        let (last, entries) = seconds.last().unwrap();
+        assert_eq!(accumulator.activity(*last, *last).samples, entries.len());
+    }
+
+    #[test]
+    fn rejections_are_summed_within_range() {
+        let mut accumulator = PriceAccumulator::new(Interpolation::Left, None, 3);
+        for timestamp in [100, 200, 300] {
+            accumulator.push(timestamp, &[], None, Rejections { jump: 1, ..Default::default() });
+        }
+
+        assert_eq!(accumulator.rejections(150, 300).jump, 2);
+        assert_eq!(accumulator.jump_reference(), JumpReference { price: None, rejected: 3 });
+
+        accumulator.truncate(300);
+        assert_eq!(accumulator.rejections(0, 1_000).jump, 2);
+    }
+}
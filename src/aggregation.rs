@@ -0,0 +1,802 @@
+use crate::{
+    aggregators::{Aggregator, Twap},
+    storage::SpotEntryEvent,
+};
+use num_bigint::BigUint;
+use starknet::core::types::Felt;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+/// Combines entries of different publishers and sources submitted within the same second into a single price.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reducer {
+    /// Median price, mean of two middle ones for even number of entries.
+    Median,
+    Mean,
+    /// Mean weighted by publisher weight, publishers without configured weight have weight of 1.
+    PublisherWeighted(HashMap<Felt, u64>),
+}
+
+impl Reducer {
+    /// Reduces entries to a single price. Returns `None` if there are no entries or all of them have zero weight.
+    pub fn reduce(&self, entries: &[SpotEntryEvent]) -> Option<u128> {
+        match self {
+            Reducer::Median => median(entries.iter().map(|entry| entry.price).collect()),
+            Reducer::Mean => weighted_mean(entries.iter().map(|entry| (entry.price, 1))),
+            Reducer::PublisherWeighted(weights) => weighted_mean(
+                entries.iter().map(|entry| (entry.price, weights.get(&entry.publisher).cloned().unwrap_or(1))),
+            ),
+        }
+    }
+}
+
+impl FromStr for Reducer {
+    type Err = String;
+
+    /// Parses reducer name. Weights of `publisher_weighted` reducer are configured separately, they are empty here.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "median" => Ok(Reducer::Median),
+            "mean" => Ok(Reducer::Mean),
+            "publisher_weighted" => Ok(Reducer::PublisherWeighted(HashMap::new())),
+            _ => Err(format!("Unknown reducer: {value:?}")),
+        }
+    }
+}
+
+/// How price behaves between two updates when it is weighted by time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Left-continuous step, price holds until the next update.
+    Left,
+    /// Right-continuous step, price of an update holds since the previous one.
+    Right,
+    /// Price changes linearly between updates.
+    Linear,
+}
+
+/// Piece of a window with price that either holds or changes linearly. Values are fixed point with 64 fractional bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub from: u64,
+    pub to: u64,
+    pub from_value: BigUint,
+    pub to_value: BigUint,
+}
+
+impl Segment {
+    pub fn duration(&self) -> u64 {
+        self.to - self.from
+    }
+
+    /// Mean value over the segment, exact for both steps and linear segments.
+    pub fn mean_value(&self) -> BigUint {
+        (&self.from_value + &self.to_value) / 2_u8
+    }
+
+    /// Area under the segment doubled, so it is exact for linear segments too.
+    pub fn double_area(&self) -> BigUint {
+        (&self.from_value + &self.to_value) * self.duration()
+    }
+}
+
+impl Interpolation {
+    /// Splits window from `start` to `end` inclusive into segments between price updates. `prices` should be ordered
+    /// by timestamp, ones outside of the window give prices at its bounds.
+    ///
+    /// Window is anchored to opening price, the last one before window start, so the whole window is covered.
+    /// Without it the window is covered from the first update only. After the last update its price holds.
+    ///
+    /// Returns no segments if covered part of the window is empty.
+    pub fn segments(&self, prices: &[(u64, u128)], start: u64, end: u64) -> Vec<Segment> {
+        let Some(from) = prices.first().map(|(timestamp, _)| start.max(*timestamp)).filter(|from| *from < end) else {
+            return Vec::new();
+        };
+
+        let value_at = |timestamp: u64| {
+            let next = prices.partition_point(|(update, _)| *update <= timestamp);
+            self.value_at(prices[next - 1], prices.get(next).cloned(), timestamp)
+        };
+
+        let mut bounds = vec![from];
+        bounds.extend(
+            prices.iter().map(|(timestamp, _)| *timestamp).filter(|timestamp| *timestamp > from && *timestamp < end),
+        );
+        bounds.push(end);
+
+        bounds.windows(2).map(|pair| self.segment(pair[0], pair[1], value_at)).collect()
+    }
+
+    /// Segment from `from` to `to` without updates strictly inside of it. `value_at` gives price at any moment.
+    pub fn segment(&self, from: u64, to: u64, value_at: impl Fn(u64) -> BigUint) -> Segment {
+        let (from_value, to_value) = match self {
+            Interpolation::Left => (value_at(from), value_at(from)),
+            Interpolation::Right => (value_at(to), value_at(to)),
+            Interpolation::Linear => (value_at(from), value_at(to)),
+        };
+
+        Segment { from, to, from_value, to_value }
+    }
+
+    /// Fixed point price at `timestamp` given `previous` update at or before it and `next` one after it. Without next
+    /// update previous price holds.
+    pub fn value_at(&self, previous: (u64, u128), next: Option<(u64, u128)>, timestamp: u64) -> BigUint {
+        let previous_value = BigUint::from(previous.1) << 64;
+
+        match (self, next) {
+            _ if previous.0 == timestamp => previous_value,
+            (Interpolation::Left, _) | (_, None) => previous_value,
+            (Interpolation::Right, Some((_, price))) => BigUint::from(price) << 64,
+            (Interpolation::Linear, Some((next_timestamp, price))) => {
+                (previous_value * (next_timestamp - timestamp) +
+                    (BigUint::from(price) << 64) * (timestamp - previous.0)) /
+                    (next_timestamp - previous.0)
+            }
+        }
+    }
+}
+
+impl Display for Interpolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interpolation::Left => write!(f, "left"),
+            Interpolation::Right => write!(f, "right"),
+            Interpolation::Linear => write!(f, "linear"),
+        }
+    }
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "left" => Ok(Interpolation::Left),
+            "right" => Ok(Interpolation::Right),
+            "linear" => Ok(Interpolation::Linear),
+            _ => Err(format!("Unknown interpolation: {value:?}")),
+        }
+    }
+}
+
+/// Publishers that should submit entries in the same second to tell which of them deviates. With two of them it is
+/// not clear which one is off.
+const MIN_DEVIATION_PUBLISHERS: usize = 3;
+
+/// Seconds in a row all entries of which are rejected as jumps, after which price of the last one is accepted as is.
+/// Lasting move of the price is followed then, rather than freezing it at the price before the move.
+const MAX_JUMP_REJECTIONS: usize = 3;
+
+/// Price jumps are checked against, it is carried from second to second.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JumpReference {
+    /// The last accepted price.
+    pub price: Option<u128>,
+    /// Seconds in a row since that price all entries of which were rejected as jumps.
+    pub rejected: usize,
+}
+
+/// Limits entries are checked against before they are reduced, every limit is optional. Deviations are relative,
+/// e.g. `0.1` is 10%.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OutlierFilter {
+    /// Entry is rejected if its distance to median of all entries in the window is greater than median absolute
+    /// deviation multiplied by cutoff. Median depends on every entry of the window, so cumulative prices can't be used
+    /// with it: every window is recalculated from its entries on every update, in time linear to their number.
+    pub mad_cutoff: Option<f64>,
+    /// Entry is rejected if it deviates from previous accepted price more than that. Price is accepted as is once
+    /// `MAX_JUMP_REJECTIONS` seconds in a row were rejected.
+    pub max_jump: Option<f64>,
+    /// Entry is rejected if it deviates from median of entries of other publishers in the same second more than that.
+    /// Seconds with fewer than `MIN_DEVIATION_PUBLISHERS` publishers are not filtered.
+    pub max_publisher_deviation: Option<f64>,
+}
+
+/// Number of entries rejected by every filter, along with number of rejected entries per publisher.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Rejections {
+    pub mad: u64,
+    pub jump: u64,
+    pub publisher_deviation: u64,
+    pub publishers: BTreeMap<Felt, u64>,
+}
+
+impl Rejections {
+    /// Adds counters of `other` to these ones.
+    pub fn merge(&mut self, other: &Rejections) {
+        self.mad += other.mad;
+        self.jump += other.jump;
+        self.publisher_deviation += other.publisher_deviation;
+
+        for (publisher, count) in other.publishers.iter() {
+            *self.publishers.entry(*publisher).or_default() += count;
+        }
+    }
+
+    fn reject(&mut self, entry: &SpotEntryEvent, counter: fn(&mut Rejections) -> &mut u64) {
+        *counter(self) += 1;
+        *self.publishers.entry(entry.publisher).or_default() += 1;
+    }
+}
+
+/// Minimal quality of data within a window for its value to be signed, every gate is optional.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QualityGates {
+    /// Entries of every publisher and source submitted within the window.
+    pub min_samples: Option<usize>,
+    /// Distinct publishers that submitted entries within the window.
+    pub min_publishers: Option<usize>,
+    /// Longest time in seconds without entries within the window, including its start and end.
+    pub max_gap: Option<u64>,
+    /// Fraction of the window there is a price for, e.g. `0.9`. Window with opening price is covered entirely.
+    pub min_coverage: Option<f64>,
+}
+
+/// Entries submitted within a window, quality gates are checked against it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Activity {
+    /// Entries of every publisher and source, rejected ones included.
+    pub samples: usize,
+    /// Distinct publishers that submitted entries.
+    pub publishers: usize,
+    /// Longest time in seconds without entries, including start and end of the window. It is exact only if it is
+    /// longer than `QualityGates::max_gap`, gaps it passes with don't matter.
+    pub gap: u64,
+    /// The first second with entries.
+    pub first: Option<u64>,
+    /// Whether there are entries before the window, they give its opening price.
+    pub opening: bool,
+}
+
+impl Activity {
+    /// Activity of `seconds` within window from `start` to `end`, they should be ordered by timestamp. Seconds without
+    /// entries are skipped.
+    pub fn of<'a>(
+        seconds: impl IntoIterator<Item = (u64, impl IntoIterator<Item = &'a SpotEntryEvent>)>,
+        opening: bool,
+        start: u64,
+        end: u64,
+    ) -> Activity {
+        let mut activity = Activity { opening, ..Default::default() };
+        let mut publishers = HashSet::new();
+        let mut previous = start;
+
+        for (timestamp, entries) in seconds {
+            let samples = entries.into_iter().inspect(|entry| _ = publishers.insert(entry.publisher)).count();
+            if samples == 0 {
+                continue;
+            }
+
+            activity.samples += samples;
+            activity.gap = activity.gap.max(timestamp.saturating_sub(previous));
+            activity.first.get_or_insert(timestamp);
+            previous = timestamp;
+        }
+
+        activity.publishers = publishers.len();
+        activity.gap = activity.gap.max(end.saturating_sub(previous));
+        activity
+    }
+}
+
+/// Quality gate window has failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InsufficientData {
+    Samples { samples: usize, min: usize },
+    Publishers { publishers: usize, min: usize },
+    Gap { gap: u64, max: u64 },
+    Coverage { coverage: f64, min: f64 },
+}
+
+impl Display for InsufficientData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InsufficientData::Samples { samples, min } => write!(f, "{samples} samples, at least {min} required"),
+            InsufficientData::Publishers { publishers, min } => {
+                write!(f, "{publishers} publishers, at least {min} required")
+            }
+            InsufficientData::Gap { gap, max } => write!(f, "{gap}s without updates, at most {max}s allowed"),
+            InsufficientData::Coverage { coverage, min } => {
+                write!(f, "{coverage:.3} of the window covered, at least {min} required")
+            }
+        }
+    }
+}
+
+impl QualityGates {
+    /// Checks `activity` of window from `start` to `end`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the first gate window has failed.
+    pub fn check(&self, activity: &Activity, start: u64, end: u64) -> Result<(), InsufficientData> {
+        if let Some(min) = self.min_samples &&
+            activity.samples < min
+        {
+            return Err(InsufficientData::Samples { samples: activity.samples, min });
+        }
+
+        if let Some(min) = self.min_publishers &&
+            activity.publishers < min
+        {
+            return Err(InsufficientData::Publishers { publishers: activity.publishers, min });
+        }
+
+        if let Some(max) = self.max_gap &&
+            activity.gap > max
+        {
+            return Err(InsufficientData::Gap { gap: activity.gap, max });
+        }
+
+        if let Some(min) = self.min_coverage &&
+            end > start
+        {
+            let covered_from = match activity.opening {
+                true => start,
+                false => activity.first.unwrap_or(end),
+            };
+
+            let coverage = (end - covered_from.max(start)) as f64 / (end - start) as f64;
+            if coverage < min {
+                return Err(InsufficientData::Coverage { coverage, min });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How entries of a pair are turned into the price series and how that series is aggregated into a single value.
+#[derive(Debug, Clone)]
+pub struct AggregationConfiguration {
+    pub reducer: Reducer,
+    pub outliers: OutlierFilter,
+    pub interpolation: Interpolation,
+    pub quality: QualityGates,
+    /// Aggregator of every window, windows without one use arithmetic TWAP.
+    pub aggregators: BTreeMap<Duration, Arc<dyn Aggregator>>,
+}
+
+impl Default for AggregationConfiguration {
+    fn default() -> Self {
+        AggregationConfiguration {
+            reducer: Reducer::Median,
+            outliers: OutlierFilter::default(),
+            interpolation: Interpolation::Left,
+            quality: QualityGates::default(),
+            aggregators: BTreeMap::new(),
+        }
+    }
+}
+
+impl AggregationConfiguration {
+    pub fn aggregator(&self, window: Duration) -> &dyn Aggregator {
+        self.aggregators.get(&window).map(|aggregator| aggregator.as_ref()).unwrap_or(&Twap)
+    }
+
+    /// Name of the algorithm value of the window is calculated with: aggregator and interpolation, e.g. `twap/left`.
+    /// It is a part of signed attestation.
+    pub fn algorithm(&self, window: Duration) -> String {
+        format!("{}/{}", self.aggregator(window).name(), self.interpolation)
+    }
+
+    /// Opening price of window that starts after `history`, that is the last accepted price along with its timestamp,
+    /// and reference jumps in the window are checked against. Entries are filtered the same way as in `prices`,
+    /// without median absolute deviation. `history` should be ordered by timestamp.
+    ///
+    /// Without jump filter it is enough to find the last second with a price, otherwise reference depends on every
+    /// accepted price before, so whole history is reduced.
+    pub fn opening(&self, history: &[(u64, &[SpotEntryEvent])]) -> (Option<(u64, u128)>, JumpReference) {
+        let mut reference = JumpReference::default();
+
+        if self.outliers.max_jump.is_none() {
+            let opening = history.iter().rev().find_map(|(timestamp, second)| {
+                let second = self.reduce_second(second.iter().collect(), &mut reference, &mut Rejections::default());
+                second.map(|(price, _)| (*timestamp, price))
+            });
+
+            return (opening, reference);
+        }
+
+        let mut opening = None;
+        for (timestamp, second) in history {
+            let second = self.reduce_second(second.iter().collect(), &mut reference, &mut Rejections::default());
+            opening = second.map(|(price, _)| (*timestamp, price)).or(opening);
+        }
+
+        (opening, reference)
+    }
+
+    /// Filters out outliers and reduces entries of every second to a single price along with volume of accepted
+    /// entries, jumps are checked against `reference` to begin with. `entries` should be ordered by timestamp,
+    /// resulting `(timestamp, price, volume)` are ordered the same way.
+    ///
+    /// Filters are applied in the following order:
+    /// 1. Median absolute deviation over all entries
+    /// 2. Deviation from median of the same second
+    /// 3. Jump from previous accepted price
+    pub fn prices(
+        &self,
+        entries: &[(u64, &[SpotEntryEvent])],
+        mut reference: JumpReference,
+    ) -> (Vec<(u64, u128, u128)>, Rejections) {
+        let mut rejections = Rejections::default();
+
+        let mut entries: Vec<(u64, Vec<&SpotEntryEvent>)> =
+            entries.iter().map(|(timestamp, entries)| (*timestamp, entries.iter().collect())).collect();
+
+        if let Some(cutoff) = self.outliers.mad_cutoff {
+            let window_median = median(entries.iter().flat_map(|(_, e)| e.iter().map(|entry| entry.price)).collect());
+            let mad = window_median.and_then(|m| {
+                median(entries.iter().flat_map(|(_, e)| e.iter().map(|entry| entry.price.abs_diff(m))).collect())
+            });
+
+            // Zero deviation means most entries are equal, any other value would be cut off then.
+            if let (Some(window_median), Some(mad)) = (window_median, mad.filter(|mad| *mad > 0)) {
+                for (_, second) in entries.iter_mut() {
+                    second.retain(|entry| {
+                        let accepted = entry.price.abs_diff(window_median) as f64 <= mad as f64 * cutoff;
+                        if !accepted {
+                            rejections.reject(entry, |r| &mut r.mad);
+                        }
+                        accepted
+                    });
+                }
+            }
+        }
+
+        let mut prices = Vec::new();
+        for (timestamp, second) in entries {
+            if let Some((price, volume)) = self.reduce_second(second, &mut reference, &mut rejections) {
+                prices.push((timestamp, price, volume));
+            }
+        }
+
+        (prices, rejections)
+    }
+
+    /// Filters entries of a single second by deviation from each other and by jump from `reference`, then reduces
+    /// accepted ones into a single price and updates reference with it. Returns the price along with volume traded by
+    /// accepted entries. Rejected entries are counted into `rejections`.
+    pub fn reduce_second(
+        &self,
+        mut second: Vec<&SpotEntryEvent>,
+        reference: &mut JumpReference,
+        rejections: &mut Rejections,
+    ) -> Option<(u128, u128)> {
+        let publishers = second.iter().map(|entry| entry.publisher).collect::<HashSet<_>>();
+        if let Some(limit) = self.outliers.max_publisher_deviation &&
+            publishers.len() >= MIN_DEVIATION_PUBLISHERS
+        {
+            // Entry is compared with others only, so it doesn't pull median towards itself. Any price between two
+            // middle ones is median of even number of others, the closest one to entry is taken.
+            let (accepted, rejected): (Vec<&SpotEntryEvent>, Vec<&SpotEntryEvent>) =
+                second.iter().copied().partition(|entry| {
+                    let others = second.iter().filter(|other| other.publisher != entry.publisher);
+                    median_bounds(others.map(|other| other.price).collect()).is_none_or(|(low, high)| {
+                        relative_deviation(entry.price, entry.price.clamp(low, high)) <= limit
+                    })
+                });
+
+            for entry in rejected {
+                rejections.reject(entry, |r| &mut r.publisher_deviation);
+            }
+            second = accepted;
+        }
+
+        if let (Some(limit), Some(last_price)) = (self.outliers.max_jump, reference.price) {
+            let (accepted, rejected): (Vec<&SpotEntryEvent>, Vec<&SpotEntryEvent>) =
+                second.iter().copied().partition(|entry| relative_deviation(entry.price, last_price) <= limit);
+
+            if accepted.is_empty() && !rejected.is_empty() {
+                reference.rejected += 1;
+            }
+
+            // Price that held long enough is the new reference, its entries are not rejected.
+            if reference.rejected < MAX_JUMP_REJECTIONS {
+                for entry in rejected {
+                    rejections.reject(entry, |r| &mut r.jump);
+                }
+                second = accepted;
+            }
+        }
+
+        let second: Vec<SpotEntryEvent> = second.into_iter().cloned().collect();
+        let price = self.reducer.reduce(&second)?;
+        *reference = JumpReference { price: Some(price), rejected: 0 };
+
+        Some((price, second.iter().fold(0_u128, |volume, entry| volume.saturating_add(entry.volume))))
+    }
+}
+
+/// Median price, mean of two middle ones for even number of prices.
+fn median(prices: Vec<u128>) -> Option<u128> {
+    median_bounds(prices).and_then(|(low, high)| weighted_mean([(low, 1), (high, 1)]))
+}
+
+/// Bounds of median: the middle price, or two middle ones for even number of prices.
+fn median_bounds(mut prices: Vec<u128>) -> Option<(u128, u128)> {
+    prices.sort();
+
+    let middle = prices.len() / 2;
+    match prices.len() {
+        0 => None,
+        len if len % 2 == 1 => Some((prices[middle], prices[middle])),
+        _ => Some((prices[middle - 1], prices[middle])),
+    }
+}
+
+/// Distance between price and reference price relative to reference one. Zero reference price gives no deviation, as
+/// there is nothing to compare with.
+fn relative_deviation(price: u128, reference: u128) -> f64 {
+    if reference == 0 {
+        return 0.0;
+    }
+
+    price.abs_diff(reference) as f64 / reference as f64
+}
+
+/// Sum of prices multiplied by weights divided by total weight. Big integers are used, so sum can't overflow.
+fn weighted_mean(prices: impl IntoIterator<Item = (u128, u64)>) -> Option<u128> {
+    let mut numerator = BigUint::ZERO;
+    let mut divisor = 0_u128;
+
+    for (price, weight) in prices {
+        numerator += BigUint::from(price) * weight;
+        divisor += u128::from(weight);
+    }
+
+    if divisor == 0 {
+        return None;
+    }
+
+    (numerator / divisor).try_into().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(prices: &[(u128, &str)]) -> Vec<SpotEntryEvent> {
+        prices
+            .iter()
+            .map(|(price, publisher)| SpotEntryEvent {
+                price: *price,
+                publisher: Felt::from_bytes_be_slice(publisher.as_bytes()),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn median_reducer() {
+        assert_eq!(Reducer::Median.reduce(&[]), None);
+        assert_eq!(Reducer::Median.reduce(&entries(&[(300, "A"), (100, "B"), (200, "C")])), Some(200));
+        assert_eq!(Reducer::Median.reduce(&entries(&[(400, "A"), (100, "B"), (200, "C"), (1_000, "D")])), Some(300));
+    }
+
+    #[test]
+    fn mean_reducer() {
+        assert_eq!(Reducer::Mean.reduce(&[]), None);
+        assert_eq!(Reducer::Mean.reduce(&entries(&[(100, "A"), (200, "B"), (600, "C")])), Some(300));
+        assert_eq!(Reducer::Mean.reduce(&entries(&[(u128::MAX, "A"), (u128::MAX, "B")])), Some(u128::MAX));
+    }
+
+    #[test]
+    fn publisher_weighted_reducer() {
+        let weights = HashMap::from([
+            (Felt::from_bytes_be_slice("A".as_bytes()), 3),
+            (Felt::from_bytes_be_slice("C".as_bytes()), 0),
+        ]);
+        let reducer = Reducer::PublisherWeighted(weights);
+
+        // (100 * 3 + 500 * 1 + 1_000_000 * 0) / 4
+        assert_eq!(reducer.reduce(&entries(&[(100, "A"), (500, "B"), (1_000_000, "C")])), Some(200));
+        assert_eq!(reducer.reduce(&entries(&[(1_000_000, "C")])), None);
+    }
+
+    #[test]
+    fn reducer_parsing() {
+        assert_eq!("median".parse(), Ok(Reducer::Median));
+        assert_eq!("MEAN".parse(), Ok(Reducer::Mean));
+        assert_eq!("publisher_weighted".parse(), Ok(Reducer::PublisherWeighted(HashMap::new())));
+        assert!("mode".parse::<Reducer>().is_err());
+    }
+
+    fn second(timestamp: u64, prices: &[(u128, &str)]) -> Vec<SpotEntryEvent> {
+        entries(prices).into_iter().map(|entry| SpotEntryEvent { timestamp, ..entry }).collect()
+    }
+
+    fn prices(outliers: OutlierFilter, seconds: &[Vec<SpotEntryEvent>]) -> (Vec<(u64, u128)>, Rejections) {
+        let entries: Vec<(u64, &[SpotEntryEvent])> =
+            seconds.iter().map(|second| (second[0].timestamp, second.as_slice())).collect();
+
+        let (prices, rejections) =
+            AggregationConfiguration { outliers, ..Default::default() }.prices(&entries, JumpReference::default());
+        (prices.into_iter().map(|(timestamp, price, _)| (timestamp, price)).collect(), rejections)
+    }
+
+    #[test]
+    fn no_filters_reject_nothing() {
+        let seconds = [second(1, &[(100, "A"), (1_000_000, "B"), (100, "C")]), second(2, &[(1_000_000, "A")])];
+        let (prices, rejections) = prices(OutlierFilter::default(), &seconds);
+
+        assert_eq!(prices, vec![(1, 100), (2, 1_000_000)]);
+        assert_eq!(rejections, Rejections::default());
+    }
+
+    #[test]
+    fn median_absolute_deviation_cutoff() {
+        // Median is 102, absolute deviations are 2, 4, 0, 2, 898, so MAD is 2 and cutoff is 6
+        let seconds =
+            [second(1, &[(100, "A"), (98, "B")]), second(2, &[(102, "A"), (104, "B")]), second(3, &[(1_000, "C")])];
+        let (prices, rejections) = prices(OutlierFilter { mad_cutoff: Some(3.0), ..Default::default() }, &seconds);
+
+        assert_eq!(prices, vec![(1, 99), (2, 103)]);
+        assert_eq!(rejections.mad, 1);
+        assert_eq!(rejections.publishers, BTreeMap::from([(Felt::from_bytes_be_slice("C".as_bytes()), 1)]));
+    }
+
+    #[test]
+    fn maximum_jump() {
+        let seconds = [
+            second(1, &[(100, "A")]),
+            second(2, &[(150, "A"), (105, "B")]),
+            second(3, &[(150, "A")]),
+            second(4, &[(112, "A")]),
+        ];
+        let (prices, rejections) = prices(OutlierFilter { max_jump: Some(0.1), ..Default::default() }, &seconds);
+
+        // Rejected price doesn't become a reference, 112 is compared with 105 rather than 150
+        assert_eq!(prices, vec![(1, 100), (2, 105), (4, 112)]);
+        assert_eq!(rejections.jump, 2);
+    }
+
+    #[test]
+    fn publisher_deviation_limit() {
+        let seconds = [second(1, &[(100, "A"), (101, "B"), (99, "C"), (200, "D")]), second(2, &[(200, "D")])];
+        let limit = OutlierFilter { max_publisher_deviation: Some(0.05), ..Default::default() };
+        let (prices, rejections) = prices(limit, &seconds);
+
+        // Lone publisher in a second has nobody to deviate from
+        assert_eq!(prices, vec![(1, 100), (2, 200)]);
+        assert_eq!(rejections.publisher_deviation, 1);
+        assert_eq!(rejections.publishers, BTreeMap::from([(Felt::from_bytes_be_slice("D".as_bytes()), 1)]));
+    }
+
+    #[test]
+    fn publisher_deviation_needs_majority() {
+        // Neither of two publishers can be told off, even though they deviate from their median by 9%
+        let seconds = [second(1, &[(100, "A"), (120, "B")])];
+        let limit = OutlierFilter { max_publisher_deviation: Some(0.05), ..Default::default() };
+        assert_eq!(prices(limit.clone(), &seconds), (vec![(1, 110)], Rejections::default()));
+
+        // Outlier doesn't pull median towards itself: 120 deviates from 100 of others by 20%, while median of others
+        // of 100 is anything within [100, 120]
+        let seconds = [second(1, &[(100, "A"), (100, "B"), (120, "C")])];
+        let (prices, rejections) = prices(limit, &seconds);
+
+        assert_eq!(prices, vec![(1, 100)]);
+        assert_eq!(rejections.publishers, BTreeMap::from([(Felt::from_bytes_be_slice("C".as_bytes()), 1)]));
+    }
+
+    /// Fixture window is [100, 200] with opening price 10 at 80 and updates 20 at 120, 40 at 150 and 10 at 180.
+    const START: u64 = 100;
+    const END: u64 = 200;
+    const OPENING: (u64, u128) = (80, 10);
+    const PRICES: [(u64, u128); 3] = [(120, 20), (150, 40), (180, 10)];
+
+    fn fixed(numerator: u64, denominator: u64) -> BigUint {
+        (BigUint::from(numerator) << 64) / denominator
+    }
+
+    fn twap(interpolation: Interpolation, prices: &[(u64, u128)], opening: Option<(u64, u128)>) -> Option<BigUint> {
+        let prices: Vec<(u64, u128)> = opening.into_iter().chain(prices.iter().cloned()).collect();
+        Twap.aggregate(&interpolation.segments(&prices, START, END))
+    }
+
+    #[test]
+    fn left_interpolation_fixture() {
+        // 10 * 20 + 20 * 30 + 40 * 30 + 10 * 20 = 2200 over 100 seconds
+        assert_eq!(twap(Interpolation::Left, &PRICES, Some(OPENING)), Some(fixed(22, 1)));
+        // Window is covered from 120 only: 20 * 30 + 40 * 30 + 10 * 20 = 2000 over 80 seconds
+        assert_eq!(twap(Interpolation::Left, &PRICES, None), Some(fixed(25, 1)));
+    }
+
+    #[test]
+    fn right_interpolation_fixture() {
+        // 20 * 20 + 40 * 30 + 10 * 30 + 10 * 20 = 2100 over 100 seconds
+        assert_eq!(twap(Interpolation::Right, &PRICES, Some(OPENING)), Some(fixed(21, 1)));
+        // 40 * 30 + 10 * 30 + 10 * 20 = 1700 over 80 seconds
+        assert_eq!(twap(Interpolation::Right, &PRICES, None), Some(fixed(85, 4)));
+    }
+
+    #[test]
+    fn linear_interpolation_fixture() {
+        // Price at window start is 15, halfway between 10 at 80 and 20 at 120:
+        // (15 + 20) / 2 * 20 + (20 + 40) / 2 * 30 + (40 + 10) / 2 * 30 + 10 * 20 = 2200 over 100 seconds
+        assert_eq!(twap(Interpolation::Linear, &PRICES, Some(OPENING)), Some(fixed(22, 1)));
+        // 900 + 750 + 200 = 1850 over 80 seconds
+        assert_eq!(twap(Interpolation::Linear, &PRICES, None), Some(fixed(185, 8)));
+        // Falling price: 50 at window start, (50 + 0) / 2 * 100 = 2500 over 100 seconds
+        assert_eq!(twap(Interpolation::Linear, &[(200, 0)], Some((0, 100))), Some(fixed(25, 1)));
+    }
+
+    #[test]
+    fn opening_price_covers_window_without_updates() {
+        for interpolation in [Interpolation::Left, Interpolation::Right, Interpolation::Linear] {
+            assert_eq!(twap(interpolation, &[], Some(OPENING)), Some(fixed(10, 1)));
+        }
+    }
+
+    #[test]
+    fn update_at_window_start_replaces_opening_price() {
+        let prices = [(100, 30), (200, 50)];
+
+        assert_eq!(twap(Interpolation::Left, &prices, Some(OPENING)), Some(fixed(30, 1)));
+        assert_eq!(twap(Interpolation::Right, &prices, Some(OPENING)), Some(fixed(50, 1)));
+        assert_eq!(twap(Interpolation::Linear, &prices, Some(OPENING)), Some(fixed(40, 1)));
+    }
+
+    #[test]
+    fn empty_coverage_has_no_average() {
+        for interpolation in [Interpolation::Left, Interpolation::Right, Interpolation::Linear] {
+            assert_eq!(twap(interpolation, &[], None), None);
+            assert_eq!(twap(interpolation, &[(END, 10)], None), None);
+            // Prices after the window don't cover it
+            assert_eq!(twap(interpolation, &[(END + 1, 10)], None), None);
+        }
+    }
+
+    #[test]
+    fn interpolation_parsing() {
+        assert_eq!("left".parse(), Ok(Interpolation::Left));
+        assert_eq!("Right".parse(), Ok(Interpolation::Right));
+        assert_eq!("linear".parse(), Ok(Interpolation::Linear));
+        assert!("cubic".parse::<Interpolation>().is_err());
+    }
+
+    #[test]
+    fn quality_gates() {
+        let (a, b) = (entries(&[(100, "A")]), entries(&[(100, "A"), (100, "B")]));
+        let window: Vec<(u64, &[SpotEntryEvent])> = vec![(3_400, &a), (3_500, &b), (3_570, &a)];
+        let check = |gates: &QualityGates, window: &[(u64, &[SpotEntryEvent])], opening, start, end| {
+            gates.check(
+                &Activity::of(window.iter().map(|(t, entries)| (*t, entries.iter())), opening, start, end),
+                start,
+                end,
+            )
+        };
+
+        assert_eq!(check(&QualityGates::default(), &window, false, 0, 3_600), Ok(()));
+
+        let gates = QualityGates { min_samples: Some(5), ..Default::default() };
+        assert_eq!(check(&gates, &window, false, 0, 3_600), Err(InsufficientData::Samples { samples: 4, min: 5 }));
+
+        let gates = QualityGates { min_publishers: Some(2), ..Default::default() };
+        assert_eq!(check(&gates, &window, false, 0, 3_600), Ok(()));
+        assert_eq!(
+            check(&gates, &window[2..], false, 0, 3_600),
+            Err(InsufficientData::Publishers { publishers: 1, min: 2 })
+        );
+
+        // Gaps at the edges of the window count as well
+        let gates = QualityGates { max_gap: Some(100), ..Default::default() };
+        assert_eq!(check(&gates, &window, true, 3_300, 3_600), Ok(()));
+        assert_eq!(check(&gates, &window, true, 3_000, 3_600), Err(InsufficientData::Gap { gap: 400, max: 100 }));
+        assert_eq!(check(&gates, &[], true, 3_500, 3_600), Ok(()));
+
+        let gates = QualityGates { min_coverage: Some(0.5), ..Default::default() };
+        assert_eq!(check(&gates, &window, true, 0, 3_600), Ok(()));
+        assert!(matches!(
+            check(&gates, &window, false, 0, 3_600),
+            Err(InsufficientData::Coverage { coverage, .. }) if coverage == 200.0 / 3_600.0
+        ));
+        assert!(check(&gates, &[], false, 0, 3_600).is_err());
+    }
+}
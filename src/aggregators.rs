@@ -0,0 +1,407 @@
+use crate::{accumulator::PriceAccumulator, aggregation::Segment};
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use std::{fmt::Debug, sync::Arc};
+
+/// Algorithm that aggregates price segments of a window into a single value.
+pub trait Aggregator: Debug + Send + Sync {
+    /// Name of the algorithm along with its parameters, e.g. `trimmed:10`. It is a part of signed attestation, so it
+    /// should be short.
+    fn name(&self) -> String;
+
+    /// Aggregated value as fixed point with 64 fractional bits. `segments` should be ordered by time.
+    ///
+    /// Returns `None` if segments cover no time.
+    fn aggregate(&self, segments: &[Segment]) -> Option<BigUint>;
+
+    /// Aggregated value given `seconds` of the window along with its segments, `(timestamp, price, volume)` of every
+    /// second with accepted entries. By default only segments are used, volume weighted aggregators override it.
+    fn aggregate_seconds(&self, segments: &[Segment], _seconds: &[(u64, u128, u128)]) -> Option<BigUint> {
+        self.aggregate(segments)
+    }
+
+    /// Aggregated value of accumulated prices from `start` to `end`. By default window is split into segments,
+    /// aggregators that can use cumulative prices override it.
+    fn aggregate_accumulated(&self, prices: &PriceAccumulator, start: u64, end: u64) -> Option<BigUint> {
+        self.aggregate(&prices.segments(start, end))
+    }
+}
+
+/// Parses aggregator name: `twap`, `tw-median`, `geometric`, `ema`, `vwap`, `trimmed:<percent>` or
+/// `winsorized:<percent>`.
+///
+/// # Errors
+///
+/// This function will return an error if aggregator is unknown or its percent is not within 1 and 49.
+pub fn parse_aggregator(value: &str) -> Result<Arc<dyn Aggregator>, String> {
+    let value = value.trim().to_lowercase();
+    let (name, parameter) = match value.split_once(':') {
+        Some((name, parameter)) => (name, Some(parameter)),
+        None => (value.as_str(), None),
+    };
+
+    let percent = || {
+        parameter
+            .and_then(|parameter| parameter.parse::<u8>().ok())
+            .filter(|percent| (1..50).contains(percent))
+            .ok_or(format!("Percent of {name} aggregator should be between 1 and 49: {value:?}"))
+    };
+
+    match (name, parameter) {
+        ("twap", None) => Ok(Arc::new(Twap)),
+        ("tw-median", None) => Ok(Arc::new(TimeWeightedMedian)),
+        ("geometric", None) => Ok(Arc::new(GeometricTwap)),
+        ("ema", None) => Ok(Arc::new(Ema)),
+        ("vwap", None) => Ok(Arc::new(Vwap)),
+        ("trimmed", Some(_)) => Ok(Arc::new(Trimmed { percent: percent()? })),
+        ("winsorized", Some(_)) => Ok(Arc::new(Winsorized { percent: percent()? })),
+        _ => Err(format!("Unknown aggregator: {value:?}")),
+    }
+}
+
+/// Arithmetic time weighted average.
+#[derive(Debug)]
+pub struct Twap;
+
+impl Aggregator for Twap {
+    fn name(&self) -> String {
+        "twap".to_string()
+    }
+
+    fn aggregate(&self, segments: &[Segment]) -> Option<BigUint> {
+        let duration = total_duration(segments)?;
+
+        // Area is doubled, so linear segments are summed without rounding.
+        let double_area: BigUint = segments.iter().map(Segment::double_area).sum();
+
+        Some(double_area / (duration * 2))
+    }
+
+    /// Difference of cumulative prices at window bounds, it doesn't depend on number of prices in the window.
+    fn aggregate_accumulated(&self, prices: &PriceAccumulator, start: u64, end: u64) -> Option<BigUint> {
+        prices.twap(start, end)
+    }
+}
+
+/// Value price was below of for half of the time.
+#[derive(Debug)]
+pub struct TimeWeightedMedian;
+
+impl Aggregator for TimeWeightedMedian {
+    fn name(&self) -> String {
+        "tw-median".to_string()
+    }
+
+    fn aggregate(&self, segments: &[Segment]) -> Option<BigUint> {
+        let duration = total_duration(segments)?;
+        quantile(&sorted_values(segments), duration * 50)
+    }
+}
+
+/// Time weighted average of price logarithm, the way Uniswap accumulates ticks. It is less sensitive to short spikes
+/// than arithmetic average. Calculated with floating point, so it is precise up to 15 significant digits.
+#[derive(Debug)]
+pub struct GeometricTwap;
+
+impl Aggregator for GeometricTwap {
+    fn name(&self) -> String {
+        "geometric".to_string()
+    }
+
+    fn aggregate(&self, segments: &[Segment]) -> Option<BigUint> {
+        let duration = total_duration(segments)?;
+
+        let log_area: f64 = segments
+            .iter()
+            .map(|segment| {
+                mean_log(to_price(&segment.from_value), to_price(&segment.to_value)) * segment.duration() as f64
+            })
+            .sum();
+
+        Some(from_price((log_area / duration as f64).exp()))
+    }
+}
+
+/// Exponential moving average over time with time constant equal to covered part of the window. Price that held
+/// `t` seconds before the end has weight proportional to `e^(-t / T)`. Linear segments are represented by their mean
+/// value. Calculated with floating point, so it is precise up to 15 significant digits.
+#[derive(Debug)]
+pub struct Ema;
+
+impl Aggregator for Ema {
+    fn name(&self) -> String {
+        "ema".to_string()
+    }
+
+    fn aggregate(&self, segments: &[Segment]) -> Option<BigUint> {
+        let time_constant = total_duration(segments)? as f64;
+
+        let mut average = to_price(&segments[0].from_value);
+        for segment in segments {
+            let price = to_price(&segment.mean_value());
+            average = price + (average - price) * (-(segment.duration() as f64) / time_constant).exp();
+        }
+
+        Some(from_price(average))
+    }
+}
+
+/// Volume weighted average of prices of seconds within the window. Every second is weighted by volume its accepted
+/// entries traded instead of time its price held, so windows nothing was traded in have no value.
+#[derive(Debug)]
+pub struct Vwap;
+
+impl Aggregator for Vwap {
+    fn name(&self) -> String {
+        "vwap".to_string()
+    }
+
+    /// Segments carry no volume, so there is nothing to weight prices by.
+    fn aggregate(&self, _segments: &[Segment]) -> Option<BigUint> {
+        None
+    }
+
+    fn aggregate_seconds(&self, _segments: &[Segment], seconds: &[(u64, u128, u128)]) -> Option<BigUint> {
+        let volume: BigUint = seconds.iter().map(|(_, _, volume)| BigUint::from(*volume)).sum();
+        if volume == BigUint::ZERO {
+            return None;
+        }
+
+        let traded: BigUint = seconds.iter().map(|(_, price, volume)| BigUint::from(*price) * *volume).sum();
+        Some((traded << 64) / volume)
+    }
+
+    /// Difference of cumulative volumes at window bounds, like arithmetic twap.
+    fn aggregate_accumulated(&self, prices: &PriceAccumulator, start: u64, end: u64) -> Option<BigUint> {
+        prices.vwap(start, end)
+    }
+}
+
+/// Time weighted average over the time price was within `percent` and `100 - percent` percentiles, highest and
+/// lowest prices are dropped. Linear segments are represented by their mean value.
+#[derive(Debug)]
+pub struct Trimmed {
+    pub percent: u8,
+}
+
+impl Aggregator for Trimmed {
+    fn name(&self) -> String {
+        format!("trimmed:{}", self.percent)
+    }
+
+    fn aggregate(&self, segments: &[Segment]) -> Option<BigUint> {
+        let duration = total_duration(segments)?;
+
+        // Time is measured in hundredths of a second, so percentile bounds are whole numbers.
+        let (low, high) = (duration * u64::from(self.percent), duration * u64::from(100 - self.percent));
+
+        let mut cumulative = 0_u64;
+        let mut area = BigUint::ZERO;
+        for (value, duration) in sorted_values(segments) {
+            let (from, to) = (cumulative, cumulative + duration * 100);
+            area += value * to.min(high).saturating_sub(from.max(low));
+            cumulative = to;
+        }
+
+        Some(area / (high - low))
+    }
+}
+
+/// Time weighted average where prices below `percent` percentile and above `100 - percent` one are replaced with
+/// those percentiles. Linear segments are represented by their mean value.
+#[derive(Debug)]
+pub struct Winsorized {
+    pub percent: u8,
+}
+
+impl Aggregator for Winsorized {
+    fn name(&self) -> String {
+        format!("winsorized:{}", self.percent)
+    }
+
+    fn aggregate(&self, segments: &[Segment]) -> Option<BigUint> {
+        let duration = total_duration(segments)?;
+
+        let sorted = sorted_values(segments);
+        let low = quantile(&sorted, duration * u64::from(self.percent))?;
+        let high = quantile(&sorted, duration * u64::from(100 - self.percent) - 1)?;
+
+        let area: BigUint =
+            sorted.into_iter().map(|(value, duration)| value.clamp(low.clone(), high.clone()) * duration).sum();
+
+        Some(area / duration)
+    }
+}
+
+/// Time covered by segments, `None` if it is zero.
+fn total_duration(segments: &[Segment]) -> Option<u64> {
+    Some(segments.iter().map(Segment::duration).sum()).filter(|duration| *duration > 0)
+}
+
+/// Mean values of segments along with their durations, ordered by value.
+fn sorted_values(segments: &[Segment]) -> Vec<(BigUint, u64)> {
+    let mut values: Vec<(BigUint, u64)> =
+        segments.iter().map(|segment| (segment.mean_value(), segment.duration())).collect();
+    values.sort();
+    values
+}
+
+/// Value price was below of for `position` of time, position is measured in hundredths of a second.
+fn quantile(sorted: &[(BigUint, u64)], position: u64) -> Option<BigUint> {
+    let mut cumulative = 0_u64;
+    for (value, duration) in sorted {
+        cumulative += duration * 100;
+        if cumulative > position {
+            return Some(value.clone());
+        }
+    }
+
+    sorted.last().map(|(value, _)| value.clone())
+}
+
+/// Mean of price logarithm over a segment where price changes linearly from `from` to `to`.
+fn mean_log(from: f64, to: f64) -> f64 {
+    let x_log_x = |x: f64| if x == 0.0 { 0.0 } else { x * x.ln() };
+
+    // Integral of ln(x) is x ln(x) - x, it is unstable for close values, those are treated as constant.
+    if (to - from).abs() <= f64::EPSILON * from.max(to) * 1024.0 {
+        from.max(to).ln()
+    } else {
+        (x_log_x(to) - x_log_x(from)) / (to - from) - 1.0
+    }
+}
+
+fn to_price(value: &BigUint) -> f64 {
+    value.to_f64().unwrap_or(f64::INFINITY) / 2_f64.powi(64)
+}
+
+/// Converts price to fixed point value. Negative and non finite prices give zero.
+fn from_price(price: f64) -> BigUint {
+    if !price.is_finite() || price <= 0.0 {
+        return BigUint::ZERO;
+    }
+
+    (BigUint::from(price.trunc() as u128) << 64) + BigUint::from((price.fract() * 2_f64.powi(64)) as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixed(numerator: u64, denominator: u64) -> BigUint {
+        (BigUint::from(numerator) << 64) / denominator
+    }
+
+    /// Steps of given durations and prices that follow one another.
+    fn steps(steps: &[(u64, u64)]) -> Vec<Segment> {
+        let mut from = 0;
+        steps
+            .iter()
+            .map(|(duration, price)| {
+                from += duration;
+                Segment { from: from - duration, to: from, from_value: fixed(*price, 1), to_value: fixed(*price, 1) }
+            })
+            .collect()
+    }
+
+    /// Price held 100 for 30 seconds, then 400 for 20 seconds and 200 for 50 seconds.
+    fn fixture() -> Vec<Segment> {
+        steps(&[(30, 100), (20, 400), (50, 200)])
+    }
+
+    fn assert_close(value: BigUint, expected: f64) {
+        assert!((to_price(&value) - expected).abs() < expected * 1e-12, "{} != {expected}", to_price(&value));
+    }
+
+    #[test]
+    fn arithmetic_average() {
+        // (100 * 30 + 400 * 20 + 200 * 50) / 100
+        assert_eq!(Twap.aggregate(&fixture()), Some(fixed(210, 1)));
+        assert_eq!(Twap.aggregate(&[]), None);
+    }
+
+    #[test]
+    fn time_weighted_median() {
+        // Price was 100 for 30 seconds and up to 200 for 80 seconds
+        assert_eq!(TimeWeightedMedian.aggregate(&fixture()), Some(fixed(200, 1)));
+        assert_eq!(TimeWeightedMedian.aggregate(&steps(&[(60, 100), (40, 1_000)])), Some(fixed(100, 1)));
+    }
+
+    #[test]
+    fn geometric_average() {
+        // 100^0.3 * 400^0.2 * 200^0.5
+        assert_close(GeometricTwap.aggregate(&fixture()).unwrap(), 186.60659830736142);
+        assert_close(GeometricTwap.aggregate(&steps(&[(10, 100)])).unwrap(), 100.0);
+
+        // Linear segment from 100 to 400 compared with numeric integration of the logarithm
+        let linear = Segment { from: 0, to: 10, from_value: fixed(100, 1), to_value: fixed(400, 1) };
+        let steps = 1_000_000;
+        let numeric = (0..steps).map(|i| (100.0 + 300.0 * (i as f64 + 0.5) / steps as f64).ln()).sum::<f64>();
+
+        let value = to_price(&GeometricTwap.aggregate(&[linear]).unwrap());
+        assert!((value - (numeric / steps as f64).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn exponential_moving_average() {
+        // Starts at 100: 400 + (100 - 400) * e^-0.2, then 200 + (154.38 - 200) * e^-0.5
+        assert_close(Ema.aggregate(&fixture()).unwrap(), 172.33054080510385);
+        assert_close(Ema.aggregate(&steps(&[(10, 100), (90, 100)])).unwrap(), 100.0);
+    }
+
+    #[test]
+    fn floating_point_aggregators_keep_relative_precision() {
+        // Prices far above 2^53, fixed point values are above 2^150
+        let scale = BigUint::from(10_u8).pow(24);
+        let scaled: Vec<Segment> = fixture()
+            .into_iter()
+            .map(|segment| Segment {
+                from_value: &segment.from_value * &scale,
+                to_value: &segment.to_value * &scale,
+                ..segment
+            })
+            .collect();
+
+        assert_close(GeometricTwap.aggregate(&scaled).unwrap(), 186.60659830736142 * 1e24);
+        assert_close(Ema.aggregate(&scaled).unwrap(), 172.33054080510385 * 1e24);
+    }
+
+    #[test]
+    fn volume_weighted_average() {
+        // (100 * 2 + 400 * 0 + 200 * 6) / 8, time prices held doesn't matter
+        let seconds = [(0, 100, 2), (30, 400, 0), (50, 200, 6)];
+        assert_eq!(Vwap.aggregate_seconds(&fixture(), &seconds), Some(fixed(175, 1)));
+
+        assert_eq!(Vwap.aggregate_seconds(&fixture(), &[(0, 100, 0)]), None);
+        assert_eq!(Vwap.aggregate(&fixture()), None);
+        // Other aggregators ignore volumes
+        assert_eq!(Twap.aggregate_seconds(&fixture(), &seconds), Some(fixed(210, 1)));
+    }
+
+    #[test]
+    fn trimmed_average() {
+        // Lowest and highest 20 seconds are dropped: (100 * 10 + 200 * 50) / 60
+        assert_eq!(Trimmed { percent: 20 }.aggregate(&fixture()), Some(fixed(11_000, 60)));
+    }
+
+    #[test]
+    fn winsorized_average() {
+        // 400 is replaced with 80th percentile, which is 200: (100 * 30 + 200 * 20 + 200 * 50) / 100
+        assert_eq!(Winsorized { percent: 20 }.aggregate(&fixture()), Some(fixed(170, 1)));
+        // Lowest 30 seconds are within 40th percentile, so 100 is replaced with 200 as well
+        assert_eq!(Winsorized { percent: 40 }.aggregate(&fixture()), Some(fixed(200, 1)));
+    }
+
+    #[test]
+    fn aggregator_parsing() {
+        for name in ["twap", "tw-median", "geometric", "ema", "vwap", "trimmed:10", "winsorized:5"] {
+            assert_eq!(parse_aggregator(name).unwrap().name(), name);
+        }
+
+        assert_eq!(parse_aggregator(" TWAP ").unwrap().name(), "twap");
+        assert!(parse_aggregator("trimmed").is_err());
+        assert!(parse_aggregator("trimmed:50").is_err());
+        assert!(parse_aggregator("winsorized:0").is_err());
+        assert!(parse_aggregator("twap:10").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use num_bigint::BigUint;
+use secp256k1::hashes::{Hash, sha256};
+use serde::Serialize;
+use sha3::{Digest, Keccak256};
+use starknet::core::{crypto::compute_hash_on_elements, types::Felt};
+
+/// Domain tag every signed payload starts with, so signatures can't be reused for other protocols.
+pub const PROTOCOL_TAG: &str = "twapper:twap";
+pub const ATTESTATION_VERSION: u8 = 3;
+
+/// EIP-712 domain of typed attestation. Domain has no `chainId` and `verifyingContract`, as attestation is not bound
+/// to EVM chain or contract, Starknet chain id is a part of the struct itself.
+pub const EIP712_DOMAIN_NAME: &str = "twapper";
+pub const EIP712_DOMAIN_VERSION: &str = "1";
+pub const EIP712_DOMAIN_TYPE: &str = "EIP712Domain(string name,string version)";
+pub const EIP712_ATTESTATION_TYPE: &str = "TwapAttestation(uint8 version,uint256 chainId,uint256 pairId,uint256 algorithm,\
+                                           uint64 windowStart,uint64 windowEnd,uint64 timestamp,uint64 sequence,\
+                                           uint8 anchorKind,uint64 anchor,uint256 twap)";
+
+/// What window of attestation ends at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum Anchor {
+    /// The latest data, window ends at the moment of calculation.
+    Latest,
+    /// Window ends at requested unix timestamp in seconds.
+    Time(u64),
+    /// Window ends at timestamp of requested block, only events of that block and earlier ones are used.
+    Block(u64),
+}
+
+impl Anchor {
+    /// Kind and value anchor is encoded with, value of `Latest` anchor is zero.
+    pub fn encode(&self) -> (u8, u64) {
+        match self {
+            Anchor::Latest => (0, 0),
+            Anchor::Time(timestamp) => (1, *timestamp),
+            Anchor::Block(block_number) => (2, *block_number),
+        }
+    }
+
+    /// Block window is calculated as of, only `Block` anchor has one.
+    pub fn block_number(&self) -> Option<u64> {
+        match self {
+            Anchor::Block(block_number) => Some(*block_number),
+            Anchor::Latest | Anchor::Time(_) => None,
+        }
+    }
+
+    /// Decodes anchor from kind and value produced by `encode`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if kind is unknown.
+    pub fn decode(kind: u8, value: u64) -> Result<Anchor, String> {
+        match kind {
+            0 => Ok(Anchor::Latest),
+            1 => Ok(Anchor::Time(value)),
+            2 => Ok(Anchor::Block(value)),
+            _ => Err(format!("Unknown anchor kind: {kind}")),
+        }
+    }
+}
+
+/// Statement about twap value that is signed by the service.
+///
+/// It is encoded into bytes as concatenation of fixed size big endian fields, so encoding is unambiguous:
+///
+/// | field        | size |
+/// | ------------ | ---- |
+/// | protocol tag | 12   |
+/// | version      | 1    |
+/// | chain id     | 32   |
+/// | pair id      | 32   |
+/// | algorithm    | 32   |
+/// | window start | 8    |
+/// | window end   | 8    |
+/// | timestamp    | 8    |
+/// | sequence     | 8    |
+/// | anchor kind  | 1    |
+/// | anchor       | 8    |
+/// | twap         | 32   |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attestation {
+    pub version: u8,
+    pub chain_id: Felt,
+    pub pair_id: Felt,
+    /// Short string naming aggregator and interpolation value is calculated with, e.g. `twap/left`.
+    pub algorithm: Felt,
+    /// Window bounds as unix timestamps in seconds.
+    pub window_start: u64,
+    pub window_end: u64,
+    /// Unix timestamp of the moment twap was calculated.
+    pub timestamp: u64,
+    /// Number of attestation for the pair and window, increases with every signature of the latest data. It is zero
+    /// for historical attestations.
+    pub sequence: u64,
+    pub anchor: Anchor,
+    /// Fixed point value, 64 lower bits are fractional part.
+    pub twap: BigUint,
+}
+
+impl Attestation {
+    /// Size of byte encoded attestation.
+    pub const ENCODED_LEN: usize = PROTOCOL_TAG.len() + 1 + 32 * 3 + 8 * 4 + 1 + 8 + 32;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let twap = abi_word(&self.twap.to_bytes_be());
+        let (anchor_kind, anchor) = self.anchor.encode();
+
+        [
+            PROTOCOL_TAG.as_bytes(),
+            &[self.version],
+            &self.chain_id.to_bytes_be(),
+            &self.pair_id.to_bytes_be(),
+            &self.algorithm.to_bytes_be(),
+            &self.window_start.to_be_bytes(),
+            &self.window_end.to_be_bytes(),
+            &self.timestamp.to_be_bytes(),
+            &self.sequence.to_be_bytes(),
+            &[anchor_kind],
+            &anchor.to_be_bytes(),
+            &twap,
+        ]
+        .concat()
+    }
+
+    /// Decodes attestation from bytes produced by `encode`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if length or protocol tag doesn't match or anchor kind is unknown.
+    pub fn decode(bytes: &[u8]) -> Result<Attestation, String> {
+        let tag_len = PROTOCOL_TAG.len();
+        if bytes.len() != Attestation::ENCODED_LEN || !bytes.starts_with(PROTOCOL_TAG.as_bytes()) {
+            return Err("Can't decode attestation".to_string());
+        }
+
+        let u64_at = |offset: usize| u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let offset = tag_len + 1 + 32 * 3;
+
+        Ok(Attestation {
+            version: bytes[tag_len],
+            chain_id: Felt::from_bytes_be_slice(&bytes[tag_len + 1..tag_len + 33]),
+            pair_id: Felt::from_bytes_be_slice(&bytes[tag_len + 33..tag_len + 65]),
+            algorithm: Felt::from_bytes_be_slice(&bytes[tag_len + 65..tag_len + 97]),
+            window_start: u64_at(offset),
+            window_end: u64_at(offset + 8),
+            timestamp: u64_at(offset + 16),
+            sequence: u64_at(offset + 24),
+            anchor: Anchor::decode(bytes[offset + 32], u64_at(offset + 33))?,
+            twap: BigUint::from_bytes_be(&bytes[offset + 41..]),
+        })
+    }
+
+    /// Sha256 digest of encoded attestation, that's what gets signed.
+    pub fn digest(&self) -> [u8; 32] {
+        sha256::Hash::hash(self.encode().as_slice()).to_byte_array()
+    }
+
+    /// Felt encoding of attestation, the way Cairo contract would see it. Fields go in the same order as in byte
+    /// encoding, protocol tag and algorithm are short strings and twap is split into `u256` low and high parts.
+    pub fn felts(&self) -> Vec<Felt> {
+        let low_mask = (BigUint::from(1_u8) << 128) - 1_u8;
+        let (anchor_kind, anchor) = self.anchor.encode();
+
+        vec![
+            Felt::from_bytes_be_slice(PROTOCOL_TAG.as_bytes()),
+            Felt::from(self.version),
+            self.chain_id,
+            self.pair_id,
+            self.algorithm,
+            Felt::from(self.window_start),
+            Felt::from(self.window_end),
+            Felt::from(self.timestamp),
+            Felt::from(self.sequence),
+            Felt::from(anchor_kind),
+            Felt::from(anchor),
+            Felt::from(&self.twap & low_mask),
+            Felt::from(&self.twap >> 128),
+        ]
+    }
+
+    /// EIP-712 digest of attestation as `TwapAttestation` typed struct, hashed with keccak256. That's what gets
+    /// signed with EVM scheme.
+    pub fn eip712_digest(&self) -> [u8; 32] {
+        let (anchor_kind, anchor) = self.anchor.encode();
+        let domain_separator = keccak256(
+            &[
+                keccak256(EIP712_DOMAIN_TYPE.as_bytes()),
+                keccak256(EIP712_DOMAIN_NAME.as_bytes()),
+                keccak256(EIP712_DOMAIN_VERSION.as_bytes()),
+            ]
+            .concat(),
+        );
+
+        let struct_hash = keccak256(
+            &[
+                keccak256(EIP712_ATTESTATION_TYPE.as_bytes()),
+                abi_word(&[self.version]),
+                self.chain_id.to_bytes_be(),
+                self.pair_id.to_bytes_be(),
+                self.algorithm.to_bytes_be(),
+                abi_word(&self.window_start.to_be_bytes()),
+                abi_word(&self.window_end.to_be_bytes()),
+                abi_word(&self.timestamp.to_be_bytes()),
+                abi_word(&self.sequence.to_be_bytes()),
+                abi_word(&[anchor_kind]),
+                abi_word(&anchor.to_be_bytes()),
+                abi_word(&self.twap.to_bytes_be()),
+            ]
+            .concat(),
+        );
+
+        keccak256(&[b"\x19\x01".as_slice(), &domain_separator, &struct_hash].concat())
+    }
+
+    /// Pedersen hash chain over felt encoding, same as `compute_hash_on_elements` in Cairo. That's what gets signed
+    /// with STARK curve.
+    pub fn pedersen_hash(&self) -> Felt {
+        compute_hash_on_elements(&self.felts())
+    }
+}
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+/// Left pads big endian bytes to 32 bytes word.
+fn abi_word(bytes: &[u8]) -> [u8; 32] {
+    let mut word = [0_u8; 32];
+    word[32 - bytes.len()..].copy_from_slice(bytes);
+    word
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use secp256k1::hashes::hex::FromHex;
+
+    fn attestation() -> Attestation {
+        Attestation {
+            version: ATTESTATION_VERSION,
+            chain_id: Felt::from_bytes_be_slice("SN_SEPOLIA".as_bytes()),
+            pair_id: Felt::from_bytes_be_slice("BTC/USD".as_bytes()),
+            algorithm: Felt::from_bytes_be_slice("twap/left".as_bytes()),
+            window_start: 1_000,
+            window_end: 4_600,
+            timestamp: 4_600,
+            sequence: 1,
+            anchor: Anchor::Latest,
+            twap: BigUint::from(100_u64) << 64,
+        }
+    }
+
+    #[test]
+    fn encoding_has_fixed_layout() {
+        let encoded = attestation().encode();
+
+        assert_eq!(encoded.len(), 12 + 1 + 32 * 3 + 8 * 4 + 1 + 8 + 32);
+        assert_eq!(encoded.len(), Attestation::ENCODED_LEN);
+        assert!(encoded.starts_with(PROTOCOL_TAG.as_bytes()));
+        assert_eq!(encoded[12], ATTESTATION_VERSION);
+        assert_eq!(&encoded[77..109], Felt::from_bytes_be_slice("twap/left".as_bytes()).to_bytes_be().as_slice());
+        assert_eq!(&encoded[109..117], 1_000_u64.to_be_bytes().as_slice());
+        assert_eq!(encoded[141], 0);
+        assert_eq!(BigUint::from_bytes_be(&encoded[encoded.len() - 32..]), BigUint::from(100_u64) << 64);
+    }
+
+    #[test]
+    fn decoding_restores_attestation() {
+        let encoded = attestation().encode();
+
+        assert_eq!(Attestation::decode(&encoded), Ok(attestation()));
+        assert!(Attestation::decode(&encoded[1..]).is_err());
+
+        for anchor in [Anchor::Time(4_000), Anchor::Block(1_234)] {
+            let attestation = Attestation { anchor, ..attestation() };
+            assert_eq!(Attestation::decode(&attestation.encode()), Ok(attestation));
+        }
+
+        let mut unknown_anchor = encoded.clone();
+        unknown_anchor[141] = 3;
+        assert!(Attestation::decode(&unknown_anchor).is_err());
+    }
+
+    #[test]
+    fn felt_encoding_splits_twap() {
+        let attestation = Attestation { twap: (BigUint::from(7_u8) << 128) + 5_u8, ..attestation() };
+        let felts = attestation.felts();
+
+        assert_eq!(felts.len(), 13);
+        assert_eq!(felts[0], Felt::from_hex_unchecked("0x747761707065723a74776170"));
+        assert_eq!(felts[11], Felt::from(5_u8));
+        assert_eq!(felts[12], Felt::from(7_u8));
+    }
+
+    #[test]
+    fn eip712_types_are_canonical() {
+        // Line continuation in constant must not leave any whitespace between members.
+        assert!(EIP712_ATTESTATION_TYPE.contains("uint256 algorithm,uint64 windowStart,uint64 windowEnd"));
+        assert!(EIP712_ATTESTATION_TYPE.contains("uint64 sequence,uint8 anchorKind,uint64 anchor,uint256 twap"));
+        assert!(!EIP712_ATTESTATION_TYPE.contains(", ") && !EIP712_ATTESTATION_TYPE.contains("  "));
+        assert_eq!(
+            keccak256(b"").to_vec(),
+            Vec::<u8>::from_hex("c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470").unwrap()
+        );
+    }
+
+    #[test]
+    fn every_field_is_signed() {
+        let base = attestation();
+        let variations = [
+            Attestation { version: ATTESTATION_VERSION + 1, ..base.clone() },
+            Attestation { chain_id: Felt::from_bytes_be_slice("SN_MAIN".as_bytes()), ..base.clone() },
+            Attestation { pair_id: Felt::from_bytes_be_slice("ETH/USD".as_bytes()), ..base.clone() },
+            Attestation { algorithm: Felt::from_bytes_be_slice("ema/left".as_bytes()), ..base.clone() },
+            Attestation { window_start: 1_001, ..base.clone() },
+            Attestation { window_end: 4_601, ..base.clone() },
+            Attestation { timestamp: 4_601, ..base.clone() },
+            Attestation { sequence: 2, ..base.clone() },
+            Attestation { anchor: Anchor::Time(0), ..base.clone() },
+            Attestation { anchor: Anchor::Block(0), ..base.clone() },
+            Attestation { anchor: Anchor::Block(1), ..base.clone() },
+            Attestation { twap: BigUint::from(101_u64) << 64, ..base.clone() },
+        ];
+
+        for variation in variations {
+            assert_ne!(base.digest(), variation.digest());
+            assert_ne!(base.pedersen_hash(), variation.pedersen_hash());
+            assert_ne!(base.eip712_digest(), variation.eip712_digest());
+        }
+    }
+}
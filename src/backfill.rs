@@ -0,0 +1,384 @@
+use crate::{
+    configuration::{ApplicationConfiguration, NetworkConfiguration},
+    metrics::Metrics,
+    provider::RpcProvider,
+    settings::BackfillArgs,
+    storage::{EventBatch, SpotEntryEvent},
+    workers::{EVENT_CHUNK_SIZE, spot_entries},
+};
+use futures_util::{StreamExt, stream};
+use starknet::core::types::{BlockId, EmittedEvent, EventFilter, Felt};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::RangeInclusive,
+};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// Fetches events of `blocks` in chunks of `network.backfill_chunk` blocks, up to `network.backfill_parallelism`
+/// chunks at once, and publishes them chunk by chunk in order of blocks. Events node returned more than once, e.g. on
+/// overlapping pages or in different chunks, are published once within the whole range. Node doesn't return index of
+/// event within its transaction, so events are identified by transaction hash, keys and data instead. That is the same:
+/// equal events of the same transaction are the same spot entry, which storage keeps once by timestamp, publisher and
+/// source anyway. Nothing is checkpointed, that is up to the caller.
+///
+/// Returns hashes of blocks that had events.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - In case of any RPC errors
+/// - If publishing channel is closed.
+pub async fn backfill(
+    network: &NetworkConfiguration,
+    provider: &RpcProvider,
+    blocks: RangeInclusive<u64>,
+    pair_ids: &HashSet<Felt>,
+    tx: &UnboundedSender<EventBatch>,
+    metrics: &Metrics,
+) -> Result<BTreeMap<u64, Felt>, String> {
+    let (from_block_number, to_block_number) = blocks.into_inner();
+    let chunks = (from_block_number..=to_block_number).step_by(network.backfill_chunk as usize).map(|block_number| {
+        block_number..=block_number.saturating_add(network.backfill_chunk - 1).min(to_block_number)
+    });
+
+    // Chunks are fetched concurrently, but yielded in the order they were requested.
+    let mut chunks = stream::iter(chunks)
+        .map(|blocks| fetch_chunk(network, provider, blocks, metrics))
+        .buffered(network.backfill_parallelism);
+
+    let mut block_hashes = BTreeMap::new();
+    let mut seen: HashSet<(Felt, Vec<Felt>, Vec<Felt>)> = HashSet::new();
+    while let Some(events) = chunks.next().await {
+        let mut unique = Vec::new();
+
+        for event in events? {
+            if !seen.insert((event.transaction_hash, event.keys.clone(), event.data.clone())) {
+                continue;
+            }
+
+            if let (Some(block_number), Some(block_hash)) = (event.block_number, event.block_hash) {
+                block_hashes.insert(block_number, block_hash);
+            }
+
+            unique.push(event);
+        }
+
+        let events = spot_entries(&unique, pair_ids);
+        if !events.is_empty() {
+            tx.send(EventBatch { events, checkpoint: None, rollback: None, timestamp: None })
+                .map_err(|_| "Can't publish events to channel")?;
+        }
+    }
+
+    Ok(block_hashes)
+}
+
+/// Every oracle event of `blocks`, page after page.
+async fn fetch_chunk(
+    network: &NetworkConfiguration,
+    provider: &RpcProvider,
+    blocks: RangeInclusive<u64>,
+    metrics: &Metrics,
+) -> Result<Vec<EmittedEvent>, String> {
+    let filter = EventFilter {
+        address: Some(network.oracle_address),
+        keys: Some(vec![vec![network.event_selector]]),
+        from_block: Some(BlockId::Number(*blocks.start())),
+        to_block: Some(BlockId::Number(*blocks.end())),
+    };
+
+    let mut events = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let page = provider.get_events(filter.clone(), continuation_token, EVENT_CHUNK_SIZE, metrics).await?;
+        events.extend(page.events);
+
+        continuation_token = page.continuation_token;
+        if continuation_token.is_none() {
+            return Ok(events);
+        }
+    }
+}
+
+/// The first block with timestamp not less than `timestamp`, or the one after `latest_block_number` if there is no
+/// such block yet.
+///
+/// # Errors
+///
+/// This function will return an error if block can't be fetched.
+pub async fn first_block_at(
+    provider: &RpcProvider,
+    timestamp: u64,
+    latest_block_number: u64,
+    metrics: &Metrics,
+) -> Result<u64, String> {
+    let (mut low, mut high) = (0, latest_block_number + 1);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if provider.get_block(middle, metrics).await?.timestamp < timestamp {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    Ok(low)
+}
+
+/// Backfill command: fetches events of requested block or time range and stores them to persistence backend, so
+/// service has them on start. Range ends at the latest block unless set.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - Persistence backend doesn't survive restarts, backfilled events would be lost on exit
+/// - Range is empty
+/// - In case of any RPC errors
+/// - Persistence backend failed
+pub async fn run(state: &ApplicationConfiguration, args: &BackfillArgs) -> Result<(), String> {
+    if !state.persistence.is_durable() {
+        return Err("Backfilled events would be lost on exit, use sled storage backend".to_string());
+    }
+
+    let (provider, metrics) = (&state.rpc, &state.metrics);
+    let latest_block_number = provider.block_number(metrics).await?;
+
+    let from_block_number = match (args.from_block, args.from_time) {
+        (Some(block_number), _) => block_number,
+        (None, Some(timestamp)) => first_block_at(provider, timestamp, latest_block_number, metrics).await?,
+        (None, None) => return Err("Either from block or from time is required".to_string()),
+    };
+
+    let to_block_number = match (args.to_block, args.to_time) {
+        (Some(block_number), _) => block_number.min(latest_block_number),
+        (None, Some(timestamp)) => {
+            let next_block_number = first_block_at(provider, timestamp.saturating_add(1), latest_block_number, metrics);
+            next_block_number.await?.checked_sub(1).ok_or("No blocks before to time")?
+        }
+        (None, None) => latest_block_number,
+    };
+
+    if from_block_number > to_block_number {
+        return Err(format!("Block range {from_block_number}..={to_block_number} is empty"));
+    }
+
+    println!("Backfilling blocks {from_block_number}..={to_block_number}");
+
+    let pair_ids: HashSet<Felt> = state.pairs.iter().map(|pair| pair.id).collect();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let fetching = async move {
+        let blocks = from_block_number..=to_block_number;
+        backfill(&state.network, provider, blocks, &pair_ids, &tx, metrics).await
+    };
+
+    let storing = async {
+        let mut stored = 0;
+        while let Some(batch) = rx.recv().await {
+            let mut batches: HashMap<Felt, Vec<SpotEntryEvent>> = HashMap::new();
+            for event in batch.events {
+                batches.entry(event.pair_id).or_default().push(event);
+            }
+
+            for (pair_id, events) in batches {
+                state.persistence.store_events(pair_id, &events)?;
+                stored += events.len();
+            }
+        }
+
+        Ok::<_, String>(stored)
+    };
+
+    let (_, stored) = tokio::try_join!(fetching, storing)?;
+    println!("Backfilled {stored} events of blocks {from_block_number}..={to_block_number}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::settings::Settings;
+    use axum::{Json, Router, routing::post};
+    use serde_json::{Value, json};
+    use starknet::providers::Url;
+
+    /// Events per page node responds with.
+    const PAGE_SIZE: usize = 2;
+
+    /// Oracle event of `pair_id` and `source` emitted in block `block_number` by transaction `transaction_hash`.
+    fn event(block_number: u64, transaction_hash: u64, pair_id: u64, source: u64) -> Value {
+        let data =
+            [Felt::from(1_000 + block_number), Felt::from(source), Felt::TWO, Felt::from(100), Felt::from(pair_id)];
+
+        json!({
+            "from_address": "0x1",
+            "keys": ["0x2"],
+            "data": data,
+            "block_hash": Felt::from(block_number),
+            "block_number": block_number,
+            "transaction_hash": Felt::from(transaction_hash),
+        })
+    }
+
+    /// Local JSON-RPC node of blocks `0..=latest_block_number`, block `n` has timestamp `1000 + 10n`. Events are
+    /// filtered by requested blocks and paged by `PAGE_SIZE`, every next page repeats `overlap` events of the previous
+    /// one.
+    async fn node(latest_block_number: u64, events: Vec<Value>, overlap: usize) -> Url {
+        let handler = move |Json(request): Json<Value>| {
+            let events = events.clone();
+
+            async move {
+                // Parameters are positional
+                let params = &request["params"][0];
+                let result = match request["method"].as_str() {
+                    Some("starknet_blockNumber") => json!(latest_block_number),
+                    Some("starknet_getBlockWithTxHashes") => {
+                        let block_number = params["block_number"].as_u64().unwrap();
+                        json!({
+                            "status": "ACCEPTED_ON_L2",
+                            "block_hash": Felt::from(block_number),
+                            "parent_hash": Felt::from(block_number.saturating_sub(1)),
+                            "block_number": block_number,
+                            "new_root": "0x0",
+                            "timestamp": 1_000 + 10 * block_number,
+                            "sequencer_address": "0x0",
+                            "l1_gas_price": { "price_in_fri": "0x1", "price_in_wei": "0x1" },
+                            "l1_data_gas_price": { "price_in_fri": "0x1", "price_in_wei": "0x1" },
+                            "l1_da_mode": "BLOB",
+                            "starknet_version": "0.13.2",
+                            "transactions": []
+                        })
+                    }
+                    _ => {
+                        let from = params["from_block"]["block_number"].as_u64().unwrap();
+                        let to = params["to_block"]["block_number"].as_u64().unwrap();
+                        let offset: usize = params["continuation_token"].as_str().map_or(0, |t| t.parse().unwrap());
+
+                        let events: Vec<&Value> = events
+                            .iter()
+                            .filter(|event| (from..=to).contains(&event["block_number"].as_u64().unwrap()))
+                            .collect();
+                        let page: Vec<&Value> = events.iter().skip(offset).take(PAGE_SIZE).cloned().collect();
+                        let next_offset = offset + PAGE_SIZE - overlap;
+                        let token = (offset + PAGE_SIZE < events.len()).then(|| next_offset.to_string());
+
+                        json!({ "events": page, "continuation_token": token })
+                    }
+                };
+
+                Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+            }
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, Router::new().route("/", post(handler))).await });
+
+        url
+    }
+
+    fn network(rpc_url: Url) -> NetworkConfiguration {
+        NetworkConfiguration {
+            rpc_urls: vec![rpc_url],
+            backfill_chunk: 3,
+            backfill_parallelism: 2,
+            ..NetworkConfiguration::sepolia()
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_are_published_in_order_without_duplicates() {
+        let events = vec![
+            event(1, 0xa, 7, 1),
+            event(1, 0xa, 8, 1),
+            event(2, 0xb, 7, 1),
+            event(4, 0xc, 7, 1),
+            event(4, 0xc, 7, 2),
+            event(4, 0xd, 7, 1),
+            // Node returns events of the transaction twice
+            event(4, 0xc, 7, 1),
+            event(4, 0xc, 7, 2),
+            event(9, 0xe, 7, 1),
+        ];
+        let network = network(node(10, events, 0).await);
+        let (provider, metrics) = (RpcProvider::new(&network), Metrics::new());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let pair_ids = HashSet::from([Felt::from(7)]);
+
+        let block_hashes = backfill(&network, &provider, 1..=9, &pair_ids, &tx, &metrics).await.unwrap();
+        assert_eq!(block_hashes.keys().cloned().collect::<Vec<_>>(), vec![1, 2, 4, 9]);
+
+        drop(tx);
+        let mut batches = Vec::new();
+        while let Some(batch) = rx.recv().await {
+            batches.push(batch.events.iter().map(|event| event.block_number).collect::<Vec<_>>());
+        }
+
+        // Chunks of blocks 1..=3, 4..=6 and 7..=9
+        assert_eq!(batches, vec![vec![1, 2], vec![4, 4, 4], vec![9]]);
+    }
+
+    #[tokio::test]
+    async fn overlapping_pages_are_deduplicated() {
+        let events = (1..=5).map(|source| event(4, 0xc, 7, source)).collect();
+        let network = network(node(10, events, 1).await);
+        let (provider, metrics) = (RpcProvider::new(&network), Metrics::new());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        backfill(&network, &provider, 4..=6, &HashSet::from([Felt::from(7)]), &tx, &metrics).await.unwrap();
+
+        // Pages [c1, c2], [c2, c3], [c3, c4], [c4, c5]
+        let sources: Vec<Felt> = rx.try_recv().unwrap().events.iter().map(|event| event.source).collect();
+        assert_eq!(sources, (1..=5).map(Felt::from).collect::<Vec<_>>());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn events_repeated_in_other_chunk_are_deduplicated() {
+        // Node returns event of block 4 in block 7 as well
+        let mut repeated = event(4, 0xc, 7, 1);
+        repeated["block_number"] = json!(7);
+        let events = vec![event(4, 0xc, 7, 1), event(4, 0xc, 7, 2), repeated, event(7, 0xd, 7, 1)];
+
+        let network = network(node(10, events, 0).await);
+        let (provider, metrics) = (RpcProvider::new(&network), Metrics::new());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        backfill(&network, &provider, 4..=9, &HashSet::from([Felt::from(7)]), &tx, &metrics).await.unwrap();
+
+        let blocks = |events: Vec<SpotEntryEvent>| events.iter().map(|event| event.block_number).collect::<Vec<_>>();
+        assert_eq!(blocks(rx.try_recv().unwrap().events), vec![4, 4]);
+        assert_eq!(blocks(rx.try_recv().unwrap().events), vec![7]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn backfill_into_memory_storage_is_refused() {
+        let url = node(10, Vec::new(), 0).await;
+        let settings = Settings::from_toml(&format!(
+            r#"
+            network = {{ rpc_url = "{url}" }}
+            storage = {{ backend = "memory" }}
+            pairs = [{{ name = "BTC/USD", windows = ["1h"] }}]
+            "#
+        ));
+        let state = ApplicationConfiguration::new(settings.unwrap()).unwrap();
+
+        let args = BackfillArgs { from_block: Some(0), to_block: None, from_time: None, to_time: None };
+        let error = run(&state, &args).await.unwrap_err();
+        assert!(error.contains("sled"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn first_block_at_timestamp_is_found() {
+        let provider = RpcProvider::new(&network(node(10, Vec::new(), 0).await));
+        let metrics = Metrics::new();
+
+        assert_eq!(first_block_at(&provider, 0, 10, &metrics).await, Ok(0));
+        assert_eq!(first_block_at(&provider, 1_050, 10, &metrics).await, Ok(5));
+        assert_eq!(first_block_at(&provider, 1_051, 10, &metrics).await, Ok(6));
+        assert_eq!(first_block_at(&provider, 2_000, 10, &metrics).await, Ok(11));
+    }
+}
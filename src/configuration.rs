@@ -1,4 +1,16 @@
-use crate::storage::SpotEntryStorage;
+use crate::{
+    aggregation::{AggregationConfiguration, Interpolation, OutlierFilter, QualityGates, Reducer},
+    aggregators::{Aggregator, Twap, parse_aggregator},
+    metrics::{Metrics, fixed_point_to_f64},
+    persistence::{MemoryPersistence, Persistence, SledPersistence},
+    provider::{RpcProvider, RpcStrategy},
+    recording::RecordingConfiguration,
+    settings::{PairSettings, Settings, parse_duration},
+    signing::{Signer, SigningScheme},
+    sources::{EventSource, FileSource, StarknetSource},
+    storage::{Checkpoint, SpotEntryStorage, TwapUpdate},
+    supervisor::{MAX_RESTART_HISTORY, RestartHistory, RestartPolicy},
+};
 
 use secp256k1::{
     Message, PublicKey, Secp256k1, SecretKey,
@@ -6,70 +18,827 @@ use secp256k1::{
     hashes::{Hash, hex::FromHex, sha256},
     rand::rngs::OsRng,
 };
-use std::{env, sync::RwLock};
+use starknet::{
+    core::{chain_id, types::Felt, utils::starknet_keccak},
+    providers::Url,
+    signers::SigningKey,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+const DEFAULT_HOST: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_HEARTBEAT: &str = "15s";
+const DEFAULT_MAX_STALENESS: &str = "5m";
+/// Signed twaps streaming clients can lag behind before they miss some.
+const UPDATES_CAPACITY: usize = 1024;
+const DEFAULT_PAIRS: &str = "BTC/USD";
+const DEFAULT_NETWORK: &str = "sepolia";
+const DEFAULT_BLOCK_TIME: u64 = 30;
+const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_BACKFILL_CHUNK: u64 = 1000;
+const DEFAULT_BACKFILL_PARALLELISM: usize = 4;
+const DEFAULT_REPLAY_SPEED: f64 = 1.0;
+const DEFAULT_WINDOWS: &str = "1h";
+const DEFAULT_STORAGE_CAPACITY: usize = 7200;
+const DEFAULT_STORAGE_BACKEND: &str = "memory";
+const DEFAULT_STORAGE_PATH: &str = "./data";
+const DEFAULT_STORAGE_RETENTION: &str = "1h";
+const DEFAULT_INITIAL_BACKOFF: &str = "1s";
+const DEFAULT_MAX_BACKOFF: &str = "5m";
+const DEFAULT_FAILURE_BUDGET: u32 = 5;
+const DEFAULT_BUDGET_PERIOD: &str = "1h";
 
 pub enum ServiceStatus {
     Running,
     Failed { message: String },
 }
 
+/// Price pair tracked by the service. Pragma identifies pairs by short string encoded as felt, e.g. `BTC/USD`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pair {
+    pub name: String,
+    pub id: Felt,
+    /// TWAP windows calculated for the pair, sorted from shortest to longest.
+    pub windows: Vec<Duration>,
+}
+
+impl Pair {
+    pub fn new(name: &str, windows: &[Duration]) -> Result<Pair, String> {
+        let name = name.trim().to_uppercase();
+
+        // Cairo short strings are limited to 31 ascii characters.
+        if name.is_empty() || name.len() > 31 || !name.is_ascii() {
+            return Err(format!("Invalid pair name: {name:?}"));
+        }
+
+        let mut windows = windows.to_vec();
+        windows.sort();
+        windows.dedup();
+
+        Ok(Pair { id: Felt::from_bytes_be_slice(name.as_bytes()), name, windows })
+    }
+
+    pub fn find_window(&self, window: Duration) -> Option<Duration> {
+        self.windows.iter().find(|w| **w == window).cloned()
+    }
+}
+
+/// Status a block should reach before its events are ingested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finality {
+    /// Latest block accepted on L2. It can be reorganised, orphaned events are rolled back then.
+    Latest,
+    /// Block with state update verified on L1, it can't be reorganised but lags behind for hours.
+    AcceptedOnL1,
+}
+
+impl FromStr for Finality {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "latest" => Ok(Finality::Latest),
+            "accepted_on_l1" => Ok(Finality::AcceptedOnL1),
+            _ => Err(format!("Unknown finality: {value:?}")),
+        }
+    }
+}
+
+/// Starknet network the service is reading Pragma events from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkConfiguration {
+    pub name: String,
+    pub chain_id: Felt,
+    /// JSON-RPC endpoints, calls fail over to the next one on error or timeout.
+    pub rpc_urls: Vec<Url>,
+    pub rpc_strategy: RpcStrategy,
+    pub rpc_timeout: Duration,
+    /// Endpoints that should respond the same to every call, every endpoint is called at once then.
+    pub rpc_quorum: Option<usize>,
+    /// WebSocket endpoint new blocks and events are subscribed to, polling is used when it's not set or drops.
+    pub ws_url: Option<Url>,
+    pub oracle_address: Felt,
+    pub event_selector: Felt,
+    /// Average time between blocks in seconds.
+    pub block_time: u64,
+    pub finality: Finality,
+    /// Blocks events are fetched for at once while catching up with the chain.
+    pub backfill_chunk: u64,
+    /// Chunks fetched concurrently while catching up with the chain.
+    pub backfill_parallelism: usize,
+}
+
+impl NetworkConfiguration {
+    /// Starknet mainnet with public BlastAPI node and Pragma oracle deployment.
+    pub fn mainnet() -> NetworkConfiguration {
+        NetworkConfiguration {
+            name: "mainnet".to_string(),
+            chain_id: chain_id::MAINNET,
+            rpc_urls: vec![Url::parse("https://starknet-mainnet.public.blastapi.io/rpc/v0_7").unwrap()],
+            rpc_strategy: RpcStrategy::Failover,
+            rpc_timeout: DEFAULT_RPC_TIMEOUT,
+            rpc_quorum: None,
+            ws_url: None,
+            oracle_address: Felt::from_hex_unchecked(
+                "0x2a85bd616f912537c50a49a4076db02c00b29b2cdc8a197ce92ed1837fa875b",
+            ),
+            event_selector: starknet_keccak("SubmittedSpotEntry".as_bytes()),
+            block_time: DEFAULT_BLOCK_TIME,
+            finality: Finality::Latest,
+            backfill_chunk: DEFAULT_BACKFILL_CHUNK,
+            backfill_parallelism: DEFAULT_BACKFILL_PARALLELISM,
+        }
+    }
+
+    /// Starknet sepolia with public BlastAPI node and Pragma oracle deployment.
+    pub fn sepolia() -> NetworkConfiguration {
+        NetworkConfiguration {
+            name: "sepolia".to_string(),
+            chain_id: chain_id::SEPOLIA,
+            rpc_urls: vec![Url::parse("https://starknet-sepolia.public.blastapi.io/rpc/v0_7").unwrap()],
+            rpc_strategy: RpcStrategy::Failover,
+            rpc_timeout: DEFAULT_RPC_TIMEOUT,
+            rpc_quorum: None,
+            ws_url: None,
+            oracle_address: Felt::from_hex_unchecked(
+                "0x36031daa264c24520b11d93af622c848b2499b66b41d611bac95e13cfca131a",
+            ),
+            event_selector: starknet_keccak("SubmittedSpotEntry".as_bytes()),
+            block_time: DEFAULT_BLOCK_TIME,
+            finality: Finality::Latest,
+            backfill_chunk: DEFAULT_BACKFILL_CHUNK,
+            backfill_parallelism: DEFAULT_BACKFILL_PARALLELISM,
+        }
+    }
+
+    pub fn preset(name: &str) -> Result<NetworkConfiguration, String> {
+        match name.trim().to_lowercase().as_str() {
+            "mainnet" => Ok(NetworkConfiguration::mainnet()),
+            "sepolia" => Ok(NetworkConfiguration::sepolia()),
+            _ => Err(format!("Unknown network preset: {name:?}")),
+        }
+    }
+}
+
 pub struct ApplicationConfiguration {
-    pub port: u32,
+    pub port: u16,
     pub host: String,
+    /// How often heartbeats are sent to streaming clients.
+    pub heartbeat: Duration,
+    /// Age of signed twap after which service is not ready.
+    pub max_staleness: Duration,
 
-    pub secret_key: SecretKey,
     pub public_key: PublicKey,
+    pub signer: Signer,
+
+    pub network: NetworkConfiguration,
+    pub rpc: Arc<RpcProvider>,
+    /// Where price observations come from: Starknet, or recording when it is replayed.
+    pub source: Box<dyn EventSource>,
 
-    pub storage: RwLock<SpotEntryStorage>,
+    pub pairs: Vec<Pair>,
+    pub storage: HashMap<Felt, RwLock<SpotEntryStorage>>,
+    pub persistence: Box<dyn Persistence>,
+    /// How long history is kept beyond the longest window.
+    pub retention: Duration,
+    /// Last block all events were processed for.
+    pub checkpoint: RwLock<Option<Checkpoint>>,
+    /// Every newly signed twap is published here for streaming clients.
+    pub updates: broadcast::Sender<TwapUpdate>,
+    pub metrics: Metrics,
 
+    pub restart_policy: RestartPolicy,
+    pub recording: RecordingConfiguration,
     pub fetcher_status: RwLock<ServiceStatus>,
     pub processor_status: RwLock<ServiceStatus>,
+    pub fetcher_restarts: RwLock<RestartHistory>,
+    pub processor_restarts: RwLock<RestartHistory>,
 }
 
 impl ApplicationConfiguration {
-    pub fn new() -> Result<ApplicationConfiguration, String> {
+    /// Validates settings and builds configuration out of them.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error listing every invalid field, one per line.
+    pub fn new(settings: Settings) -> Result<ApplicationConfiguration, String> {
         let secp: Secp256k1<secp256k1::All> = Secp256k1::gen_new();
+        let mut errors: Vec<String> = Vec::new();
+
+        let port = settings.server.port.unwrap_or(DEFAULT_PORT);
+        let host = settings.server.host.unwrap_or(DEFAULT_HOST.to_string());
+
+        let heartbeat = settings.server.heartbeat.unwrap_or(DEFAULT_HEARTBEAT.to_string());
+        let heartbeat = parse_positive_duration("server.heartbeat", &heartbeat, &mut errors);
+
+        let max_staleness = settings.server.max_staleness.unwrap_or(DEFAULT_MAX_STALENESS.to_string());
+        let max_staleness = parse_positive_duration("server.max_staleness", &max_staleness, &mut errors);
+
+        let network_name = settings.network.preset.unwrap_or(DEFAULT_NETWORK.to_string());
+        let mut network = NetworkConfiguration::preset(&network_name).unwrap_or_else(|e| {
+            errors.push(format!("network.preset: {e}"));
+            NetworkConfiguration::sepolia()
+        });
 
-        let port: u32 = if let Ok(key) = env::var("PORT") {
-            key.parse().map_err(|_| "Value in PORT variable is invalid")?
-        } else {
-            3000_u32
+        // List of endpoints takes precedence over a single one.
+        let rpc_urls = match (settings.network.rpc_urls, settings.network.rpc_url) {
+            (Some(urls), _) => Some(("network.rpc_urls", urls)),
+            (None, Some(url)) => Some(("network.rpc_url", vec![url])),
+            (None, None) => None,
         };
 
-        let host: String = if let Ok(key) = env::var("host") { key } else { "0.0.0.0".to_string() };
+        if let Some((field, urls)) = rpc_urls {
+            let mut rpc_urls = Vec::new();
+            for url in urls {
+                match Url::parse(&url) {
+                    Ok(url) => rpc_urls.push(url),
+                    Err(_) => errors.push(format!("{field}: Invalid url {url:?}")),
+                }
+            }
 
-        let secret_key = if let Ok(key) = env::var("SECRET_KEY") {
-            let secret_bytes = <[u8; 32]>::from_hex(key.as_str()).map_err(|_| "Invalid env var SECRET_KEY")?;
+            if rpc_urls.is_empty() {
+                errors.push(format!("{field}: At least one url is required"));
+            } else {
+                network.rpc_urls = rpc_urls;
+            }
+        }
 
-            SecretKey::from_byte_array(&secret_bytes).map_err(|_| "Secret key format invalid")?
-        } else {
-            let (secret_key, _) = secp.generate_keypair(&mut OsRng);
-            secret_key
+        match settings.network.rpc_strategy.as_deref().map(RpcStrategy::from_str) {
+            Some(Ok(strategy)) => network.rpc_strategy = strategy,
+            Some(Err(e)) => errors.push(format!("network.rpc_strategy: {e}")),
+            None => {}
+        }
+
+        if let Some(rpc_timeout) = settings.network.rpc_timeout {
+            network.rpc_timeout = parse_positive_duration("network.rpc_timeout", &rpc_timeout, &mut errors);
+        }
+
+        match settings.network.rpc_quorum {
+            Some(quorum) if quorum == 0 || quorum > network.rpc_urls.len() => {
+                errors.push("network.rpc_quorum: Should be within 1 and number of RPC urls".to_string())
+            }
+            quorum => network.rpc_quorum = quorum,
+        }
+
+        if let Some(address) = settings.network.oracle_address {
+            match Felt::from_hex(&address) {
+                Ok(address) => network.oracle_address = address,
+                Err(_) => errors.push(format!("network.oracle_address: Invalid hex value {address:?}")),
+            }
+        }
+
+        if let Some(selector) = settings.network.event_selector {
+            match Felt::from_hex(&selector) {
+                Ok(selector) => network.event_selector = selector,
+                Err(_) => errors.push(format!("network.event_selector: Invalid hex value {selector:?}")),
+            }
+        }
+
+        match settings.network.block_time {
+            Some(0) => errors.push("network.block_time: Should be greater than zero".to_string()),
+            Some(block_time) => network.block_time = block_time,
+            None => {}
+        }
+
+        match settings.network.finality.as_deref().map(Finality::from_str) {
+            Some(Ok(finality)) => network.finality = finality,
+            Some(Err(e)) => errors.push(format!("network.finality: {e}")),
+            None => {}
+        }
+
+        match settings.network.backfill_chunk {
+            Some(0) => errors.push("network.backfill_chunk: Should be greater than zero".to_string()),
+            Some(backfill_chunk) => network.backfill_chunk = backfill_chunk,
+            None => {}
+        }
+
+        match settings.network.backfill_parallelism {
+            Some(0) => errors.push("network.backfill_parallelism: Should be greater than zero".to_string()),
+            Some(backfill_parallelism) => network.backfill_parallelism = backfill_parallelism,
+            None => {}
+        }
+
+        if let Some(url) = settings.network.ws_url {
+            match Url::parse(&url) {
+                Ok(url) if !matches!(url.scheme(), "ws" | "wss") => {
+                    errors.push(format!("network.ws_url: Should be ws:// or wss:// url, got {url:?}"))
+                }
+                Ok(_) if network.finality == Finality::AcceptedOnL1 => errors
+                    .push("network.ws_url: Subscriptions are not supported with accepted_on_l1 finality".to_string()),
+                Ok(_) if network.rpc_quorum.is_some() => {
+                    errors.push("network.ws_url: Subscriptions are not supported with RPC quorum".to_string())
+                }
+                Ok(url) => network.ws_url = Some(url),
+                Err(_) => errors.push(format!("network.ws_url: Invalid url {url:?}")),
+            }
+        }
+
+        let default_windows =
+            settings.window.durations.unwrap_or_else(|| DEFAULT_WINDOWS.split(',').map(|w| w.to_string()).collect());
+        let default_windows = parse_windows("window.durations", &default_windows, &mut errors);
+
+        let pair_settings = settings.pairs.unwrap_or_else(|| {
+            DEFAULT_PAIRS.split(',').map(|name| PairSettings { name: name.to_string(), ..Default::default() }).collect()
+        });
+
+        if pair_settings.is_empty() {
+            errors.push("pairs: At least one pair should be configured".to_string());
+        }
+
+        let default_aggregator: Arc<dyn Aggregator> =
+            match settings.aggregation.aggregator.as_deref().map(parse_aggregator) {
+                Some(Ok(aggregator)) => aggregator,
+                Some(Err(e)) => {
+                    errors.push(format!("aggregation.aggregator: {e}"));
+                    Arc::new(Twap)
+                }
+                None => Arc::new(Twap),
+            };
+
+        let mut pairs: Vec<Pair> = Vec::new();
+        let mut pair_aggregators: HashMap<Felt, BTreeMap<Duration, Arc<dyn Aggregator>>> = HashMap::new();
+        for (idx, pair) in pair_settings.iter().enumerate() {
+            let windows = match &pair.windows {
+                Some(windows) => parse_windows(&format!("pairs[{idx}].windows"), windows, &mut errors),
+                None => default_windows.clone(),
+            };
+
+            // Window specific aggregator takes precedence over pair one, which takes precedence over default one.
+            let aggregator = match pair.aggregator.as_deref().map(parse_aggregator) {
+                Some(Ok(aggregator)) => aggregator,
+                Some(Err(e)) => {
+                    errors.push(format!("pairs[{idx}].aggregator: {e}"));
+                    default_aggregator.clone()
+                }
+                None => default_aggregator.clone(),
+            };
+
+            let mut aggregators: BTreeMap<Duration, Arc<dyn Aggregator>> =
+                windows.iter().map(|window| (*window, aggregator.clone())).collect();
+
+            for (window, aggregator) in pair.aggregators.iter().flatten() {
+                let field = format!("pairs[{idx}].aggregators.{window}");
+                match (parse_duration(window), parse_aggregator(aggregator)) {
+                    (Ok(seconds), Ok(aggregator)) if aggregators.contains_key(&Duration::from_secs(seconds)) => {
+                        aggregators.insert(Duration::from_secs(seconds), aggregator);
+                    }
+                    (Ok(_), Ok(_)) => errors.push(format!("{field}: Window is not configured for the pair")),
+                    (Err(e), _) | (_, Err(e)) => errors.push(format!("{field}: {e}")),
+                }
+            }
+
+            match Pair::new(&pair.name, &windows) {
+                Ok(pair) if pairs.iter().any(|p| p.id == pair.id) => {
+                    errors.push(format!("pairs[{idx}]: Duplicate pair {}", pair.name))
+                }
+                Ok(pair) => {
+                    pair_aggregators.insert(pair.id, aggregators);
+                    pairs.push(pair);
+                }
+                Err(e) => errors.push(format!("pairs[{idx}]: {e}")),
+            }
+        }
+
+        let mut reducer = match settings.aggregation.reducer.as_deref().map(Reducer::from_str) {
+            Some(Ok(reducer)) => reducer,
+            Some(Err(e)) => {
+                errors.push(format!("aggregation.reducer: {e}"));
+                Reducer::Median
+            }
+            None => Reducer::Median,
+        };
+
+        if let Some(publisher_weights) = settings.aggregation.publisher_weights {
+            match &mut reducer {
+                Reducer::PublisherWeighted(weights) => {
+                    for (publisher, weight) in publisher_weights {
+                        // Publishers are short strings just like pairs.
+                        if publisher.is_empty() || publisher.len() > 31 || !publisher.is_ascii() {
+                            errors.push(format!("aggregation.publisher_weights: Invalid publisher name {publisher:?}"));
+                        } else {
+                            weights.insert(Felt::from_bytes_be_slice(publisher.as_bytes()), weight);
+                        }
+                    }
+                }
+                _ => errors.push(
+                    "aggregation.publisher_weights: Weights are used by publisher_weighted reducer only".to_string(),
+                ),
+            }
+        }
+
+        let limits = [
+            ("aggregation.mad_cutoff", settings.aggregation.mad_cutoff),
+            ("aggregation.max_jump", settings.aggregation.max_jump),
+            ("aggregation.max_publisher_deviation", settings.aggregation.max_publisher_deviation),
+        ];
+
+        for (field, limit) in limits {
+            if limit.is_some_and(|limit| !limit.is_finite() || limit <= 0.0) {
+                errors.push(format!("{field}: Should be a positive number"));
+            }
+        }
+
+        let outliers = OutlierFilter {
+            mad_cutoff: settings.aggregation.mad_cutoff,
+            max_jump: settings.aggregation.max_jump,
+            max_publisher_deviation: settings.aggregation.max_publisher_deviation,
+        };
+
+        let interpolation = match settings.aggregation.interpolation.as_deref().map(Interpolation::from_str) {
+            Some(Ok(interpolation)) => interpolation,
+            Some(Err(e)) => {
+                errors.push(format!("aggregation.interpolation: {e}"));
+                Interpolation::Left
+            }
+            None => Interpolation::Left,
         };
 
-        let public_key = if let Ok(key) = env::var("PUBLIC_KEY") {
-            let public_bytes =
-                <[u8; PUBLIC_KEY_SIZE]>::from_hex(key.as_str()).map_err(|_| "Invalid env var PUBLIC_KEY")?;
+        if settings.quality.min_coverage.is_some_and(|coverage| !(coverage > 0.0 && coverage <= 1.0)) {
+            errors.push("quality.min_coverage: Should be within (0, 1]".to_string());
+        }
 
-            PublicKey::from_byte_array_compressed(&public_bytes).map_err(|_| "Public key format invalid")?
-        } else {
-            PublicKey::from_secret_key(&secp, &secret_key)
+        let quality = QualityGates {
+            min_samples: settings.quality.min_samples,
+            min_publishers: settings.quality.min_publishers,
+            max_gap: settings
+                .quality
+                .max_gap
+                .map(|max_gap| parse_positive_duration("quality.max_gap", &max_gap, &mut errors).as_secs()),
+            min_coverage: settings.quality.min_coverage,
         };
 
-        let digest = sha256::Hash::hash([0_u8, 0_u8, 0_u8, 0_u8].as_slice());
-        let message = Message::from_digest(digest.to_byte_array());
-        let signature = secp.sign_ecdsa(&message, &secret_key);
+        let aggregation =
+            AggregationConfiguration { reducer, outliers, interpolation, quality, aggregators: BTreeMap::new() };
+
+        let capacity = settings.storage.capacity.unwrap_or(DEFAULT_STORAGE_CAPACITY);
+
+        let retention = settings.storage.retention.unwrap_or(DEFAULT_STORAGE_RETENTION.to_string());
+        let retention = parse_duration(&retention).map(Duration::from_secs).unwrap_or_else(|e| {
+            errors.push(format!("storage.retention: {e}"));
+            Duration::ZERO
+        });
+
+        let supervisor = settings.supervisor;
+        let initial_backoff = supervisor.initial_backoff.unwrap_or(DEFAULT_INITIAL_BACKOFF.to_string());
+        let max_backoff = supervisor.max_backoff.unwrap_or(DEFAULT_MAX_BACKOFF.to_string());
+        let budget_period = supervisor.budget_period.unwrap_or(DEFAULT_BUDGET_PERIOD.to_string());
+        let restart_policy = RestartPolicy {
+            initial_backoff: parse_positive_duration("supervisor.initial_backoff", &initial_backoff, &mut errors),
+            max_backoff: parse_positive_duration("supervisor.max_backoff", &max_backoff, &mut errors),
+            failure_budget: supervisor.failure_budget.unwrap_or(DEFAULT_FAILURE_BUDGET),
+            budget_period: parse_positive_duration("supervisor.budget_period", &budget_period, &mut errors),
+        };
+
+        if restart_policy.max_backoff < restart_policy.initial_backoff {
+            errors.push("supervisor.max_backoff: Should not be less than initial backoff".to_string());
+        }
+
+        // Failures are counted within restart history, so larger budget would never be exhausted.
+        if restart_policy.failure_budget as usize >= MAX_RESTART_HISTORY {
+            errors.push(format!("supervisor.failure_budget: Should be less than {MAX_RESTART_HISTORY}"));
+        }
+
+        let recording = RecordingConfiguration {
+            record: settings.recording.record,
+            replay: settings.recording.replay,
+            replay_speed: settings.recording.replay_speed.unwrap_or(DEFAULT_REPLAY_SPEED),
+        };
 
-        secp.verify_ecdsa(&message, &signature, &public_key).map_err(|_| "Public and Secret keys do not match.")?;
+        if !(recording.replay_speed.is_finite() && recording.replay_speed > 0.0) {
+            errors.push("recording.replay_speed: Should be greater than zero".to_string());
+        }
+
+        // Backend is opened only once settings are valid, there is no point in holding database of invalid setup.
+        let backend = settings.storage.backend.unwrap_or(DEFAULT_STORAGE_BACKEND.to_string()).trim().to_lowercase();
+        let storage_path = settings.storage.path.unwrap_or(PathBuf::from(DEFAULT_STORAGE_PATH));
+        match backend.as_str() {
+            "memory" => {}
+            "sled" if storage_path.as_os_str().is_empty() => {
+                errors.push("storage.path: Should not be empty".to_string())
+            }
+            "sled" if storage_path.exists() && !storage_path.is_dir() => {
+                errors.push(format!("storage.path: {} is not a directory", storage_path.display()))
+            }
+            "sled" => {}
+            _ => errors.push(format!("storage.backend: Unknown backend {backend:?}")),
+        }
+
+        // Replay must not touch production state: stored events and twaps, or keys attestations are trusted by.
+        if recording.replay.is_some() {
+            if backend == "sled" {
+                errors.push("recording.replay: Can't replay into sled storage, use memory backend".to_string());
+            }
+
+            if settings.signing.secret_key.is_some() || settings.signing.stark_secret_key.is_some() {
+                errors.push("recording.replay: Can't sign replayed twaps with configured keys".to_string());
+            }
+        }
+
+        let secret_key = match settings.signing.secret_key {
+            Some(key) => <[u8; 32]>::from_hex(key.as_str())
+                .map_err(|_| "signing.secret_key: Invalid hex value".to_string())
+                .and_then(|bytes| {
+                    SecretKey::from_byte_array(&bytes).map_err(|_| "signing.secret_key: Format invalid".to_string())
+                })
+                .map_err(|e| errors.push(e))
+                .ok(),
+            None => Some(secp.generate_keypair(&mut OsRng).0),
+        };
+
+        let public_key = match settings.signing.public_key {
+            Some(key) => <[u8; PUBLIC_KEY_SIZE]>::from_hex(key.as_str())
+                .map_err(|_| "signing.public_key: Invalid hex value".to_string())
+                .and_then(|bytes| {
+                    PublicKey::from_byte_array_compressed(&bytes)
+                        .map_err(|_| "signing.public_key: Format invalid".to_string())
+                })
+                .map_err(|e| errors.push(e))
+                .ok(),
+            None => secret_key.map(|secret_key| PublicKey::from_secret_key(&secp, &secret_key)),
+        };
+
+        if let (Some(secret_key), Some(public_key)) = (secret_key, public_key) {
+            let digest = sha256::Hash::hash([0_u8, 0_u8, 0_u8, 0_u8].as_slice());
+            let message = Message::from_digest(digest.to_byte_array());
+            let signature = secp.sign_ecdsa(&message, &secret_key);
+
+            if secp.verify_ecdsa(&message, &signature, &public_key).is_err() {
+                errors.push("signing: Public and Secret keys do not match.".to_string());
+            }
+        }
+
+        let scheme = match settings.signing.scheme.as_deref().map(SigningScheme::from_str) {
+            Some(Ok(scheme)) => scheme,
+            Some(Err(e)) => {
+                errors.push(format!("signing.scheme: {e}"));
+                SigningScheme::Secp256k1
+            }
+            None => SigningScheme::Secp256k1,
+        };
+
+        let stark_key = match settings.signing.stark_secret_key {
+            Some(key) => match Felt::from_hex(&key) {
+                Ok(scalar) if scalar != Felt::ZERO => Some(SigningKey::from_secret_scalar(scalar)),
+                _ => {
+                    errors.push("signing.stark_secret_key: Invalid hex value".to_string());
+                    None
+                }
+            },
+            None => Some(SigningKey::from_random()),
+        };
+
+        let (Some(secret_key), Some(public_key), Some(stark_key), true) =
+            (secret_key, public_key, stark_key, errors.is_empty())
+        else {
+            return Err(errors.join("\n"));
+        };
+
+        let persistence: Box<dyn Persistence> = match backend.as_str() {
+            "sled" => Box::new(SledPersistence::open(&storage_path).map_err(|e| format!("storage.path: {e}"))?),
+            _ => Box::new(MemoryPersistence),
+        };
+
+        let storage = pairs
+            .iter()
+            .map(|pair| {
+                let aggregators = pair_aggregators.remove(&pair.id).unwrap_or_default();
+                let aggregation = AggregationConfiguration { aggregators, ..aggregation.clone() };
+                let storage = SpotEntryStorage::new(network.chain_id, pair.id, capacity, aggregation);
+                (pair.id, RwLock::new(storage))
+            })
+            .collect();
+
+        let rpc = Arc::new(RpcProvider::new(&network));
+        let source: Box<dyn EventSource> = match &recording.replay {
+            Some(path) => Box::new(FileSource { path: path.clone(), speed: recording.replay_speed }),
+            None => Box::new(StarknetSource {
+                network: network.clone(),
+                provider: rpc.clone(),
+                history: pairs.iter().flat_map(|pair| pair.windows.iter()).max().cloned().unwrap_or_default() +
+                    retention,
+                pair_ids: pairs.iter().map(|pair| pair.id).collect(),
+            }),
+        };
 
         Ok(ApplicationConfiguration {
             host,
             port,
-            secret_key,
+            heartbeat,
+            max_staleness,
             public_key,
-            storage: RwLock::new(SpotEntryStorage::new()),
+            signer: Signer::new(scheme, secret_key, stark_key),
+            rpc,
+            source,
+            network,
+            pairs,
+            storage,
+            persistence,
+            retention,
+            checkpoint: RwLock::new(None),
+            updates: broadcast::channel(UPDATES_CAPACITY).0,
+            metrics: Metrics::new(),
+            restart_policy,
+            recording,
             fetcher_status: RwLock::new(ServiceStatus::Running),
             processor_status: RwLock::new(ServiceStatus::Running),
+            fetcher_restarts: RwLock::default(),
+            processor_restarts: RwLock::default(),
         })
     }
+
+    pub fn find_pair(&self, name: &str) -> Option<&Pair> {
+        let name = name.trim().to_uppercase();
+        self.pairs.iter().find(|pair| pair.name == name)
+    }
+
+    /// Restores events, signed twaps of every pair and the last processed block from persistence backend. Twaps that
+    /// aren't signed with current keys and scheme for current chain are dropped, only their sequence is kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if can't acqure storage write lock.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if persistence backend fails to load data.
+    pub fn restore(&self) -> Result<(), String> {
+        for pair in self.pairs.iter() {
+            let mut storage = self.storage[&pair.id].write().unwrap();
+
+            for event in self.persistence.load_events(pair.id)? {
+                storage.append(event);
+            }
+
+            for window in pair.windows.iter() {
+                if let Some(signed) = self.persistence.load_signed_twap(pair.id, *window)? {
+                    storage.sequences.insert(*window, signed.attestation.sequence);
+
+                    // Keys are random unless configured, scheme or network could have changed since twap was signed.
+                    let attestation = &signed.attestation;
+                    if attestation.chain_id != self.network.chain_id ||
+                        attestation.pair_id != pair.id ||
+                        !self.signer.verify(attestation, &signed.signature)
+                    {
+                        println!(
+                            "Dropping {} {}s twap signed with other keys or for other chain",
+                            pair.name,
+                            window.as_secs()
+                        );
+                        continue;
+                    }
+
+                    let labels = [pair.name.as_str(), &window.as_secs().to_string()];
+                    self.metrics.twap.with_label_values(&labels).set(fixed_point_to_f64(&signed.attestation.twap));
+                    storage.twaps.insert(*window, signed);
+                }
+            }
+        }
+
+        let checkpoint = self.persistence.load_checkpoint()?;
+        if let Some(checkpoint) = checkpoint {
+            self.metrics.processed_block.set(checkpoint.block_number as i64);
+        }
+
+        *self.checkpoint.write().unwrap() = checkpoint;
+
+        Ok(())
+    }
+
+    /// Longest window among all pairs. Events older than that are not needed for the latest twaps.
+    pub fn max_window(&self) -> Duration {
+        self.pairs.iter().flat_map(|pair| pair.windows.iter()).max().cloned().unwrap_or_default()
+    }
+
+    /// Longest window along with retention period, events older than that are not needed at all.
+    pub fn history(&self) -> Duration {
+        self.max_window() + self.retention
+    }
+}
+
+/// Parses duration that should be positive, error is reported to `errors` under `field` name.
+fn parse_positive_duration(field: &str, value: &str, errors: &mut Vec<String>) -> Duration {
+    match parse_duration(value) {
+        Ok(0) => {
+            errors.push(format!("{field}: Should be positive"));
+            Duration::ZERO
+        }
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(e) => {
+            errors.push(format!("{field}: {e}"));
+            Duration::ZERO
+        }
+    }
+}
+
+/// Parses list of window durations, every invalid one is reported to `errors` under `field` name.
+fn parse_windows(field: &str, windows: &[String], errors: &mut Vec<String>) -> Vec<Duration> {
+    if windows.is_empty() {
+        errors.push(format!("{field}: At least one window should be configured"));
+    }
+
+    let mut durations = Vec::new();
+    for (idx, window) in windows.iter().enumerate() {
+        match parse_duration(window) {
+            Ok(0) => errors.push(format!("{field}[{idx}]: Should be greater than zero")),
+            Ok(seconds) => durations.push(Duration::from_secs(seconds)),
+            Err(e) => errors.push(format!("{field}[{idx}]: {e}")),
+        }
+    }
+
+    durations
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::SpotEntryEvent;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+    const SECRET_KEY: &str = "d150f1224d8c75c25f186d0d18c058201a4f6e9ca13237ade9eb9988ef391de5";
+
+    fn configuration(secret_key: Option<&str>) -> ApplicationConfiguration {
+        let signing = secret_key.map(|key| format!("signing = {{ secret_key = {key:?} }}")).unwrap_or_default();
+        let settings =
+            Settings::from_toml(&format!("{signing}\npairs = [{{ name = \"BTC/USD\", windows = [\"1h\"] }}]"));
+        ApplicationConfiguration::new(settings.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn every_invalid_field_is_reported() {
+        let settings = Settings::from_toml(
+            r#"
+            signing = { scheme = "rsa" }
+            storage = { retention = "forever" }
+            supervisor = { initial_backoff = "10m", max_backoff = "1m" }
+            recording = { replay_speed = 0.0 }
+            "#,
+        )
+        .unwrap();
+
+        let Err(message) = ApplicationConfiguration::new(settings) else {
+            panic!("Configuration should be invalid");
+        };
+
+        let errors: Vec<&str> = message.lines().collect();
+        for field in ["signing.scheme: ", "storage.retention: ", "supervisor.max_backoff: ", "recording.replay_speed: "]
+        {
+            assert_eq!(errors.iter().filter(|error| error.starts_with(field)).count(), 1, "{field} in {message}");
+        }
+        assert_eq!(errors.len(), 4);
+    }
+
+    #[test]
+    fn failure_budget_is_less_than_restart_history() {
+        let budget = |failure_budget: usize| {
+            let settings = Settings::from_toml(&format!("supervisor = {{ failure_budget = {failure_budget} }}"));
+            ApplicationConfiguration::new(settings.unwrap()).map(|state| state.restart_policy.failure_budget)
+        };
+
+        assert_eq!(budget(MAX_RESTART_HISTORY - 1), Ok(MAX_RESTART_HISTORY as u32 - 1));
+        assert!(budget(MAX_RESTART_HISTORY).is_err_and(|message| message.starts_with("supervisor.failure_budget: ")));
+    }
+
+    #[test]
+    fn twaps_signed_with_other_keys_are_not_restored() {
+        let path = std::env::temp_dir().join(format!("twapper-restore-{}", std::process::id()));
+        let state = ApplicationConfiguration {
+            persistence: Box::new(SledPersistence::open(&path).unwrap()),
+            ..configuration(Some(SECRET_KEY))
+        };
+
+        let (pair_id, now) = (state.pairs[0].id, 100_000);
+        {
+            let mut storage = state.storage[&pair_id].write().unwrap();
+            storage.append(SpotEntryEvent { timestamp: now - 10, price: 100, pair_id, ..Default::default() });
+            storage.calculate_and_sign_twap(HOUR, now, &state.signer);
+            state.persistence.store_signed_twap(pair_id, HOUR, &storage.twaps[&HOUR]).unwrap();
+        }
+
+        // Same keys after restart
+        let state = ApplicationConfiguration { persistence: state.persistence, ..configuration(Some(SECRET_KEY)) };
+        state.restore().unwrap();
+        assert_eq!(state.storage[&pair_id].read().unwrap().twaps[&HOUR].attestation.sequence, 1);
+
+        // Random keys after restart
+        let state = ApplicationConfiguration { persistence: state.persistence, ..configuration(None) };
+        state.restore().unwrap();
+
+        let mut storage = state.storage[&pair_id].write().unwrap();
+        assert!(storage.twaps.is_empty());
+
+        storage.append(SpotEntryEvent { timestamp: now - 10, price: 100, pair_id, ..Default::default() });
+        storage.calculate_and_sign_twap(HOUR, now + 1, &state.signer);
+        assert_eq!(storage.twaps[&HOUR].attestation.sequence, 2);
+
+        drop(storage);
+        drop(state);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
 }
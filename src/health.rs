@@ -0,0 +1,253 @@
+use crate::{
+    configuration::{ApplicationConfiguration, ServiceStatus},
+    supervisor::{Restart, RestartHistory},
+};
+use axum::{
+    Json,
+    extract::State,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::{AppendHeaders, IntoResponse},
+};
+use serde::Serialize;
+use std::{
+    ops::Deref,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+/// Detailed state of the service, ready when no worker has failed and every window has fresh signed twap.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub ready: bool,
+    /// Reasons service is not ready.
+    pub problems: Vec<String>,
+    /// The latest block on chain seen by fetcher.
+    pub chain_head: Option<u64>,
+    /// The last block all events were processed for.
+    pub processed_block: Option<u64>,
+    pub fetcher: WorkerReport,
+    pub processor: WorkerReport,
+    pub pairs: Vec<PairReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerReport {
+    pub failed: bool,
+    pub restarts: usize,
+    pub last_restart: Option<Restart>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairReport {
+    pub pair: String,
+    /// Seconds since the latest stored entry, publishers timestamp entries themselves.
+    pub last_event_age: Option<u64>,
+    pub windows: Vec<WindowReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WindowReport {
+    pub window: u64,
+    /// Seconds since the latest twap of the window was signed.
+    pub signed_age: Option<u64>,
+    /// Entries of every publisher submitted within the window.
+    pub samples: usize,
+    /// Quality gate the latest calculation has failed, signed twap is stale then.
+    pub insufficient_data: Option<String>,
+}
+
+impl WorkerReport {
+    fn new(status: &RwLock<ServiceStatus>, restarts: &RwLock<RestartHistory>) -> WorkerReport {
+        let restarts = restarts.read().unwrap();
+
+        WorkerReport {
+            failed: matches!(status.read().unwrap().deref(), ServiceStatus::Failed { .. }),
+            restarts: restarts.total,
+            last_restart: restarts.restarts.back().cloned(),
+        }
+    }
+}
+
+impl HealthReport {
+    /// Collects state of workers and every pair at `now`. Service is not ready if any worker has failed, or if any
+    /// window has no signed twap, it was signed more than `max_staleness` ago or window has failed quality gates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if can't acqure storage or status read lock.
+    pub fn new(state: &ApplicationConfiguration, now: u64) -> HealthReport {
+        let mut problems = Vec::new();
+
+        let fetcher = WorkerReport::new(&state.fetcher_status, &state.fetcher_restarts);
+        let processor = WorkerReport::new(&state.processor_status, &state.processor_restarts);
+        for (name, worker) in [("fetcher", &fetcher), ("processor", &processor)] {
+            if worker.failed {
+                let message = worker.last_restart.as_ref().map(|restart| restart.message.as_str()).unwrap_or_default();
+                problems.push(format!("Worker {name} has failed: {message}"));
+            }
+        }
+
+        let pairs = state
+            .pairs
+            .iter()
+            .map(|pair| {
+                let storage = state.storage[&pair.id].read().unwrap();
+
+                let windows = pair
+                    .windows
+                    .iter()
+                    .map(|window| {
+                        let signed_age =
+                            storage.twaps.get(window).map(|signed| now.saturating_sub(signed.attestation.timestamp));
+
+                        match signed_age {
+                            None => {
+                                problems.push(format!("{} {}s: Twap is not signed yet", pair.name, window.as_secs()))
+                            }
+                            Some(age) if age > state.max_staleness.as_secs() => {
+                                problems.push(format!("{} {}s: Twap is signed {age}s ago", pair.name, window.as_secs()))
+                            }
+                            Some(_) => {}
+                        }
+
+                        let insufficient_data = storage.insufficient.get(window).map(|e| e.to_string());
+                        if let Some(insufficient) = &insufficient_data {
+                            problems.push(format!(
+                                "{} {}s: Insufficient data: {insufficient}",
+                                pair.name,
+                                window.as_secs()
+                            ))
+                        }
+
+                        let samples = storage.samples(now.saturating_sub(window.as_secs()), now);
+                        WindowReport { window: window.as_secs(), signed_age, samples, insufficient_data }
+                    })
+                    .collect();
+
+                PairReport {
+                    pair: pair.name.clone(),
+                    last_event_age: storage.last_timestamp().map(|timestamp| now.saturating_sub(timestamp)),
+                    windows,
+                }
+            })
+            .collect();
+
+        let chain_head = u64::try_from(state.metrics.chain_head.get()).ok().filter(|block| *block > 0);
+
+        HealthReport {
+            ready: problems.is_empty(),
+            problems,
+            chain_head,
+            processed_block: state.checkpoint.read().unwrap().map(|checkpoint| checkpoint.block_number),
+            fetcher,
+            processor,
+            pairs,
+        }
+    }
+}
+
+/// Liveness: fails only when a worker has failed for good, restarting it is up to supervisor.
+pub async fn livez_handler(State(state): State<Arc<ApplicationConfiguration>>) -> impl IntoResponse {
+    if let ServiceStatus::Failed { message } = state.fetcher_status.read().unwrap().deref() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            AppendHeaders([(CONTENT_TYPE, "application/json")]),
+            Json(Result::Err(message.to_string())),
+        );
+    }
+
+    if let ServiceStatus::Failed { message } = state.processor_status.read().unwrap().deref() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            AppendHeaders([(CONTENT_TYPE, "application/json")]),
+            Json(Result::Err(message.to_string())),
+        );
+    }
+
+    (StatusCode::OK, AppendHeaders([(CONTENT_TYPE, "application/json")]), Json(Result::Ok("Good".to_string())))
+}
+
+/// Readiness: detailed health report, status is `503` if data is stale or a worker has failed.
+pub async fn readyz_handler(State(state): State<Arc<ApplicationConfiguration>>) -> impl IntoResponse {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let report = HealthReport::new(&state, now);
+
+    if report.ready {
+        (StatusCode::OK, AppendHeaders([(CONTENT_TYPE, "application/json")]), Json(Result::Ok(report)))
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            AppendHeaders([(CONTENT_TYPE, "application/json")]),
+            Json(Result::Err(report)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{settings::Settings, storage::SpotEntryEvent};
+    use std::time::Duration;
+
+    fn state() -> ApplicationConfiguration {
+        let settings = Settings::from_toml(
+            r#"
+            pairs = [{ name = "BTC/USD", windows = ["1h", "24h"] }]
+
+            [server]
+            max_staleness = "5m"
+            "#,
+        )
+        .unwrap();
+
+        ApplicationConfiguration::new(settings).unwrap()
+    }
+
+    #[test]
+    fn service_is_ready_with_fresh_twaps() {
+        let state = state();
+        let pair_id = state.pairs[0].id;
+        let now = 100_000;
+
+        let report = HealthReport::new(&state, now);
+        assert!(!report.ready);
+        assert_eq!(report.problems.len(), 2);
+
+        {
+            let mut storage = state.storage[&pair_id].write().unwrap();
+            for timestamp in [now - 7200, now - 600, now - 60] {
+                storage.append(SpotEntryEvent { timestamp, price: 100, pair_id, ..Default::default() });
+            }
+
+            storage.calculate_and_sign_twap(Duration::from_secs(3600), now, &state.signer);
+            storage.calculate_and_sign_twap(Duration::from_secs(86400), now - 600, &state.signer);
+        }
+
+        let report = HealthReport::new(&state, now);
+        assert_eq!(report.problems, vec!["BTC/USD 86400s: Twap is signed 600s ago".to_string()]);
+        assert_eq!(report.pairs[0].last_event_age, Some(60));
+
+        let windows: Vec<_> = report.pairs[0].windows.iter().map(|w| (w.window, w.signed_age, w.samples)).collect();
+        assert_eq!(windows, vec![(3600, Some(0), 2), (86400, Some(600), 3)]);
+
+        state.storage[&pair_id].write().unwrap().calculate_and_sign_twap(
+            Duration::from_secs(86400),
+            now,
+            &state.signer,
+        );
+        assert!(HealthReport::new(&state, now).ready);
+    }
+
+    #[test]
+    fn failed_worker_is_reported() {
+        let state = state();
+        state.processor_restarts.write().unwrap().record(Restart { timestamp: 1, message: "Boom".to_string() });
+        *state.processor_status.write().unwrap() = ServiceStatus::Failed { message: "Boom".to_string() };
+
+        let report = HealthReport::new(&state, 100_000);
+
+        assert!(report.processor.failed && !report.fetcher.failed);
+        assert_eq!(report.processor.restarts, 1);
+        assert!(report.problems.contains(&"Worker processor has failed: Boom".to_string()));
+    }
+}
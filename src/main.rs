@@ -1,106 +1,444 @@
+mod accumulator;
+mod aggregation;
+mod aggregators;
+mod attestation;
+mod backfill;
 mod configuration;
+mod health;
+mod metrics;
+mod persistence;
+mod provider;
+mod recording;
+mod settings;
+mod signing;
+mod sources;
 mod storage;
+mod streaming;
+mod supervisor;
+mod websocket;
 mod workers;
 
-use configuration::{ApplicationConfiguration, ServiceStatus};
+use aggregation::{InsufficientData, Rejections};
+use attestation::{Anchor, PROTOCOL_TAG};
+use clap::Parser;
+use configuration::{ApplicationConfiguration, Pair};
+use recording::Recorder;
 use secp256k1::hashes::hex::DisplayHex;
-use serde::Serialize;
-use std::{ops::Deref, sync::Arc};
-use storage::SpotEntryEvent;
+use serde::{Deserialize, Serialize};
+use settings::{Cli, Command, Settings, parse_duration};
+use signing::{AttestationSignature, checksum_address, ethereum_address};
+use starknet::core::utils::parse_cairo_short_string;
+use std::{
+    collections::BTreeMap,
+    process::exit,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use storage::{EventBatch, SignedTwap};
 use tokio::sync::mpsc;
 use workers::WorkerRunner;
 
 use axum::{
     Json, Router,
-    extract::State,
-    http::{StatusCode, header::CONTENT_TYPE},
-    response::{AppendHeaders, IntoResponse},
+    extract::{MatchedPath, Query, Request, State},
+    http::{HeaderName, StatusCode, header::CONTENT_TYPE},
+    middleware::{self, Next},
+    response::{AppendHeaders, IntoResponse, Response},
     routing::get,
 };
 
 // TODO: add proper serialiser
 #[derive(Serialize)]
 struct Data {
+    pair: String,
+    window: u64,
+    protocol: String,
+    version: u8,
+    chain_id: String,
+    pair_id: String,
+    /// Aggregator and interpolation value is calculated with, e.g. `twap/left`.
+    algorithm: String,
+    window_start: u64,
+    window_end: u64,
+    timestamp: u64,
+    sequence: u64,
+    /// What window ends at: the latest data, requested time or requested block.
+    anchor: Anchor,
     twap: String,
+    payload: String,
+    scheme: String,
     signature: String,
     pk: String,
+    /// Felt encoded attestation, only for STARK scheme.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    felts: Option<Vec<String>>,
+    /// `check_ecdsa_signature` arguments: message hash, public key, r and s. Only for STARK scheme.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    calldata: Option<Vec<String>>,
+    /// EIP-55 encoded Ethereum address of the signer, only for EVM scheme.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    rejected: RejectedInfo,
+    /// Twap is stale if the latest calculation of the window failed quality gates, it is the previous one then.
+    stale: bool,
+    /// Quality gate the latest calculation has failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    insufficient_data: Option<String>,
 }
 
-async fn data_handler(State(state): State<Arc<ApplicationConfiguration>>) -> impl IntoResponse {
-    let storage = { state.storage.read().unwrap() };
+/// Entries rejected as outliers during latest calculation, by filter and by publisher.
+#[derive(Serialize, Default)]
+struct RejectedInfo {
+    mad: u64,
+    jump: u64,
+    publisher_deviation: u64,
+    publishers: BTreeMap<String, u64>,
+}
 
-    let twap = if let Some(value) = storage.twap.clone() {
-        value
-    } else {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            AppendHeaders([(CONTENT_TYPE, "application/json")]),
-            Json(Result::Err("Data not ready".to_string())),
-        );
+impl From<&Rejections> for RejectedInfo {
+    fn from(rejections: &Rejections) -> Self {
+        RejectedInfo {
+            mad: rejections.mad,
+            jump: rejections.jump,
+            publisher_deviation: rejections.publisher_deviation,
+            publishers: rejections
+                .publishers
+                .iter()
+                .map(|(publisher, count)| {
+                    let name = parse_cairo_short_string(publisher).unwrap_or_else(|_| publisher.to_hex_string());
+                    (name, *count)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PairInfo {
+    pair: String,
+    pair_id: String,
+    windows: Vec<WindowInfo>,
+}
+
+#[derive(Serialize)]
+struct WindowInfo {
+    window: u64,
+    algorithm: String,
+    ready: bool,
+}
+
+#[derive(Deserialize)]
+struct DataParams {
+    pair: Option<String>,
+    window: Option<String>,
+    /// Block number window should end at.
+    at_block: Option<u64>,
+    /// Unix timestamp window should end at.
+    at_time: Option<u64>,
+}
+
+type DataResponse = (StatusCode, AppendHeaders<[(HeaderName, &'static str); 1]>, Json<Result<Data, String>>);
+
+fn error_response(status: StatusCode, message: &str) -> DataResponse {
+    (status, AppendHeaders([(CONTENT_TYPE, "application/json")]), Json(Result::Err(message.to_string())))
+}
+
+async fn data_handler(
+    State(state): State<Arc<ApplicationConfiguration>>,
+    Query(params): Query<DataParams>,
+) -> impl IntoResponse {
+    // First configured pair is a default one, so single pair setups can omit parameter.
+    let pair = match params.pair {
+        Some(name) => state.find_pair(&name),
+        None => state.pairs.first(),
     };
 
-    let signature = if let Some(value) = storage.signature {
-        value
-    } else {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            AppendHeaders([(CONTENT_TYPE, "application/json")]),
-            Json(Result::Err("Data not ready".to_string())),
-        );
+    let Some(pair) = pair else {
+        return error_response(StatusCode::NOT_FOUND, "Unknown pair");
+    };
+
+    // Same goes for window, shortest one is used by default.
+    let window = match params.window.as_deref().map(parse_duration) {
+        Some(Ok(seconds)) => pair.find_window(Duration::from_secs(seconds)),
+        Some(Err(_)) => None,
+        None => pair.windows.first().cloned(),
     };
 
-    let twap_bytes = [twap.to_bytes_be()].concat();
-    let twap_serialised = twap_bytes.to_lower_hex_string();
+    let Some(window) = window else {
+        return error_response(StatusCode::NOT_FOUND, "Unknown window");
+    };
+
+    let anchor = match (params.at_block, params.at_time) {
+        (Some(_), Some(_)) => {
+            return error_response(StatusCode::BAD_REQUEST, "Only one of at_block and at_time is allowed");
+        }
+        (Some(block_number), None) => Anchor::Block(block_number),
+        (None, Some(timestamp)) => Anchor::Time(timestamp),
+        (None, None) => Anchor::Latest,
+    };
 
-    let signature = signature.serialize_compact().to_lower_hex_string();
+    let (signed, rejected, insufficient) = if anchor == Anchor::Latest {
+        let storage = state.storage[&pair.id].read().unwrap();
+        let insufficient = storage.insufficient.get(&window).cloned();
+
+        let Some(signed) = storage.twaps.get(&window).cloned() else {
+            return match insufficient {
+                Some(insufficient) => {
+                    error_response(StatusCode::SERVICE_UNAVAILABLE, &format!("Insufficient data: {insufficient}"))
+                }
+                None => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Data not ready"),
+            };
+        };
+
+        (signed, storage.rejections.get(&window).map(RejectedInfo::from).unwrap_or_default(), insufficient)
+    } else {
+        match historical_twap(&state, pair, window, anchor).await {
+            Ok((signed, rejections)) => (signed, RejectedInfo::from(&rejections), None),
+            Err((status, message)) => return error_response(status, &message),
+        }
+    };
 
     (
         StatusCode::OK,
         AppendHeaders([(CONTENT_TYPE, "application/json")]),
-        Json(Result::Ok(Data { twap: twap_serialised, signature, pk: state.public_key.to_string() })),
+        Json(Result::Ok(data(&state, pair, window, signed, rejected, insufficient.as_ref()))),
     )
 }
 
-async fn health_handler(State(state): State<Arc<ApplicationConfiguration>>) -> impl IntoResponse {
-    if let ServiceStatus::Failed { message } = state.fetcher_status.read().unwrap().deref() {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            AppendHeaders([(CONTENT_TYPE, "application/json")]),
-            Json(Result::Err(message.to_string())),
-        );
+/// Builds response of signed twap of the pair and window, encodings and signer keys depend on signing scheme. Twap is
+/// marked stale if `insufficient` is set.
+fn data(
+    state: &ApplicationConfiguration,
+    pair: &Pair,
+    window: Duration,
+    signed: SignedTwap,
+    rejected: RejectedInfo,
+    insufficient: Option<&InsufficientData>,
+) -> Data {
+    let attestation = signed.attestation;
+    let twap_serialised = attestation.twap.to_bytes_be().to_lower_hex_string();
+    let payload = attestation.encode().to_lower_hex_string();
+
+    let address = match signed.signature {
+        AttestationSignature::Evm(_) => Some(checksum_address(&ethereum_address(&state.public_key))),
+        _ => None,
+    };
+
+    let (signature, pk, felts, calldata) = match signed.signature {
+        AttestationSignature::Secp256k1(signature) => {
+            (signature.serialize_compact().to_lower_hex_string(), state.public_key.to_string(), None, None)
+        }
+        AttestationSignature::Evm(signature) => {
+            (signature.to_lower_hex_string(), state.public_key.to_string(), None, None)
+        }
+        AttestationSignature::Stark { r, s } => {
+            let public_key = state.signer.stark_public_key();
+            let signature = [r.to_bytes_be(), s.to_bytes_be()].concat().to_lower_hex_string();
+            let felts = attestation.felts().iter().map(|felt| felt.to_hex_string()).collect();
+            let calldata = [attestation.pedersen_hash(), public_key, r, s].iter().map(|f| f.to_hex_string()).collect();
+
+            (signature, public_key.to_hex_string(), Some(felts), Some(calldata))
+        }
+    };
+
+    Data {
+        pair: pair.name.clone(),
+        window: window.as_secs(),
+        protocol: PROTOCOL_TAG.to_string(),
+        version: attestation.version,
+        chain_id: attestation.chain_id.to_hex_string(),
+        pair_id: attestation.pair_id.to_hex_string(),
+        algorithm: parse_cairo_short_string(&attestation.algorithm)
+            .unwrap_or_else(|_| attestation.algorithm.to_hex_string()),
+        window_start: attestation.window_start,
+        window_end: attestation.window_end,
+        timestamp: attestation.timestamp,
+        sequence: attestation.sequence,
+        anchor: attestation.anchor,
+        twap: twap_serialised,
+        payload,
+        scheme: state.signer.scheme.to_string(),
+        signature,
+        pk,
+        felts,
+        calldata,
+        address,
+        rejected,
+        stale: insufficient.is_some(),
+        insufficient_data: insufficient.map(|insufficient| insufficient.to_string()),
+    }
+}
+
+/// Calculates and signs twap of `window` ending at historical `anchor`. Window ending at a block ends at its timestamp,
+/// the block should be processed already. Window should be within retention period. Storage is only read, so requests
+/// don't hold processor back.
+async fn historical_twap(
+    state: &ApplicationConfiguration,
+    pair: &Pair,
+    window: Duration,
+    anchor: Anchor,
+) -> Result<(SignedTwap, Rejections), (StatusCode, String)> {
+    let end = match anchor {
+        Anchor::Latest => return Err((StatusCode::BAD_REQUEST, "Anchor is not historical".to_string())),
+        Anchor::Time(timestamp) => timestamp,
+        Anchor::Block(block_number) => {
+            let processed = state.checkpoint.read().unwrap().is_some_and(|c| c.block_number >= block_number);
+            if !processed {
+                return Err((StatusCode::NOT_FOUND, "Block is not processed yet".to_string()));
+            }
+
+            state
+                .rpc
+                .get_block(block_number, &state.metrics)
+                .await
+                .map_err(|message| (StatusCode::INTERNAL_SERVER_ERROR, message))?
+                .timestamp
+        }
+    };
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Can't calculate duration".to_string()))?
+        .as_secs();
+
+    if end > now {
+        return Err((StatusCode::BAD_REQUEST, "Requested time is in the future".to_string()));
     }
 
-    if let ServiceStatus::Failed { message } = state.processor_status.read().unwrap().deref() {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            AppendHeaders([(CONTENT_TYPE, "application/json")]),
-            Json(Result::Err(message.to_string())),
-        );
+    if end.saturating_sub(window.as_secs()) < now.saturating_sub(state.history().as_secs()) {
+        return Err((StatusCode::NOT_FOUND, "Requested window is out of retention period".to_string()));
     }
 
-    (StatusCode::OK, AppendHeaders([(CONTENT_TYPE, "application/json")]), Json(Result::Ok("Good".to_string())))
+    let storage = state.storage[&pair.id].read().unwrap();
+    let signed = storage.sign_historical(window, anchor, end, now, &state.signer).map_err(|message| {
+        match message.starts_with("Insufficient data") {
+            true => (StatusCode::UNPROCESSABLE_ENTITY, message),
+            false => (StatusCode::INTERNAL_SERVER_ERROR, message),
+        }
+    })?;
+
+    let labels = [pair.name.as_str(), &window.as_secs().to_string(), "historical"];
+    state.metrics.signatures.with_label_values(&labels).inc();
+
+    Ok(signed)
+}
+
+async fn pairs_handler(State(state): State<Arc<ApplicationConfiguration>>) -> impl IntoResponse {
+    let pairs: Vec<PairInfo> = state
+        .pairs
+        .iter()
+        .map(|pair| {
+            let storage = state.storage[&pair.id].read().unwrap();
+
+            PairInfo {
+                pair: pair.name.clone(),
+                pair_id: pair.id.to_hex_string(),
+                windows: pair
+                    .windows
+                    .iter()
+                    .map(|window| WindowInfo {
+                        window: window.as_secs(),
+                        algorithm: storage.algorithm(*window),
+                        ready: storage.twaps.contains_key(window),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    (StatusCode::OK, AppendHeaders([(CONTENT_TYPE, "application/json")]), Json(Result::<_, String>::Ok(pairs)))
+}
+
+async fn metrics_handler(State(state): State<Arc<ApplicationConfiguration>>) -> impl IntoResponse {
+    match state.metrics.encode() {
+        Ok(metrics) => (StatusCode::OK, AppendHeaders([(CONTENT_TYPE, "text/plain; version=0.0.4")]), metrics),
+        Err(message) => (StatusCode::INTERNAL_SERVER_ERROR, AppendHeaders([(CONTENT_TYPE, "text/plain")]), message),
+    }
+}
+
+/// Observes latency of every request by route, so paths with parameters don't make separate series.
+async fn track_requests(State(state): State<Arc<ApplicationConfiguration>>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let path = match request.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => "unmatched".to_string(),
+    };
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    let status = response.status();
+    let labels = [method.as_str(), &path, status.as_str()];
+    state.metrics.http_duration.with_label_values(&labels).observe(started.elapsed().as_secs_f64());
+
+    response
 }
 
 #[tokio::main]
 async fn main() {
-    let app_state = match ApplicationConfiguration::new() {
+    let cli = Cli::parse();
+
+    let app_state = match Settings::load(&cli).and_then(ApplicationConfiguration::new) {
         Ok(state) => Arc::new(state),
-        Err(message) => panic!("{}", message),
+        Err(message) => {
+            eprintln!("Invalid configuration:\n{message}");
+            exit(1);
+        }
     };
 
+    if let Some(Command::Backfill(args)) = &cli.command {
+        if let Err(message) = backfill::run(&app_state, args).await {
+            eprintln!("Backfill failed:\n{message}");
+            exit(1);
+        }
+
+        return;
+    }
+
+    if let Err(message) = app_state.restore() {
+        eprintln!("Can't restore state:\n{message}");
+        exit(1);
+    }
+
     let app = Router::new()
         .route("/data", get(data_handler))
-        .route("/health", get(health_handler))
+        .route("/stream", get(streaming::sse_handler))
+        .route("/stream/ws", get(streaming::websocket_handler))
+        .route("/pairs", get(pairs_handler))
+        .route("/health", get(health::livez_handler))
+        .route("/livez", get(health::livez_handler))
+        .route("/readyz", get(health::readyz_handler))
+        .route("/metrics", get(metrics_handler))
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), track_requests))
         .with_state(app_state.clone());
 
     let addr = format!("{}:{}", app_state.host, app_state.port);
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
 
-    let (tx, rx) = mpsc::unbounded_channel::<Vec<SpotEntryEvent>>();
+    let (tx, rx) = mpsc::unbounded_channel::<EventBatch>();
+
+    // Recorder sits between fetcher and processor, every batch goes through it.
+    let rx = match &app_state.recording.record {
+        Some(path) => {
+            let recorder = Recorder::open(path).await.unwrap_or_else(|message| {
+                eprintln!("Can't start recording:\n{message}");
+                exit(1);
+            });
+
+            let (recorded_tx, recorded_rx) = mpsc::unbounded_channel::<EventBatch>();
+            tokio::spawn(recording::record(recorder, rx, recorded_tx));
+            println!("Recording event batches to {}", path.display());
 
-    let fetching_handle = tokio::spawn(app_state.clone().start_fetcher(tx));
+            recorded_rx
+        }
+        None => rx,
+    };
+
+    // Channel is kept open when source is exhausted, so processor keeps running.
+    let fetching_handle = tokio::spawn(app_state.clone().start_fetcher(tx.clone()));
     let processing_handle = tokio::spawn(app_state.clone().start_processor(rx));
 
+    println!("Reading events from {}", app_state.source.description());
     println!("Starting server on address: {}", addr);
     if let Err(z) = axum::serve(listener, app).await {
         panic!("{z}");
@@ -109,3 +447,127 @@ async fn main() {
     fetching_handle.abort();
     processing_handle.abort();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::SpotEntryEvent;
+    use serde_json::Value;
+
+    fn state() -> Arc<ApplicationConfiguration> {
+        let settings = Settings::from_toml(
+            r#"
+            [[pairs]]
+            name = "BTC/USD"
+            windows = ["1h", "24h"]
+
+            [[pairs]]
+            name = "ETH/USD"
+            windows = ["5m"]
+            "#,
+        )
+        .unwrap();
+        let state = ApplicationConfiguration::new(settings).unwrap();
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        for pair in state.pairs.iter() {
+            let mut storage = state.storage[&pair.id].write().unwrap();
+            storage.append(SpotEntryEvent { timestamp: now - 10, price: 100, pair_id: pair.id, ..Default::default() });
+            for window in pair.windows.iter() {
+                storage.calculate_and_sign_twap(*window, now, &state.signer);
+            }
+        }
+
+        Arc::new(state)
+    }
+
+    async fn get_data(state: &Arc<ApplicationConfiguration>, pair: Option<&str>, window: Option<&str>) -> (u16, Value) {
+        let params = DataParams {
+            pair: pair.map(str::to_string),
+            window: window.map(str::to_string),
+            at_block: None,
+            at_time: None,
+        };
+        let response = data_handler(State(state.clone()), Query(params)).await.into_response();
+
+        let status = response.status().as_u16();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn data_is_routed_by_pair_and_window() {
+        let state = state();
+
+        // First pair and its shortest window by default
+        let (status, body) = get_data(&state, None, None).await;
+        assert_eq!((status, &body["Ok"]["pair"], &body["Ok"]["window"]), (200, &"BTC/USD".into(), &3600.into()));
+
+        let (status, body) = get_data(&state, Some(" eth/usd "), None).await;
+        assert_eq!((status, &body["Ok"]["pair"], &body["Ok"]["window"]), (200, &"ETH/USD".into(), &300.into()));
+
+        let (status, body) = get_data(&state, Some("btc/USD"), Some("24h")).await;
+        assert_eq!((status, &body["Ok"]["pair"], &body["Ok"]["window"]), (200, &"BTC/USD".into(), &86400.into()));
+    }
+
+    #[tokio::test]
+    async fn unknown_pair_or_window_is_not_found() {
+        let state = state();
+
+        assert_eq!(get_data(&state, Some("DOGE/USD"), None).await, (404, serde_json::json!({ "Err": "Unknown pair" })));
+        for window in ["5m", "soon"] {
+            let expected = (404, serde_json::json!({ "Err": "Unknown window" }));
+            assert_eq!(get_data(&state, Some("BTC/USD"), Some(window)).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn historical_window_failing_quality_gates_is_unprocessable() {
+        let settings = Settings::from_toml(
+            r#"
+            pairs = [{ name = "BTC/USD", windows = ["1h"] }]
+            quality = { min_samples = 2 }
+            "#,
+        )
+        .unwrap();
+        let state = Arc::new(ApplicationConfiguration::new(settings).unwrap());
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let pair_id = state.pairs[0].id;
+        state.storage[&pair_id].write().unwrap().append(SpotEntryEvent {
+            timestamp: now - 120,
+            price: 100,
+            pair_id,
+            ..Default::default()
+        });
+
+        let params = DataParams { pair: None, window: None, at_block: None, at_time: Some(now - 60) };
+        let response = data_handler(State(state.clone()), Query(params)).await.into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let expected = serde_json::json!({ "Err": "Insufficient data: 1 samples, at least 2 required" });
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn every_pair_is_listed() {
+        let state = state();
+        let response = pairs_handler(State(state.clone())).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let pairs: Value = serde_json::from_slice(&body).unwrap();
+        let pairs = pairs["Ok"].as_array().unwrap();
+
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0]["pair"], "BTC/USD");
+        assert_eq!(pairs[0]["pair_id"], state.pairs[0].id.to_hex_string());
+        assert_eq!(
+            pairs[0]["windows"][1],
+            serde_json::json!({ "window": 86400, "algorithm": "twap/left", "ready": true })
+        );
+        assert_eq!(pairs[1]["pair"], "ETH/USD");
+        assert_eq!(pairs[1]["windows"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,185 @@
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{future::Future, time::Instant};
+
+const NAMESPACE: &str = "twapper";
+
+/// Prometheus metrics of workers and API, exposed at `/metrics` in text format.
+pub struct Metrics {
+    registry: Registry,
+    /// The latest block on chain that satisfies finality.
+    pub chain_head: IntGauge,
+    /// The last block all events were processed for.
+    pub processed_block: IntGauge,
+    /// Blocks between chain head and the last processed one, updated on every scrape.
+    pub block_lag: IntGauge,
+    /// Events stored per pair.
+    pub events: IntCounterVec,
+    /// Entries rejected during latest calculation per pair, window and outlier filter.
+    pub rejected: IntGaugeVec,
+    pub rpc_duration: HistogramVec,
+    pub rpc_errors: IntCounterVec,
+    /// Calls endpoints responded differently to with quorum.
+    pub rpc_disagreements: IntCounterVec,
+    /// Time to calculate and sign twap per pair and window.
+    pub twap_duration: HistogramVec,
+    /// Attestations signed per pair, window and anchor, `latest` or `historical`.
+    pub signatures: IntCounterVec,
+    /// Latest signed twap per pair and window as a float.
+    pub twap: GaugeVec,
+    pub http_duration: HistogramVec,
+    /// Restarts of failed workers.
+    pub worker_restarts: IntCounterVec,
+}
+
+impl Metrics {
+    /// Creates metrics and registers them in a fresh registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if metric options are invalid or metric is registered twice, both are programming errors.
+    pub fn new() -> Metrics {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None).unwrap();
+
+        let chain_head =
+            IntGauge::new("chain_head_block", "The latest block on chain that satisfies finality").unwrap();
+        let processed_block = IntGauge::new("processed_block", "The last block all events were processed for").unwrap();
+        let block_lag = IntGauge::new("block_lag", "Blocks between chain head and the last processed one").unwrap();
+        let events = IntCounterVec::new(Opts::new("events_total", "Events stored per pair"), &["pair"]).unwrap();
+        let rejected = IntGaugeVec::new(
+            Opts::new("rejected_entries", "Entries rejected during latest calculation per outlier filter"),
+            &["pair", "window", "filter"],
+        )
+        .unwrap();
+        let rpc_duration = HistogramVec::new(
+            HistogramOpts::new("rpc_duration_seconds", "JSON-RPC call latency by method"),
+            &["method"],
+        )
+        .unwrap();
+        let rpc_errors =
+            IntCounterVec::new(Opts::new("rpc_errors_total", "JSON-RPC call errors by method"), &["method"]).unwrap();
+        let rpc_disagreements = IntCounterVec::new(
+            Opts::new("rpc_disagreements_total", "JSON-RPC calls endpoints disagreed on by method"),
+            &["method"],
+        )
+        .unwrap();
+        let twap_duration = HistogramVec::new(
+            HistogramOpts::new("twap_duration_seconds", "Time to calculate and sign twap")
+                .buckets(prometheus::exponential_buckets(0.000_01, 4.0, 10).unwrap()),
+            &["pair", "window"],
+        )
+        .unwrap();
+        let signatures = IntCounterVec::new(
+            Opts::new("signatures_total", "Attestations signed per pair and window"),
+            &["pair", "window", "anchor"],
+        )
+        .unwrap();
+        let twap = GaugeVec::new(Opts::new("twap", "Latest signed twap"), &["pair", "window"]).unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route and status"),
+            &["method", "path", "status"],
+        )
+        .unwrap();
+        let worker_restarts =
+            IntCounterVec::new(Opts::new("worker_restarts_total", "Restarts of failed workers"), &["worker"]).unwrap();
+
+        registry.register(Box::new(chain_head.clone())).unwrap();
+        registry.register(Box::new(processed_block.clone())).unwrap();
+        registry.register(Box::new(block_lag.clone())).unwrap();
+        registry.register(Box::new(events.clone())).unwrap();
+        registry.register(Box::new(rejected.clone())).unwrap();
+        registry.register(Box::new(rpc_duration.clone())).unwrap();
+        registry.register(Box::new(rpc_errors.clone())).unwrap();
+        registry.register(Box::new(rpc_disagreements.clone())).unwrap();
+        registry.register(Box::new(twap_duration.clone())).unwrap();
+        registry.register(Box::new(signatures.clone())).unwrap();
+        registry.register(Box::new(twap.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(worker_restarts.clone())).unwrap();
+
+        Metrics {
+            registry,
+            chain_head,
+            processed_block,
+            block_lag,
+            events,
+            rejected,
+            rpc_duration,
+            rpc_errors,
+            rpc_disagreements,
+            twap_duration,
+            signatures,
+            twap,
+            http_duration,
+            worker_restarts,
+        }
+    }
+
+    /// Awaits JSON-RPC `call`, its latency is observed and error is counted under `method` name.
+    pub async fn rpc<T, E>(&self, method: &str, call: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+        let started = Instant::now();
+        let result = call.await;
+
+        self.rpc_duration.with_label_values(&[method]).observe(started.elapsed().as_secs_f64());
+        if result.is_err() {
+            self.rpc_errors.with_label_values(&[method]).inc();
+        }
+
+        result
+    }
+
+    /// Metrics in Prometheus text format.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if metrics can't be encoded.
+    pub fn encode(&self) -> Result<String, String> {
+        self.block_lag.set((self.chain_head.get() - self.processed_block.get()).max(0));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| format!("Can't encode metrics: {e}"))?;
+
+        String::from_utf8(buffer).map_err(|_| "Can't encode metrics".to_string())
+    }
+}
+
+/// Fixed point value with 64 fractional bits as a float, precision loss is fine for a gauge.
+pub fn fixed_point_to_f64(value: &BigUint) -> f64 {
+    value.to_f64().unwrap_or(f64::INFINITY) / 2_f64.powi(64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metrics_are_encoded() {
+        let metrics = Metrics::new();
+        metrics.chain_head.set(120);
+        metrics.processed_block.set(100);
+        metrics.events.with_label_values(&["BTC/USD"]).inc_by(3);
+        metrics.twap.with_label_values(&["BTC/USD", "3600"]).set(fixed_point_to_f64(&(BigUint::from(5_u8) << 63)));
+
+        let encoded = metrics.encode().unwrap();
+
+        assert!(encoded.contains("twapper_block_lag 20"));
+        assert!(encoded.contains("twapper_events_total{pair=\"BTC/USD\"} 3"));
+        assert!(encoded.contains("twapper_twap{pair=\"BTC/USD\",window=\"3600\"} 2.5"));
+    }
+
+    #[tokio::test]
+    async fn rpc_calls_are_measured() {
+        let metrics = Metrics::new();
+
+        let _ = metrics.rpc("starknet_blockNumber", async { Ok::<_, ()>(1) }).await;
+        let _ = metrics.rpc("starknet_blockNumber", async { Err::<u64, _>(()) }).await;
+
+        assert_eq!(metrics.rpc_duration.with_label_values(&["starknet_blockNumber"]).get_sample_count(), 2);
+        assert_eq!(metrics.rpc_errors.with_label_values(&["starknet_blockNumber"]).get(), 1);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::{
+    attestation::{ATTESTATION_VERSION, Attestation, PROTOCOL_TAG},
+    signing::AttestationSignature,
+    storage::{Checkpoint, SignedTwap, SpotEntryEvent},
+};
+use starknet::core::types::Felt;
+use std::{path::Path, time::Duration};
+
+const CHECKPOINT_KEY: &[u8] = b"checkpoint";
+
+/// Backend that keeps service state between restarts: stored events, last processed block and last signed twaps.
+pub trait Persistence: Send + Sync {
+    fn store_events(&self, pair_id: Felt, events: &[SpotEntryEvent]) -> Result<(), String>;
+    fn load_events(&self, pair_id: Felt) -> Result<Vec<SpotEntryEvent>, String>;
+    /// Removes events of the pair with timestamp less or equal to `timestamp`.
+    fn clean_events_older_than(&self, pair_id: Felt, timestamp: u64) -> Result<(), String>;
+    /// Removes events of the pair emitted in `block_number` or later ones.
+    fn rollback_events(&self, pair_id: Felt, block_number: u64) -> Result<(), String>;
+
+    fn store_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), String>;
+    fn load_checkpoint(&self) -> Result<Option<Checkpoint>, String>;
+
+    fn store_signed_twap(&self, pair_id: Felt, window: Duration, signed: &SignedTwap) -> Result<(), String>;
+    fn load_signed_twap(&self, pair_id: Felt, window: Duration) -> Result<Option<SignedTwap>, String>;
+
+    /// Whether stored state survives restarts.
+    fn is_durable(&self) -> bool;
+}
+
+/// State lives in memory only, nothing is persisted and nothing is restored.
+pub struct MemoryPersistence;
+
+impl Persistence for MemoryPersistence {
+    fn store_events(&self, _pair_id: Felt, _events: &[SpotEntryEvent]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn load_events(&self, _pair_id: Felt) -> Result<Vec<SpotEntryEvent>, String> {
+        Ok(Vec::new())
+    }
+
+    fn clean_events_older_than(&self, _pair_id: Felt, _timestamp: u64) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn rollback_events(&self, _pair_id: Felt, _block_number: u64) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn store_checkpoint(&self, _checkpoint: &Checkpoint) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn load_checkpoint(&self) -> Result<Option<Checkpoint>, String> {
+        Ok(None)
+    }
+
+    fn store_signed_twap(&self, _pair_id: Felt, _window: Duration, _signed: &SignedTwap) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn load_signed_twap(&self, _pair_id: Felt, _window: Duration) -> Result<Option<SignedTwap>, String> {
+        Ok(None)
+    }
+
+    fn is_durable(&self) -> bool {
+        false
+    }
+}
+
+/// Embedded sled database. Events are keyed by pair id followed by big endian timestamp, publisher and source, so
+/// events of a pair are ordered by time and can be cleaned with a range scan.
+pub struct SledPersistence {
+    events: sled::Tree,
+    twaps: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl SledPersistence {
+    pub fn open(path: &Path) -> Result<SledPersistence, String> {
+        let db = sled::open(path).map_err(|e| format!("Can't open database at {}: {e}", path.display()))?;
+        SledPersistence::from_db(db)
+    }
+
+    fn from_db(db: sled::Db) -> Result<SledPersistence, String> {
+        let open_tree = |name: &str| db.open_tree(name).map_err(|e| format!("Can't open tree {name}: {e}"));
+
+        Ok(SledPersistence { events: open_tree("events")?, twaps: open_tree("twaps")?, meta: open_tree("meta")? })
+    }
+
+    fn event_key(pair_id: Felt, event: &SpotEntryEvent) -> Vec<u8> {
+        [
+            SledPersistence::timestamp_key(pair_id, event.timestamp).as_slice(),
+            &event.publisher.to_bytes_be(),
+            &event.source.to_bytes_be(),
+        ]
+        .concat()
+    }
+
+    /// Prefix of keys of all events of the pair with the timestamp.
+    fn timestamp_key(pair_id: Felt, timestamp: u64) -> Vec<u8> {
+        [pair_id.to_bytes_be().as_slice(), &timestamp.to_be_bytes()].concat()
+    }
+
+    fn twap_key(pair_id: Felt, window: Duration) -> Vec<u8> {
+        [pair_id.to_bytes_be().as_slice(), &window.as_secs().to_be_bytes()].concat()
+    }
+}
+
+impl Persistence for SledPersistence {
+    fn store_events(&self, pair_id: Felt, events: &[SpotEntryEvent]) -> Result<(), String> {
+        let mut batch = sled::Batch::default();
+        for event in events {
+            let value = serde_json::to_vec(event).map_err(|e| format!("Can't serialise event: {e}"))?;
+            batch.insert(SledPersistence::event_key(pair_id, event), value);
+        }
+
+        self.events.apply_batch(batch).map_err(|e| format!("Can't store events: {e}"))
+    }
+
+    fn load_events(&self, pair_id: Felt) -> Result<Vec<SpotEntryEvent>, String> {
+        self.events
+            .scan_prefix(pair_id.to_bytes_be())
+            .map(|item| {
+                let (_, value) = item.map_err(|e| format!("Can't load events: {e}"))?;
+                serde_json::from_slice(&value).map_err(|e| format!("Can't deserialise event: {e}"))
+            })
+            .collect()
+    }
+
+    fn clean_events_older_than(&self, pair_id: Felt, timestamp: u64) -> Result<(), String> {
+        // Keys of the timestamp are longer than its prefix, so range ends right before prefix of the next second.
+        let range = SledPersistence::timestamp_key(pair_id, 0)..
+            SledPersistence::timestamp_key(pair_id, timestamp.saturating_add(1));
+
+        let mut batch = sled::Batch::default();
+        for item in self.events.range(range) {
+            let (key, _) = item.map_err(|e| format!("Can't clean events: {e}"))?;
+            batch.remove(key);
+        }
+
+        self.events.apply_batch(batch).map_err(|e| format!("Can't clean events: {e}"))
+    }
+
+    fn rollback_events(&self, pair_id: Felt, block_number: u64) -> Result<(), String> {
+        // Events are keyed by timestamp, so every event of the pair has to be checked.
+        let mut batch = sled::Batch::default();
+        for item in self.events.scan_prefix(pair_id.to_bytes_be()) {
+            let (key, value) = item.map_err(|e| format!("Can't rollback events: {e}"))?;
+            let event: SpotEntryEvent =
+                serde_json::from_slice(&value).map_err(|e| format!("Can't deserialise event: {e}"))?;
+
+            if event.block_number >= block_number {
+                batch.remove(key);
+            }
+        }
+
+        self.events.apply_batch(batch).map_err(|e| format!("Can't rollback events: {e}"))
+    }
+
+    fn store_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), String> {
+        let value = [checkpoint.block_number.to_be_bytes().as_slice(), &checkpoint.block_hash.to_bytes_be()].concat();
+
+        self.meta.insert(CHECKPOINT_KEY, value).map(|_| ()).map_err(|e| format!("Can't store checkpoint: {e}"))?;
+
+        // Checkpoint is stored after events of the block, so flushing here makes everything before it durable.
+        self.meta.flush().map(|_| ()).map_err(|e| format!("Can't flush database: {e}"))
+    }
+
+    fn load_checkpoint(&self) -> Result<Option<Checkpoint>, String> {
+        let value = self.meta.get(CHECKPOINT_KEY).map_err(|e| format!("Can't load checkpoint: {e}"))?;
+
+        let Some(value) = value else {
+            return Ok(None);
+        };
+
+        // Block number followed by block hash.
+        if value.len() != 8 + 32 {
+            return Err("Malformed checkpoint".to_string());
+        }
+
+        Ok(Some(Checkpoint {
+            block_number: u64::from_be_bytes(value[..8].try_into().unwrap()),
+            block_hash: Felt::from_bytes_be_slice(&value[8..]),
+        }))
+    }
+
+    fn store_signed_twap(&self, pair_id: Felt, window: Duration, signed: &SignedTwap) -> Result<(), String> {
+        let attestation = signed.attestation.encode();
+        let signature = signed.signature.encode();
+
+        // Attestation has fixed size, so signature is everything after it.
+        self.twaps
+            .insert(SledPersistence::twap_key(pair_id, window), [attestation, signature].concat())
+            .map(|_| ())
+            .map_err(|e| format!("Can't store signed twap: {e}"))
+    }
+
+    fn load_signed_twap(&self, pair_id: Felt, window: Duration) -> Result<Option<SignedTwap>, String> {
+        let value = self
+            .twaps
+            .get(SledPersistence::twap_key(pair_id, window))
+            .map_err(|e| format!("Can't load signed twap: {e}"))?;
+
+        let Some(value) = value else {
+            return Ok(None);
+        };
+
+        // Attestation of previous layout can't be decoded, it is signed anew once data is ready.
+        if value.get(PROTOCOL_TAG.len()) != Some(&ATTESTATION_VERSION) {
+            return Ok(None);
+        }
+
+        let attestation_len = Attestation::ENCODED_LEN;
+        if value.len() < attestation_len {
+            return Err("Malformed signed twap".to_string());
+        }
+
+        Ok(Some(SignedTwap {
+            attestation: Attestation::decode(&value[..attestation_len])?,
+            signature: AttestationSignature::decode(&value[attestation_len..])?,
+        }))
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attestation::Anchor;
+    use num_bigint::BigUint;
+
+    fn persistence() -> SledPersistence {
+        SledPersistence::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn events_are_restored_by_pair() {
+        let persistence = persistence();
+        let event_factory =
+            |timestamp, pair_id| SpotEntryEvent { timestamp, price: 100, pair_id, ..Default::default() };
+
+        let events: Vec<SpotEntryEvent> = (0..10).map(|ts| event_factory(ts, Felt::ONE)).collect();
+        persistence.store_events(Felt::ONE, &events).unwrap();
+        persistence.store_events(Felt::TWO, &[event_factory(5, Felt::TWO)]).unwrap();
+
+        assert_eq!(persistence.load_events(Felt::ONE).unwrap(), events);
+        assert_eq!(persistence.load_events(Felt::TWO).unwrap().len(), 1);
+
+        persistence.clean_events_older_than(Felt::ONE, 4).unwrap();
+
+        assert_eq!(persistence.load_events(Felt::ONE).unwrap(), events[5..].to_vec());
+        assert_eq!(persistence.load_events(Felt::TWO).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn events_of_different_publishers_are_kept() {
+        let persistence = persistence();
+        let events: Vec<SpotEntryEvent> = ["AVNU", "PRAGMA"]
+            .iter()
+            .flat_map(|publisher| {
+                (0..2).map(|ts| SpotEntryEvent {
+                    timestamp: 10 + ts,
+                    price: 100,
+                    pair_id: Felt::ONE,
+                    publisher: Felt::from_bytes_be_slice(publisher.as_bytes()),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        persistence.store_events(Felt::ONE, &events).unwrap();
+        assert_eq!(persistence.load_events(Felt::ONE).unwrap().len(), 4);
+
+        persistence.clean_events_older_than(Felt::ONE, 10).unwrap();
+        let restored = persistence.load_events(Felt::ONE).unwrap();
+
+        assert_eq!(restored.len(), 2);
+        assert!(restored.iter().all(|event| event.timestamp == 11));
+    }
+
+    #[test]
+    fn orphaned_events_are_rolled_back() {
+        let persistence = persistence();
+        let events: Vec<SpotEntryEvent> = (0..10)
+            .map(|block_number| SpotEntryEvent {
+                timestamp: 100 - block_number,
+                price: 100,
+                pair_id: Felt::ONE,
+                block_number,
+                ..Default::default()
+            })
+            .collect();
+
+        persistence.store_events(Felt::ONE, &events).unwrap();
+        persistence.store_events(Felt::TWO, &[SpotEntryEvent { pair_id: Felt::TWO, ..events[9].clone() }]).unwrap();
+        persistence.rollback_events(Felt::ONE, 7).unwrap();
+
+        let mut restored = persistence.load_events(Felt::ONE).unwrap();
+        restored.sort_by_key(|event| event.block_number);
+
+        assert_eq!(restored, events[..7].to_vec());
+        assert_eq!(persistence.load_events(Felt::TWO).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn checkpoint_and_signed_twap_are_restored() {
+        let persistence = persistence();
+        let window = Duration::from_secs(3600);
+
+        assert_eq!(persistence.load_checkpoint(), Ok(None));
+        assert_eq!(persistence.load_signed_twap(Felt::ONE, window), Ok(None));
+
+        let signed = SignedTwap {
+            attestation: Attestation {
+                version: ATTESTATION_VERSION,
+                chain_id: Felt::ONE,
+                pair_id: Felt::ONE,
+                algorithm: Felt::from_bytes_be_slice("twap/left".as_bytes()),
+                window_start: 1_000,
+                window_end: 4_600,
+                timestamp: 4_600,
+                sequence: 7,
+                anchor: Anchor::Latest,
+                twap: BigUint::from(100_u64) << 64,
+            },
+            signature: AttestationSignature::Stark { r: Felt::ONE, s: Felt::TWO },
+        };
+
+        let checkpoint = Checkpoint { block_number: 42, block_hash: Felt::THREE };
+        persistence.store_checkpoint(&checkpoint).unwrap();
+        persistence.store_signed_twap(Felt::ONE, window, &signed).unwrap();
+
+        assert_eq!(persistence.load_checkpoint(), Ok(Some(checkpoint)));
+        assert_eq!(persistence.load_signed_twap(Felt::ONE, window), Ok(Some(signed.clone())));
+        assert_eq!(persistence.load_signed_twap(Felt::ONE, Duration::from_secs(60)), Ok(None));
+
+        // Attestation of previous version is not restored
+        let mut previous = [signed.attestation.encode(), signed.signature.encode()].concat();
+        previous[PROTOCOL_TAG.len()] = ATTESTATION_VERSION - 1;
+        persistence.twaps.insert(SledPersistence::twap_key(Felt::ONE, window), previous).unwrap();
+
+        assert_eq!(persistence.load_signed_twap(Felt::ONE, window), Ok(None));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::{configuration::NetworkConfiguration, metrics::Metrics};
+use futures_util::future::join_all;
+use starknet::{
+    core::types::{BlockId, BlockWithTxHashes, EventFilter, EventsPage, MaybePendingBlockWithTxHashes},
+    providers::{
+        Provider, ProviderError, Url,
+        jsonrpc::{HttpTransport, JsonRpcClient},
+    },
+};
+use std::{
+    fmt::Display,
+    future::Future,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::time::timeout;
+
+type Client = Arc<JsonRpcClient<HttpTransport>>;
+
+/// How calls are spread over RPC endpoints when quorum is not required.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcStrategy {
+    /// Every call goes to the same endpoint until it fails, the next one is used after that.
+    Failover,
+    /// Every next call goes to the next endpoint, failed call is retried on the following ones.
+    RoundRobin,
+}
+
+impl Display for RpcStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcStrategy::Failover => write!(f, "failover"),
+            RpcStrategy::RoundRobin => write!(f, "round_robin"),
+        }
+    }
+}
+
+impl FromStr for RpcStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "failover" => Ok(RpcStrategy::Failover),
+            "round_robin" => Ok(RpcStrategy::RoundRobin),
+            _ => Err(format!("Unknown RPC strategy: {value:?}")),
+        }
+    }
+}
+
+/// JSON-RPC client of several Starknet nodes. Call that fails or doesn't respond within timeout is retried on the
+/// next endpoint. With quorum every call is sent to every endpoint at once, at least quorum of them should respond and
+/// every response should be the same, otherwise call fails and nothing is ingested.
+pub struct RpcProvider {
+    endpoints: Vec<(Url, Client)>,
+    strategy: RpcStrategy,
+    timeout: Duration,
+    quorum: Option<usize>,
+    /// Endpoint the next call starts from.
+    next: AtomicUsize,
+}
+
+impl RpcProvider {
+    pub fn new(network: &NetworkConfiguration) -> RpcProvider {
+        let endpoints = network
+            .rpc_urls
+            .iter()
+            .map(|url| (url.clone(), Arc::new(JsonRpcClient::new(HttpTransport::new(url.clone())))))
+            .collect();
+
+        RpcProvider {
+            endpoints,
+            strategy: network.rpc_strategy,
+            timeout: network.rpc_timeout,
+            quorum: network.rpc_quorum,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The latest block number. With quorum it is the latest block that at least quorum of endpoints has reached, so
+    /// it can be cross-checked by them.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if not enough endpoints responded.
+    pub async fn block_number(&self, metrics: &Metrics) -> Result<u64, String> {
+        let mut block_numbers = self
+            .read("starknet_blockNumber", metrics, |client| async move { client.block_number().await })
+            .await
+            .map_err(|e| format!("Can't fetch latest block number: {e}"))?;
+
+        block_numbers.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(block_numbers[self.quorum.unwrap_or(1) - 1])
+    }
+
+    /// Block with transaction hashes, with quorum every endpoint should return block with the same hash.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if not enough endpoints responded, they disagree or block is pending.
+    pub async fn get_block(&self, block_number: u64, metrics: &Metrics) -> Result<BlockWithTxHashes, String> {
+        let blocks = self
+            .read("starknet_getBlockWithTxHashes", metrics, |client| async move {
+                client.get_block_with_tx_hashes(BlockId::Number(block_number)).await
+            })
+            .await
+            .map_err(|e| format!("Can't get block {block_number} with tx hashes: {e}"))?;
+
+        let block = agreed("starknet_getBlockWithTxHashes", blocks, metrics, |a, b| match (a, b) {
+            (MaybePendingBlockWithTxHashes::Block(a), MaybePendingBlockWithTxHashes::Block(b)) => {
+                a.block_hash == b.block_hash
+            }
+            _ => false,
+        })?;
+
+        match block {
+            MaybePendingBlockWithTxHashes::Block(block) => Ok(block),
+            MaybePendingBlockWithTxHashes::PendingBlock(_) => Err(format!("Block {block_number} is pending")),
+        }
+    }
+
+    /// Page of events matching `filter`, with quorum every endpoint should return the same events. Continuation
+    /// token of the first endpoint is returned, so endpoints should run the same node implementation with quorum.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if not enough endpoints responded or they disagree.
+    pub async fn get_events(
+        &self,
+        filter: EventFilter,
+        continuation_token: Option<String>,
+        chunk_size: u64,
+        metrics: &Metrics,
+    ) -> Result<EventsPage, String> {
+        let pages = self
+            .read("starknet_getEvents", metrics, |client| {
+                let (filter, continuation_token) = (filter.clone(), continuation_token.clone());
+                async move { client.get_events(filter, continuation_token, chunk_size).await }
+            })
+            .await
+            .map_err(|e| format!("Can't fetch events: {e}"))?;
+
+        agreed("starknet_getEvents", pages, metrics, |a, b| a.events == b.events)
+    }
+
+    /// Results of `call` made to endpoints. Without quorum it is a single result of the first endpoint that
+    /// responded, starting from the one strategy points to. With quorum call is made to every endpoint at once and
+    /// results of every endpoint that responded are returned.
+    async fn read<T, F>(&self, method: &str, metrics: &Metrics, call: impl Fn(Client) -> F) -> Result<Vec<T>, String>
+    where
+        F: Future<Output = Result<T, ProviderError>>,
+    {
+        if let Some(quorum) = self.quorum {
+            let calls =
+                self.endpoints.iter().map(|(url, client)| self.attempt(method, metrics, url, call(client.clone())));
+            let results: Vec<T> = join_all(calls).await.into_iter().flatten().collect();
+
+            if results.len() < quorum {
+                return Err(format!(
+                    "{} of {} endpoints responded, {quorum} required",
+                    results.len(),
+                    self.endpoints.len()
+                ));
+            }
+
+            return Ok(results);
+        }
+
+        let start = match self.strategy {
+            RpcStrategy::Failover => self.next.load(Ordering::Relaxed),
+            RpcStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+
+        for offset in 0..self.endpoints.len() {
+            let index = (start + offset) % self.endpoints.len();
+            let (url, client) = &self.endpoints[index];
+
+            if let Some(result) = self.attempt(method, metrics, url, call(client.clone())).await {
+                if self.strategy == RpcStrategy::Failover {
+                    self.next.store(index, Ordering::Relaxed);
+                }
+
+                return Ok(vec![result]);
+            }
+        }
+
+        Err("No endpoint responded".to_string())
+    }
+
+    /// Awaits call to the endpoint within timeout, failure is logged and counted.
+    async fn attempt<T>(
+        &self,
+        method: &str,
+        metrics: &Metrics,
+        url: &Url,
+        call: impl Future<Output = Result<T, ProviderError>>,
+    ) -> Option<T> {
+        let call = async {
+            match timeout(self.timeout, call).await {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(_) => Err(format!("Timed out after {}ms", self.timeout.as_millis())),
+            }
+        };
+
+        match metrics.rpc(method, call).await {
+            Ok(result) => Some(result),
+            Err(message) => {
+                println!("Endpoint {url} failed on {method}: {message}");
+                None
+            }
+        }
+    }
+}
+
+/// The first of `results` if every other one is the `same`, disagreement is counted.
+fn agreed<T>(method: &str, results: Vec<T>, metrics: &Metrics, same: impl Fn(&T, &T) -> bool) -> Result<T, String> {
+    let mut results = results.into_iter();
+    let first = results.next().ok_or("No endpoint responded")?;
+
+    if results.any(|result| !same(&first, &result)) {
+        metrics.rpc_disagreements.with_label_values(&[method]).inc();
+        return Err(format!("Endpoints disagree on {method}"));
+    }
+
+    Ok(first)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{Json, Router, routing::post};
+    use serde_json::{Value, json};
+
+    /// Local JSON-RPC node that responds with `block_number` to `starknet_blockNumber` and with a single event of
+    /// `block_hash` to `starknet_getEvents`.
+    async fn node(block_number: u64, block_hash: &'static str) -> Url {
+        let handler = move |Json(request): Json<Value>| async move {
+            let result = match request["method"].as_str() {
+                Some("starknet_blockNumber") => json!(block_number),
+                _ => json!({
+                    "events": [{
+                        "from_address": "0x1",
+                        "keys": ["0x2"],
+                        "data": ["0x3"],
+                        "block_hash": block_hash,
+                        "block_number": 1,
+                        "transaction_hash": "0x4"
+                    }]
+                }),
+            };
+
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, Router::new().route("/", post(handler))).await });
+
+        url
+    }
+
+    /// Endpoint nothing listens on.
+    async fn dead_node() -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap()
+    }
+
+    fn rpc_provider(rpc_urls: Vec<Url>, rpc_strategy: RpcStrategy, rpc_quorum: Option<usize>) -> RpcProvider {
+        let network = NetworkConfiguration { rpc_urls, rpc_strategy, rpc_quorum, ..NetworkConfiguration::sepolia() };
+        RpcProvider::new(&network)
+    }
+
+    fn filter() -> EventFilter {
+        EventFilter { from_block: None, to_block: None, address: None, keys: None }
+    }
+
+    #[tokio::test]
+    async fn failed_endpoint_is_skipped() {
+        let metrics = Metrics::new();
+        let provider = rpc_provider(vec![dead_node().await, node(10, "0xa").await], RpcStrategy::Failover, None);
+
+        assert_eq!(provider.block_number(&metrics).await, Ok(10));
+        // Working endpoint is used from now on
+        assert_eq!(provider.next.load(Ordering::Relaxed), 1);
+        assert_eq!(provider.block_number(&metrics).await, Ok(10));
+        assert_eq!(metrics.rpc_errors.with_label_values(&["starknet_blockNumber"]).get(), 1);
+
+        let provider = rpc_provider(vec![dead_node().await], RpcStrategy::Failover, None);
+        assert!(provider.block_number(&metrics).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn calls_are_spread_round_robin() {
+        let metrics = Metrics::new();
+        let provider = rpc_provider(vec![node(10, "0xa").await, node(11, "0xa").await], RpcStrategy::RoundRobin, None);
+
+        let mut block_numbers = Vec::new();
+        for _ in 0..4 {
+            block_numbers.push(provider.block_number(&metrics).await.unwrap());
+        }
+
+        assert_eq!(block_numbers, vec![10, 11, 10, 11]);
+    }
+
+    #[tokio::test]
+    async fn quorum_cross_checks_endpoints() {
+        let metrics = Metrics::new();
+
+        let nodes = vec![node(10, "0xa").await, node(12, "0xa").await, node(11, "0xa").await, dead_node().await];
+        let provider = rpc_provider(nodes, RpcStrategy::Failover, Some(2));
+
+        // The latest block two endpoints have reached
+        assert_eq!(provider.block_number(&metrics).await, Ok(11));
+        assert_eq!(provider.get_events(filter(), None, 10, &metrics).await.unwrap().events.len(), 1);
+
+        let nodes = vec![node(10, "0xa").await, node(10, "0xb").await];
+        let provider = rpc_provider(nodes, RpcStrategy::Failover, Some(2));
+
+        let disagreement = Err("Endpoints disagree on starknet_getEvents".to_string());
+        assert_eq!(provider.get_events(filter(), None, 10, &metrics).await, disagreement);
+        assert_eq!(metrics.rpc_disagreements.with_label_values(&["starknet_getEvents"]).get(), 1);
+
+        let provider = rpc_provider(vec![node(10, "0xa").await, dead_node().await], RpcStrategy::Failover, Some(2));
+        assert!(provider.block_number(&metrics).await.is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use crate::storage::{Checkpoint, EventBatch};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+};
+
+/// Where batches are recorded to and replayed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingConfiguration {
+    /// NDJSON log every batch is appended to.
+    pub record: Option<PathBuf>,
+    /// NDJSON log batches are read from instead of the chain.
+    pub replay: Option<PathBuf>,
+    /// How many times faster than originally batches are replayed.
+    pub replay_speed: f64,
+}
+
+/// NDJSON log of event batches, one batch per line. Every batch is stamped with the time it was recorded at, so it is
+/// processed at the same time when replayed. File is written asynchronously, so slow disk doesn't block runtime
+/// threads HTTP handlers run on.
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Opens log at `path`, batches are appended to the existing ones.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if file can't be opened.
+    pub async fn open(path: &Path) -> Result<Recorder, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| format!("Can't open recording {}: {e}", path.display()))?;
+
+        Ok(Recorder { file: BufWriter::new(file) })
+    }
+
+    /// Appends batch to the log, every line is flushed right away.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if batch can't be written.
+    pub async fn record(&mut self, batch: &EventBatch) -> Result<(), String> {
+        let mut line = serde_json::to_string(batch).map_err(|e| format!("Can't serialise batch: {e}"))?;
+        line.push('\n');
+
+        self.file.write_all(line.as_bytes()).await.map_err(|e| format!("Can't write batch: {e}"))?;
+        self.file.flush().await.map_err(|e| format!("Can't write batch: {e}"))
+    }
+}
+
+/// Forwards every batch from `rx` to `tx`, stamped with the current time, and records it on the way. Failed recording
+/// is reported but doesn't stop batches from being forwarded.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - Datetime calculations failed
+/// - Forwarding channel is closed.
+pub async fn record(
+    mut recorder: Recorder,
+    mut rx: UnboundedReceiver<EventBatch>,
+    tx: UnboundedSender<EventBatch>,
+) -> Result<(), String> {
+    while let Some(batch) = rx.recv().await {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|_| "Can't calculate duration")?;
+        let batch = EventBatch { timestamp: batch.timestamp.or(Some(now.as_secs())), ..batch };
+
+        if let Err(message) = recorder.record(&batch).await {
+            println!("Can't record batch: {message}");
+        }
+
+        tx.send(batch).map_err(|_| "Can't publish events to channel")?;
+    }
+
+    Ok(())
+}
+
+/// Publishes batches of the log at `path` to `tx`, keeping time between them `speed` times shorter than it was when
+/// they were recorded. With `after` set, replay resumes after the last batch with that checkpoint, batches up to it
+/// were processed already. Returns number of replayed batches.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - Log can't be read or has invalid batch
+/// - Publishing channel is closed.
+pub async fn replay(
+    path: &Path,
+    speed: f64,
+    after: Option<Checkpoint>,
+    tx: &UnboundedSender<EventBatch>,
+) -> Result<usize, String> {
+    let resumed = match after {
+        Some(checkpoint) => checkpoint_line(path, checkpoint).await?,
+        None => None,
+    };
+
+    let mut replayed = 0;
+    let mut last_timestamp: Option<u64> = None;
+    let mut lines = open(path).await?.lines();
+    for index in 0.. {
+        let Some(line) = lines.next_line().await.map_err(|e| format!("Can't read recording: {e}"))? else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let batch: EventBatch =
+            serde_json::from_str(&line).map_err(|e| format!("Invalid batch at line {}: {e}", index + 1))?;
+
+        if resumed.is_some_and(|resumed| index <= resumed) {
+            last_timestamp = batch.timestamp.or(last_timestamp);
+            continue;
+        }
+
+        if let (Some(last_timestamp), Some(timestamp)) = (last_timestamp, batch.timestamp) {
+            tokio::time::sleep(Duration::from_secs(timestamp.saturating_sub(last_timestamp)).div_f64(speed)).await;
+        }
+
+        last_timestamp = batch.timestamp.or(last_timestamp);
+        tx.send(batch).map_err(|_| "Can't publish events to channel")?;
+        replayed += 1;
+    }
+
+    Ok(replayed)
+}
+
+/// Index of the last line of log at `path` with batch of `checkpoint`. Invalid lines are skipped, replay reports them.
+async fn checkpoint_line(path: &Path, checkpoint: Checkpoint) -> Result<Option<usize>, String> {
+    let mut found = None;
+    let mut lines = open(path).await?.lines();
+    for index in 0.. {
+        let Some(line) = lines.next_line().await.map_err(|e| format!("Can't read recording: {e}"))? else {
+            break;
+        };
+        let batch = serde_json::from_str::<EventBatch>(&line).ok();
+
+        if batch.is_some_and(|batch| batch.checkpoint == Some(checkpoint)) {
+            found = Some(index);
+        }
+    }
+
+    Ok(found)
+}
+
+async fn open(path: &Path) -> Result<BufReader<File>, String> {
+    let file = File::open(path).await.map_err(|e| format!("Can't open recording {}: {e}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::SpotEntryEvent;
+    use starknet::core::types::Felt;
+    use tokio::{sync::mpsc, time::Instant};
+
+    fn batches() -> Vec<EventBatch> {
+        let event =
+            SpotEntryEvent { timestamp: 990, price: 100, pair_id: Felt::ONE, block_number: 5, ..Default::default() };
+        let checkpoint = Checkpoint { block_number: 5, block_hash: Felt::from(0x5) };
+
+        vec![
+            EventBatch {
+                events: vec![event],
+                checkpoint: Some(checkpoint),
+                timestamp: Some(1_000),
+                ..Default::default()
+            },
+            EventBatch { rollback: Some(5), timestamp: Some(1_060), ..Default::default() },
+            EventBatch { timestamp: Some(1_120), ..Default::default() },
+        ]
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recorded_batches_are_replayed_at_speed() {
+        let path = std::env::temp_dir().join(format!("twapper-recording-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (recorded_tx, mut recorded_rx) = mpsc::unbounded_channel();
+        for batch in batches() {
+            tx.send(batch).unwrap();
+        }
+        drop(tx);
+
+        record(Recorder::open(&path).await.unwrap(), rx, recorded_tx).await.unwrap();
+
+        let mut recorded = Vec::new();
+        while let Some(batch) = recorded_rx.recv().await {
+            recorded.push(batch);
+        }
+
+        assert_eq!(recorded, batches());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let started = Instant::now();
+        assert_eq!(replay(&path, 10.0, None, &tx).await, Ok(3));
+
+        let mut replayed = Vec::new();
+        while let Ok(batch) = rx.try_recv() {
+            replayed.push(batch);
+        }
+
+        assert_eq!(replayed, recorded);
+        // Two gaps of 60s are 10 times shorter
+        assert_eq!(started.elapsed(), Duration::from_secs(12));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_resumes_after_checkpoint() {
+        let path = std::env::temp_dir().join(format!("twapper-resumed-recording-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut recorder = Recorder::open(&path).await.unwrap();
+        for batch in batches() {
+            recorder.record(&batch).await.unwrap();
+        }
+
+        // Checkpoint of the first batch was processed before restart
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let checkpoint = batches()[0].checkpoint;
+        assert_eq!(replay(&path, 1.0, checkpoint, &tx).await, Ok(2));
+
+        let mut replayed = Vec::new();
+        while let Ok(batch) = rx.try_recv() {
+            replayed.push(batch);
+        }
+        assert_eq!(replayed, batches()[1..]);
+
+        // Unknown checkpoint replays the whole log
+        let checkpoint = Checkpoint { block_number: 6, block_hash: Felt::from(0x6) };
+        assert_eq!(replay(&path, 1.0, Some(checkpoint), &tx).await, Ok(3));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn batch_without_time_is_stamped() {
+        let path = std::env::temp_dir().join(format!("twapper-stamped-recording-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (recorded_tx, mut recorded_rx) = mpsc::unbounded_channel();
+        tx.send(EventBatch::default()).unwrap();
+        drop(tx);
+
+        let before = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        record(Recorder::open(&path).await.unwrap(), rx, recorded_tx).await.unwrap();
+        let after = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+
+        let timestamp = recorded_rx.recv().await.unwrap().timestamp.unwrap();
+        assert!(before <= timestamp && timestamp <= after);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_batch_is_reported() {
+        let path = std::env::temp_dir().join(format!("twapper-invalid-recording-{}.ndjson", std::process::id()));
+        std::fs::write(&path, "{\"events\": []}\n\nnot a batch\n").unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let result = replay(&path, 1.0, None, &tx).await;
+
+        assert!(matches!(result, Err(message) if message.starts_with("Invalid batch at line 3")));
+        assert_eq!(rx.try_recv(), Ok(EventBatch::default()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,634 @@
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::PathBuf};
+
+/// Raw configuration as it is read from configuration file, environment and command line. Values are not validated
+/// here, this is done when `ApplicationConfiguration` is built, so every invalid field can be reported at once.
+///
+/// Sources are layered in the following order, each next one overrides previous:
+/// 1. Defaults
+/// 2. Configuration file
+/// 3. Environment variables
+/// 4. Command line arguments
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub network: NetworkSettings,
+    pub pairs: Option<Vec<PairSettings>>,
+    pub window: WindowSettings,
+    pub aggregation: AggregationSettings,
+    pub signing: SigningSettings,
+    pub storage: StorageSettings,
+    pub supervisor: SupervisorSettings,
+    pub quality: QualitySettings,
+    pub recording: RecordingSettings,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub heartbeat: Option<String>,
+    /// Age of signed twap after which service is not ready anymore.
+    pub max_staleness: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSettings {
+    pub preset: Option<String>,
+    pub rpc_url: Option<String>,
+    /// Several JSON-RPC endpoints, overrides `rpc_url`.
+    pub rpc_urls: Option<Vec<String>>,
+    /// How calls are spread over endpoints: `failover` or `round_robin`.
+    pub rpc_strategy: Option<String>,
+    pub rpc_timeout: Option<String>,
+    /// Endpoints that should respond the same to every call.
+    pub rpc_quorum: Option<usize>,
+    /// WebSocket endpoint new blocks are subscribed to, instead of polling.
+    pub ws_url: Option<String>,
+    pub oracle_address: Option<String>,
+    pub event_selector: Option<String>,
+    pub block_time: Option<u64>,
+    /// Block status blocks are ingested at: `latest` or `accepted_on_l1`.
+    pub finality: Option<String>,
+    /// Blocks fetched per request while catching up.
+    pub backfill_chunk: Option<u64>,
+    /// Chunks fetched at once while catching up.
+    pub backfill_parallelism: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PairSettings {
+    pub name: String,
+    /// Windows of the pair, defaults from `window` section are used if omitted.
+    #[serde(default)]
+    pub windows: Option<Vec<String>>,
+    /// Aggregator of every window of the pair, overrides one from `aggregation` section.
+    #[serde(default)]
+    pub aggregator: Option<String>,
+    /// Aggregators of particular windows of the pair by window, e.g. `{ "24h" = "tw-median" }`.
+    #[serde(default)]
+    pub aggregators: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowSettings {
+    /// Default TWAP windows for pairs without own ones.
+    pub durations: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregationSettings {
+    /// Reducer of same second entries: `median`, `mean` or `publisher_weighted`.
+    pub reducer: Option<String>,
+    /// Weights of publishers by name for `publisher_weighted` reducer, publishers that are not listed have weight 1.
+    pub publisher_weights: Option<HashMap<String, u64>>,
+    /// Outlier cutoff in median absolute deviations of the window.
+    pub mad_cutoff: Option<f64>,
+    /// Maximum relative jump from previous accepted price, e.g. `0.1` for 10%.
+    pub max_jump: Option<f64>,
+    /// Maximum relative deviation from median of the same second.
+    pub max_publisher_deviation: Option<f64>,
+    /// Price between updates: `left`, `right` or `linear`.
+    pub interpolation: Option<String>,
+    /// Default aggregator of windows: `twap`, `tw-median`, `geometric`, `ema`, `trimmed:N` or `winsorized:N`.
+    pub aggregator: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningSettings {
+    pub scheme: Option<String>,
+    pub secret_key: Option<String>,
+    pub public_key: Option<String>,
+    pub stark_secret_key: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    pub capacity: Option<usize>,
+    /// Persistence backend: `memory` or `sled`.
+    pub backend: Option<String>,
+    /// Database directory for `sled` backend.
+    pub path: Option<PathBuf>,
+    /// How long history is kept beyond the longest window, so twaps of windows ending in the past can be calculated.
+    pub retention: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisorSettings {
+    /// Delay before the first restart of failed worker, doubles with every next failure.
+    pub initial_backoff: Option<String>,
+    pub max_backoff: Option<String>,
+    /// Failures tolerated within `budget_period` before worker is reported failed.
+    pub failure_budget: Option<u32>,
+    pub budget_period: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QualitySettings {
+    /// Entries required within a window for it to be signed.
+    pub min_samples: Option<usize>,
+    /// Distinct publishers required within a window.
+    pub min_publishers: Option<usize>,
+    /// Longest time without entries allowed within a window, e.g. `5m`.
+    pub max_gap: Option<String>,
+    /// Fraction of a window there should be a price for, e.g. `0.9`.
+    pub min_coverage: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingSettings {
+    /// NDJSON log every event batch is appended to.
+    pub record: Option<PathBuf>,
+    /// NDJSON log event batches are replayed from instead of fetching them from the chain.
+    pub replay: Option<PathBuf>,
+    /// How many times faster than originally batches are replayed, e.g. `10`.
+    pub replay_speed: Option<f64>,
+}
+
+/// Command line arguments. Every argument can be supplied with environment variable as well, command line value
+/// takes precedence over environment one.
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "Calculates and signs TWAP of Pragma oracle spot entries on Starknet")]
+pub struct Cli {
+    /// Path to configuration file. Format is detected by extension: `.toml`, `.yaml` or `.yml`.
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Address server is bound to.
+    #[arg(long, env = "ADDRESS")]
+    pub host: Option<String>,
+
+    /// Port server is bound to.
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+
+    /// How often heartbeats are sent to streaming clients, e.g. `15s`.
+    #[arg(long, env = "HEARTBEAT")]
+    pub heartbeat: Option<String>,
+
+    /// Age of signed twap after which `/readyz` reports service as not ready, e.g. `5m`.
+    #[arg(long, env = "MAX_STALENESS")]
+    pub max_staleness: Option<String>,
+
+    /// Network preset: `mainnet` or `sepolia`.
+    #[arg(long, env = "NETWORK")]
+    pub network: Option<String>,
+
+    /// Starknet JSON-RPC node url.
+    #[arg(long, env = "RPC_URL")]
+    pub rpc_url: Option<String>,
+
+    /// Comma separated list of Starknet JSON-RPC node urls, overrides single url.
+    #[arg(long, env = "RPC_URLS", value_delimiter = ',')]
+    pub rpc_urls: Option<Vec<String>>,
+
+    /// How calls are spread over RPC nodes: `failover` or `round_robin`.
+    #[arg(long, env = "RPC_STRATEGY")]
+    pub rpc_strategy: Option<String>,
+
+    /// Time RPC node should respond within before the next one is called, e.g. `10s`.
+    #[arg(long, env = "RPC_TIMEOUT")]
+    pub rpc_timeout: Option<String>,
+
+    /// Number of RPC nodes that should respond the same to every call, every node is called at once then.
+    #[arg(long, env = "RPC_QUORUM")]
+    pub rpc_quorum: Option<usize>,
+
+    /// Starknet WebSocket node url, new blocks are subscribed to instead of polling when set.
+    #[arg(long, env = "WS_URL")]
+    pub ws_url: Option<String>,
+
+    /// Hex encoded address of Pragma oracle contract.
+    #[arg(long, env = "ORACLE_ADDRESS")]
+    pub oracle_address: Option<String>,
+
+    /// Hex encoded key of spot entry event.
+    #[arg(long, env = "EVENT_SELECTOR")]
+    pub event_selector: Option<String>,
+
+    /// Average block time in seconds, used to estimate how many blocks to scan on start.
+    #[arg(long, env = "BLOCK_TIME")]
+    pub block_time: Option<u64>,
+
+    /// Blocks ingested by the service: `latest` or `accepted_on_l1` ones only.
+    #[arg(long, env = "FINALITY")]
+    pub finality: Option<String>,
+
+    /// Number of blocks events are fetched for at once while catching up with the chain.
+    #[arg(long, env = "BACKFILL_CHUNK")]
+    pub backfill_chunk: Option<u64>,
+
+    /// Number of block chunks fetched concurrently while catching up with the chain.
+    #[arg(long, env = "BACKFILL_PARALLELISM")]
+    pub backfill_parallelism: Option<usize>,
+
+    /// Comma separated list of tracked pairs, e.g. `BTC/USD,ETH/USD`.
+    #[arg(long, env = "PAIRS", value_delimiter = ',')]
+    pub pairs: Option<Vec<String>>,
+
+    /// Comma separated list of default TWAP windows, e.g. `5m,30m,1h,24h`.
+    #[arg(long, env = "WINDOWS", value_delimiter = ',')]
+    pub windows: Option<Vec<String>>,
+
+    /// Reducer of entries submitted within the same second: `median`, `mean` or `publisher_weighted`.
+    #[arg(long, env = "REDUCER")]
+    pub reducer: Option<String>,
+
+    /// Entries further from window median than that many median absolute deviations are rejected.
+    #[arg(long, env = "MAD_CUTOFF")]
+    pub mad_cutoff: Option<f64>,
+
+    /// Entries that jump from previous accepted price more than that relative value are rejected, e.g. `0.1`.
+    #[arg(long, env = "MAX_JUMP")]
+    pub max_jump: Option<f64>,
+
+    /// Entries that deviate from median of the same second more than that relative value are rejected.
+    #[arg(long, env = "MAX_PUBLISHER_DEVIATION")]
+    pub max_publisher_deviation: Option<f64>,
+
+    /// Price between updates: `left` (previous price holds), `right` (next price holds) or `linear`.
+    #[arg(long, env = "INTERPOLATION")]
+    pub interpolation: Option<String>,
+
+    /// Aggregator of windows: `twap`, `tw-median`, `geometric`, `ema`, `trimmed:N` or `winsorized:N`, where `N` is
+    /// percent of time cut off or clamped at each tail.
+    #[arg(long, env = "AGGREGATOR")]
+    pub aggregator: Option<String>,
+
+    /// Signing scheme: `secp256k1`, `stark` or `evm`.
+    #[arg(long, env = "SIGNING_SCHEME")]
+    pub signing_scheme: Option<String>,
+
+    /// Hex encoded secret key.
+    #[arg(long, env = "SECRET_KEY", hide_env_values = true)]
+    pub secret_key: Option<String>,
+
+    /// Hex encoded compressed public key.
+    #[arg(long, env = "PUBLIC_KEY")]
+    pub public_key: Option<String>,
+
+    /// Hex encoded STARK curve secret key.
+    #[arg(long, env = "STARK_SECRET_KEY", hide_env_values = true)]
+    pub stark_secret_key: Option<String>,
+
+    /// Amount of per second prices per pair storage preallocates memory for.
+    #[arg(long, env = "STORAGE_CAPACITY")]
+    pub storage_capacity: Option<usize>,
+
+    /// Persistence backend: `memory` or `sled`.
+    #[arg(long, env = "STORAGE_BACKEND")]
+    pub storage_backend: Option<String>,
+
+    /// Database directory for `sled` backend.
+    #[arg(long, env = "STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+
+    /// How long history is kept beyond the longest window for historical queries, e.g. `24h`.
+    #[arg(long, env = "STORAGE_RETENTION")]
+    pub storage_retention: Option<String>,
+
+    /// Delay before the first restart of failed worker, e.g. `1s`. Doubles with every next failure.
+    #[arg(long, env = "SUPERVISOR_INITIAL_BACKOFF")]
+    pub supervisor_initial_backoff: Option<String>,
+
+    /// Maximal delay before restart of failed worker, e.g. `5m`.
+    #[arg(long, env = "SUPERVISOR_MAX_BACKOFF")]
+    pub supervisor_max_backoff: Option<String>,
+
+    /// Failures tolerated within budget period before worker is reported failed.
+    #[arg(long, env = "SUPERVISOR_FAILURE_BUDGET")]
+    pub supervisor_failure_budget: Option<u32>,
+
+    /// Period failures are counted within, e.g. `1h`.
+    #[arg(long, env = "SUPERVISOR_BUDGET_PERIOD")]
+    pub supervisor_budget_period: Option<String>,
+
+    /// Entries of every publisher required within a window for it to be signed.
+    #[arg(long, env = "MIN_SAMPLES")]
+    pub min_samples: Option<usize>,
+
+    /// Distinct publishers required within a window for it to be signed.
+    #[arg(long, env = "MIN_PUBLISHERS")]
+    pub min_publishers: Option<usize>,
+
+    /// Longest time without entries allowed within a window, e.g. `5m`.
+    #[arg(long, env = "MAX_GAP")]
+    pub max_gap: Option<String>,
+
+    /// Fraction of a window there should be a price for, e.g. `0.9`. Window with opening price is covered entirely.
+    #[arg(long, env = "MIN_COVERAGE")]
+    pub min_coverage: Option<f64>,
+
+    /// Path to NDJSON log every event batch is appended to.
+    #[arg(long, env = "RECORD")]
+    pub record: Option<PathBuf>,
+
+    /// Path to NDJSON log event batches are replayed from instead of fetching them from the chain.
+    #[arg(long, env = "REPLAY")]
+    pub replay: Option<PathBuf>,
+
+    /// How many times faster than originally batches are replayed, e.g. `10`.
+    #[arg(long, env = "REPLAY_SPEED")]
+    pub replay_speed: Option<f64>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands run instead of the service.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Fetches events of a block or time range into storage and exits. Service should be stopped meanwhile.
+    Backfill(BackfillArgs),
+}
+
+/// Range to backfill, it ends at the latest block unless end is set.
+#[derive(Debug, Args)]
+pub struct BackfillArgs {
+    /// The first block of the range.
+    #[arg(long, required_unless_present = "from_time", conflicts_with = "from_time")]
+    pub from_block: Option<u64>,
+
+    /// The last block of the range.
+    #[arg(long, conflicts_with = "to_time")]
+    pub to_block: Option<u64>,
+
+    /// Unix timestamp the range starts at.
+    #[arg(long)]
+    pub from_time: Option<u64>,
+
+    /// Unix timestamp the range ends at.
+    #[arg(long)]
+    pub to_time: Option<u64>,
+}
+
+impl Settings {
+    /// Reads settings from file. Format is detected by file extension.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if file can't be read, has unsupported extension or can't be parsed.
+    pub fn from_file(path: &PathBuf) -> Result<Settings, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("Can't read configuration file {}: {e}", path.display()))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Settings::from_toml(&content),
+            Some("yaml") | Some("yml") => Settings::from_yaml(&content),
+            _ => Err(format!("Unsupported configuration file format: {}", path.display())),
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<Settings, String> {
+        toml::from_str(content).map_err(|e| format!("Can't parse configuration file: {e}"))
+    }
+
+    pub fn from_yaml(content: &str) -> Result<Settings, String> {
+        serde_yaml::from_str(content).map_err(|e| format!("Can't parse configuration file: {e}"))
+    }
+
+    /// Loads settings from all sources in order of precedence.
+    pub fn load(cli: &Cli) -> Result<Settings, String> {
+        let settings = if let Some(path) = &cli.config { Settings::from_file(path)? } else { Settings::default() };
+
+        Ok(settings.merge(cli.into()))
+    }
+
+    /// Merges two layers of settings, values from `other` take precedence.
+    pub fn merge(self, other: Settings) -> Settings {
+        Settings {
+            server: ServerSettings {
+                host: other.server.host.or(self.server.host),
+                port: other.server.port.or(self.server.port),
+                heartbeat: other.server.heartbeat.or(self.server.heartbeat),
+                max_staleness: other.server.max_staleness.or(self.server.max_staleness),
+            },
+            network: NetworkSettings {
+                preset: other.network.preset.or(self.network.preset),
+                rpc_url: other.network.rpc_url.or(self.network.rpc_url),
+                rpc_urls: other.network.rpc_urls.or(self.network.rpc_urls),
+                rpc_strategy: other.network.rpc_strategy.or(self.network.rpc_strategy),
+                rpc_timeout: other.network.rpc_timeout.or(self.network.rpc_timeout),
+                rpc_quorum: other.network.rpc_quorum.or(self.network.rpc_quorum),
+                ws_url: other.network.ws_url.or(self.network.ws_url),
+                oracle_address: other.network.oracle_address.or(self.network.oracle_address),
+                event_selector: other.network.event_selector.or(self.network.event_selector),
+                block_time: other.network.block_time.or(self.network.block_time),
+                finality: other.network.finality.or(self.network.finality),
+                backfill_chunk: other.network.backfill_chunk.or(self.network.backfill_chunk),
+                backfill_parallelism: other.network.backfill_parallelism.or(self.network.backfill_parallelism),
+            },
+            pairs: other.pairs.or(self.pairs),
+            window: WindowSettings { durations: other.window.durations.or(self.window.durations) },
+            aggregation: AggregationSettings {
+                reducer: other.aggregation.reducer.or(self.aggregation.reducer),
+                publisher_weights: other.aggregation.publisher_weights.or(self.aggregation.publisher_weights),
+                mad_cutoff: other.aggregation.mad_cutoff.or(self.aggregation.mad_cutoff),
+                max_jump: other.aggregation.max_jump.or(self.aggregation.max_jump),
+                max_publisher_deviation: other
+                    .aggregation
+                    .max_publisher_deviation
+                    .or(self.aggregation.max_publisher_deviation),
+                interpolation: other.aggregation.interpolation.or(self.aggregation.interpolation),
+                aggregator: other.aggregation.aggregator.or(self.aggregation.aggregator),
+            },
+            signing: SigningSettings {
+                scheme: other.signing.scheme.or(self.signing.scheme),
+                secret_key: other.signing.secret_key.or(self.signing.secret_key),
+                public_key: other.signing.public_key.or(self.signing.public_key),
+                stark_secret_key: other.signing.stark_secret_key.or(self.signing.stark_secret_key),
+            },
+            storage: StorageSettings {
+                capacity: other.storage.capacity.or(self.storage.capacity),
+                backend: other.storage.backend.or(self.storage.backend),
+                path: other.storage.path.or(self.storage.path),
+                retention: other.storage.retention.or(self.storage.retention),
+            },
+            supervisor: SupervisorSettings {
+                initial_backoff: other.supervisor.initial_backoff.or(self.supervisor.initial_backoff),
+                max_backoff: other.supervisor.max_backoff.or(self.supervisor.max_backoff),
+                failure_budget: other.supervisor.failure_budget.or(self.supervisor.failure_budget),
+                budget_period: other.supervisor.budget_period.or(self.supervisor.budget_period),
+            },
+            quality: QualitySettings {
+                min_samples: other.quality.min_samples.or(self.quality.min_samples),
+                min_publishers: other.quality.min_publishers.or(self.quality.min_publishers),
+                max_gap: other.quality.max_gap.or(self.quality.max_gap),
+                min_coverage: other.quality.min_coverage.or(self.quality.min_coverage),
+            },
+            recording: RecordingSettings {
+                record: other.recording.record.or(self.recording.record),
+                replay: other.recording.replay.or(self.recording.replay),
+                replay_speed: other.recording.replay_speed.or(self.recording.replay_speed),
+            },
+        }
+    }
+}
+
+impl From<&Cli> for Settings {
+    fn from(cli: &Cli) -> Self {
+        Settings {
+            server: ServerSettings {
+                host: cli.host.clone(),
+                port: cli.port,
+                heartbeat: cli.heartbeat.clone(),
+                max_staleness: cli.max_staleness.clone(),
+            },
+            network: NetworkSettings {
+                preset: cli.network.clone(),
+                rpc_url: cli.rpc_url.clone(),
+                rpc_urls: cli.rpc_urls.clone(),
+                rpc_strategy: cli.rpc_strategy.clone(),
+                rpc_timeout: cli.rpc_timeout.clone(),
+                rpc_quorum: cli.rpc_quorum,
+                ws_url: cli.ws_url.clone(),
+                oracle_address: cli.oracle_address.clone(),
+                event_selector: cli.event_selector.clone(),
+                block_time: cli.block_time,
+                finality: cli.finality.clone(),
+                backfill_chunk: cli.backfill_chunk,
+                backfill_parallelism: cli.backfill_parallelism,
+            },
+            pairs: cli.pairs.as_ref().map(|pairs| {
+                pairs.iter().map(|name| PairSettings { name: name.clone(), ..Default::default() }).collect()
+            }),
+            window: WindowSettings { durations: cli.windows.clone() },
+            aggregation: AggregationSettings {
+                reducer: cli.reducer.clone(),
+                publisher_weights: None,
+                mad_cutoff: cli.mad_cutoff,
+                max_jump: cli.max_jump,
+                max_publisher_deviation: cli.max_publisher_deviation,
+                interpolation: cli.interpolation.clone(),
+                aggregator: cli.aggregator.clone(),
+            },
+            signing: SigningSettings {
+                scheme: cli.signing_scheme.clone(),
+                secret_key: cli.secret_key.clone(),
+                public_key: cli.public_key.clone(),
+                stark_secret_key: cli.stark_secret_key.clone(),
+            },
+            storage: StorageSettings {
+                capacity: cli.storage_capacity,
+                backend: cli.storage_backend.clone(),
+                path: cli.storage_path.clone(),
+                retention: cli.storage_retention.clone(),
+            },
+            supervisor: SupervisorSettings {
+                initial_backoff: cli.supervisor_initial_backoff.clone(),
+                max_backoff: cli.supervisor_max_backoff.clone(),
+                failure_budget: cli.supervisor_failure_budget,
+                budget_period: cli.supervisor_budget_period.clone(),
+            },
+            quality: QualitySettings {
+                min_samples: cli.min_samples,
+                min_publishers: cli.min_publishers,
+                max_gap: cli.max_gap.clone(),
+                min_coverage: cli.min_coverage,
+            },
+            recording: RecordingSettings {
+                record: cli.record.clone(),
+                replay: cli.replay.clone(),
+                replay_speed: cli.replay_speed,
+            },
+        }
+    }
+}
+
+/// Parses duration in seconds. Value is either plain number of seconds or number with one of `s`, `m`, `h`, `d`
+/// suffixes.
+pub fn parse_duration(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (amount, multiplier) = match value.char_indices().last() {
+        Some((idx, 's')) => (&value[..idx], 1),
+        Some((idx, 'm')) => (&value[..idx], 60),
+        Some((idx, 'h')) => (&value[..idx], 3600),
+        Some((idx, 'd')) => (&value[..idx], 86400),
+        _ => (value, 1),
+    };
+
+    let amount: u64 = amount.parse().map_err(|_| format!("Invalid duration: {value:?}"))?;
+
+    amount.checked_mul(multiplier).ok_or(format!("Duration is too big: {value:?}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn duration_parsing() {
+        assert_eq!(parse_duration("3600"), Ok(3600));
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("5m"), Ok(300));
+        assert_eq!(parse_duration("1h"), Ok(3600));
+        assert_eq!(parse_duration("1d"), Ok(86400));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1w").is_err());
+    }
+
+    #[test]
+    fn toml_and_yaml_are_equal() {
+        let toml = r#"
+            pairs = [{ name = "BTC/USD", windows = ["5m", "1h"] }, { name = "ETH/USD" }]
+
+            [server]
+            host = "127.0.0.1"
+            port = 8080
+
+            [network]
+            preset = "mainnet"
+
+            [window]
+            durations = ["1h"]
+        "#;
+
+        let yaml = r#"
+            server:
+              host: 127.0.0.1
+              port: 8080
+            network:
+              preset: mainnet
+            pairs:
+              - name: BTC/USD
+                windows: [5m, 1h]
+              - name: ETH/USD
+            window:
+              durations: [1h]
+        "#;
+
+        let settings = Settings::from_toml(toml).unwrap();
+        assert_eq!(settings, Settings::from_yaml(yaml).unwrap());
+        assert_eq!(settings.server.port, Some(8080));
+        assert_eq!(settings.pairs.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(Settings::from_toml("[server]\nhots = \"127.0.0.1\"").is_err());
+    }
+
+    #[test]
+    fn later_layer_takes_precedence() {
+        let file = Settings::from_toml("[server]\nhost = \"127.0.0.1\"\nport = 8080").unwrap();
+        let cli = Cli { port: Some(9090), ..Default::default() };
+
+        let settings = file.merge((&cli).into());
+
+        assert_eq!(settings.server.host, Some("127.0.0.1".to_string()));
+        assert_eq!(settings.server.port, Some(9090));
+    }
+}
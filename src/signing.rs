@@ -0,0 +1,261 @@
+use crate::attestation::{Attestation, keccak256};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey, ecdsa};
+use starknet::{core::types::Felt, signers::SigningKey};
+use std::{fmt::Display, str::FromStr};
+
+/// Scheme attestations are signed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigningScheme {
+    /// ECDSA over secp256k1 curve of sha256 digest of byte encoded attestation.
+    Secp256k1,
+    /// ECDSA over STARK curve of pedersen hash of felt encoded attestation, verifiable in Cairo with
+    /// `check_ecdsa_signature`.
+    Stark,
+    /// Recoverable ECDSA over secp256k1 curve of EIP-712 digest of typed attestation, verifiable in Solidity with
+    /// `ecrecover`.
+    Evm,
+}
+
+impl FromStr for SigningScheme {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "secp256k1" => Ok(SigningScheme::Secp256k1),
+            "stark" => Ok(SigningScheme::Stark),
+            "evm" => Ok(SigningScheme::Evm),
+            _ => Err(format!("Unknown signing scheme: {value:?}")),
+        }
+    }
+}
+
+impl Display for SigningScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SigningScheme::Secp256k1 => write!(f, "secp256k1"),
+            SigningScheme::Stark => write!(f, "stark"),
+            SigningScheme::Evm => write!(f, "evm"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttestationSignature {
+    Secp256k1(ecdsa::Signature),
+    Stark {
+        r: Felt,
+        s: Felt,
+    },
+    /// `r || s || v` where `v` is recovery id plus 27.
+    Evm([u8; 65]),
+}
+
+impl AttestationSignature {
+    /// Serialises signature as scheme tag byte followed by signature bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            AttestationSignature::Secp256k1(signature) => [&[0_u8], signature.serialize_compact().as_slice()].concat(),
+            AttestationSignature::Stark { r, s } => [[1_u8].as_slice(), &r.to_bytes_be(), &s.to_bytes_be()].concat(),
+            AttestationSignature::Evm(signature) => [&[2_u8], signature.as_slice()].concat(),
+        }
+    }
+
+    /// Deserialises signature produced by `encode`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if scheme tag is unknown or signature is malformed.
+    pub fn decode(bytes: &[u8]) -> Result<AttestationSignature, String> {
+        match bytes.split_first() {
+            Some((0, signature)) => ecdsa::Signature::from_compact(signature)
+                .map(AttestationSignature::Secp256k1)
+                .map_err(|_| "Can't decode secp256k1 signature".to_string()),
+            Some((1, signature)) if signature.len() == 64 => Ok(AttestationSignature::Stark {
+                r: Felt::from_bytes_be_slice(&signature[..32]),
+                s: Felt::from_bytes_be_slice(&signature[32..]),
+            }),
+            Some((2, signature)) => signature
+                .try_into()
+                .map(AttestationSignature::Evm)
+                .map_err(|_| "Can't decode evm signature".to_string()),
+            _ => Err("Can't decode signature".to_string()),
+        }
+    }
+}
+
+/// Holds keys and signs attestations with configured scheme.
+pub struct Signer {
+    pub scheme: SigningScheme,
+    secp: Secp256k1<secp256k1::All>,
+    secret_key: SecretKey,
+    stark_key: SigningKey,
+}
+
+impl Signer {
+    pub fn new(scheme: SigningScheme, secret_key: SecretKey, stark_key: SigningKey) -> Signer {
+        Signer { scheme, secp: Secp256k1::gen_new(), secret_key, stark_key }
+    }
+
+    pub fn stark_public_key(&self) -> Felt {
+        self.stark_key.verifying_key().scalar()
+    }
+
+    /// Signs attestation with configured scheme.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if attestation hash is out of STARK curve signing range.
+    pub fn sign(&self, attestation: &Attestation) -> Result<AttestationSignature, String> {
+        match self.scheme {
+            SigningScheme::Secp256k1 => {
+                let message = Message::from_digest(attestation.digest());
+                Ok(AttestationSignature::Secp256k1(self.secp.sign_ecdsa(&message, &self.secret_key)))
+            }
+            SigningScheme::Stark => {
+                let signature = self
+                    .stark_key
+                    .sign(&attestation.pedersen_hash())
+                    .map_err(|e| format!("Can't sign attestation with STARK key: {e}"))?;
+
+                Ok(AttestationSignature::Stark { r: signature.r, s: signature.s })
+            }
+            SigningScheme::Evm => {
+                let message = Message::from_digest(attestation.eip712_digest());
+                let (recovery_id, rs) =
+                    self.secp.sign_ecdsa_recoverable(&message, &self.secret_key).serialize_compact();
+
+                let mut signature = [0_u8; 65];
+                signature[..64].copy_from_slice(&rs);
+                signature[64] = 27 + i32::from(recovery_id) as u8;
+
+                Ok(AttestationSignature::Evm(signature))
+            }
+        }
+    }
+
+    /// Checks that attestation is signed with configured scheme and keys.
+    pub fn verify(&self, attestation: &Attestation, signature: &AttestationSignature) -> bool {
+        let scheme = match signature {
+            AttestationSignature::Secp256k1(_) => SigningScheme::Secp256k1,
+            AttestationSignature::Stark { .. } => SigningScheme::Stark,
+            AttestationSignature::Evm(_) => SigningScheme::Evm,
+        };
+
+        if scheme != self.scheme {
+            return false;
+        }
+
+        match signature {
+            AttestationSignature::Secp256k1(signature) => {
+                let message = Message::from_digest(attestation.digest());
+                let public_key = secp256k1::PublicKey::from_secret_key(&self.secp, &self.secret_key);
+                self.secp.verify_ecdsa(&message, signature, &public_key).is_ok()
+            }
+            AttestationSignature::Stark { r, s } => self
+                .stark_key
+                .verifying_key()
+                .verify(&attestation.pedersen_hash(), &starknet::core::crypto::Signature { r: *r, s: *s })
+                .unwrap_or(false),
+            AttestationSignature::Evm(signature) => {
+                let message = Message::from_digest(attestation.eip712_digest());
+                let public_key = secp256k1::PublicKey::from_secret_key(&self.secp, &self.secret_key);
+
+                ecdsa::RecoveryId::try_from(i32::from(signature[64]) - 27)
+                    .and_then(|id| ecdsa::RecoverableSignature::from_compact(&signature[..64], id))
+                    .and_then(|signature| self.secp.recover_ecdsa(&message, &signature))
+                    .is_ok_and(|recovered| ethereum_address(&recovered) == ethereum_address(&public_key))
+            }
+        }
+    }
+}
+
+/// Ethereum address of the key, last 20 bytes of keccak256 of uncompressed public key without prefix byte.
+pub fn ethereum_address(public_key: &PublicKey) -> [u8; 20] {
+    let hash = keccak256(&public_key.serialize_uncompressed()[1..]);
+    hash[12..].try_into().unwrap()
+}
+
+/// Hex encoded address with EIP-55 mixed case checksum.
+pub fn checksum_address(address: &[u8; 20]) -> String {
+    let hex: String = address.iter().map(|byte| format!("{byte:02x}")).collect();
+    let hash = keccak256(hex.as_bytes());
+
+    let checksummed: String = hex
+        .chars()
+        .enumerate()
+        .map(|(idx, c)| {
+            let nibble = (hash[idx / 2] >> if idx % 2 == 0 { 4 } else { 0 }) & 0x0f;
+            if nibble >= 8 { c.to_ascii_uppercase() } else { c }
+        })
+        .collect();
+
+    format!("0x{checksummed}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attestation::{ATTESTATION_VERSION, Anchor};
+    use num_bigint::BigUint;
+    use secp256k1::rand::rngs::OsRng;
+
+    fn attestation() -> Attestation {
+        Attestation {
+            version: ATTESTATION_VERSION,
+            chain_id: Felt::ONE,
+            pair_id: Felt::TWO,
+            algorithm: Felt::from_bytes_be_slice("twap/left".as_bytes()),
+            window_start: 1_000,
+            window_end: 4_600,
+            timestamp: 4_600,
+            sequence: 1,
+            anchor: Anchor::Latest,
+            twap: BigUint::from(100_u64) << 64,
+        }
+    }
+
+    #[test]
+    fn signatures_are_verified() {
+        for scheme in [SigningScheme::Secp256k1, SigningScheme::Stark, SigningScheme::Evm] {
+            let signer = Signer::new(scheme, SecretKey::new(&mut OsRng), SigningKey::from_random());
+            let signature = signer.sign(&attestation()).unwrap();
+
+            assert!(signer.verify(&attestation(), &signature));
+            assert!(!signer.verify(&Attestation { sequence: 2, ..attestation() }, &signature));
+            assert_eq!(AttestationSignature::decode(&signature.encode()), Ok(signature));
+        }
+    }
+
+    #[test]
+    fn scheme_parsing() {
+        assert_eq!("secp256k1".parse(), Ok(SigningScheme::Secp256k1));
+        assert_eq!("STARK".parse(), Ok(SigningScheme::Stark));
+        assert_eq!("evm".parse(), Ok(SigningScheme::Evm));
+        assert!("ed25519".parse::<SigningScheme>().is_err());
+    }
+
+    #[test]
+    fn evm_signature_has_recovery_id() {
+        let signer = Signer::new(SigningScheme::Evm, SecretKey::new(&mut OsRng), SigningKey::from_random());
+        let AttestationSignature::Evm(signature) = signer.sign(&attestation()).unwrap() else {
+            panic!("Wrong signature type");
+        };
+
+        assert!(signature[64] == 27 || signature[64] == 28);
+        assert!(signer.verify(&attestation(), &AttestationSignature::Evm(signature)));
+    }
+
+    #[test]
+    fn ethereum_address_derivation() {
+        // Well known key `0x...01`, its address is a generator point address.
+        let secret_key = SecretKey::from_byte_array(&{
+            let mut bytes = [0_u8; 32];
+            bytes[31] = 1;
+            bytes
+        })
+        .unwrap();
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+
+        assert_eq!(checksum_address(&ethereum_address(&public_key)), "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf");
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{
+    configuration::NetworkConfiguration,
+    metrics::Metrics,
+    provider::RpcProvider,
+    recording::replay,
+    storage::{Checkpoint, EventBatch},
+    workers::fetch_events,
+};
+use starknet::core::types::Felt;
+use std::{collections::HashSet, future::Future, path::PathBuf, pin::Pin, sync::Arc, time::Duration};
+use tokio::sync::mpsc::UnboundedSender;
+
+pub type SourceFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// Source of price observations. It publishes them to processor in batches, storage and HTTP layer don't depend on
+/// where they come from.
+pub trait EventSource: Send + Sync {
+    /// Human readable description of the source for logs.
+    fn description(&self) -> String;
+
+    /// Publishes batches of observations to `tx`, resuming after `checkpoint` if source supports it. Source that can
+    /// be exhausted returns once it is, it is restarted only if it fails.
+    fn publish<'a>(
+        &'a self,
+        checkpoint: Option<Checkpoint>,
+        tx: UnboundedSender<EventBatch>,
+        metrics: &'a Metrics,
+    ) -> SourceFuture<'a>;
+}
+
+/// Spot entries submitted to Pragma oracle on Starknet, read over JSON-RPC.
+pub struct StarknetSource {
+    pub network: NetworkConfiguration,
+    pub provider: Arc<RpcProvider>,
+    /// How far back from the chain head blocks are scanned on start.
+    pub history: Duration,
+    pub pair_ids: HashSet<Felt>,
+}
+
+impl EventSource for StarknetSource {
+    fn description(&self) -> String {
+        let rpc_urls: Vec<String> = self.network.rpc_urls.iter().map(|url| url.to_string()).collect();
+        format!("{} network using nodes: {}", self.network.name, rpc_urls.join(", "))
+    }
+
+    fn publish<'a>(
+        &'a self,
+        checkpoint: Option<Checkpoint>,
+        tx: UnboundedSender<EventBatch>,
+        metrics: &'a Metrics,
+    ) -> SourceFuture<'a> {
+        let (network, provider, pair_ids) = (&self.network, self.provider.as_ref(), self.pair_ids.clone());
+        Box::pin(fetch_events(network, provider, self.history, checkpoint, pair_ids, tx, metrics))
+    }
+}
+
+/// Batches recorded to NDJSON log, replayed once at `speed` times original pace. When restarted after failure, replay
+/// resumes after the checkpoint, so batches that were processed already are not published again.
+pub struct FileSource {
+    pub path: PathBuf,
+    pub speed: f64,
+}
+
+impl EventSource for FileSource {
+    fn description(&self) -> String {
+        format!("recording {} at {}x speed", self.path.display(), self.speed)
+    }
+
+    fn publish<'a>(
+        &'a self,
+        checkpoint: Option<Checkpoint>,
+        tx: UnboundedSender<EventBatch>,
+        _metrics: &'a Metrics,
+    ) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let batches = replay(&self.path, self.speed, checkpoint, &tx).await?;
+            println!("Replayed {batches} event batches from {}", self.path.display());
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::storage::SpotEntryEvent;
+
+    /// Deterministic source for tests: every block has an entry of every pair, price of a pair walks randomly from
+    /// `initial_price` by up to 1% a block. Same seed gives the same observations.
+    pub struct SyntheticSource {
+        pub pair_ids: Vec<Felt>,
+        pub seed: u64,
+        pub blocks: u64,
+        /// Timestamp of the first block, batches are processed at timestamps of their blocks.
+        pub start: u64,
+        /// Seconds between blocks.
+        pub block_time: u64,
+        pub initial_price: u128,
+    }
+
+    impl SyntheticSource {
+        pub fn batches(&self) -> Vec<EventBatch> {
+            // xorshift64, seed should not be zero
+            let mut state = self.seed.max(1);
+            let mut next = move || {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
+            };
+
+            let mut prices = vec![self.initial_price; self.pair_ids.len()];
+            (0..self.blocks)
+                .map(|block_number| {
+                    let timestamp = self.start + block_number * self.block_time;
+                    let events = self
+                        .pair_ids
+                        .iter()
+                        .zip(prices.iter_mut())
+                        .map(|(pair_id, price)| {
+                            // Change within [-1%, 1%]
+                            let change = *price * (next() % 201) as u128 / 10_000;
+                            *price = *price - *price / 100 + change;
+
+                            SpotEntryEvent {
+                                timestamp,
+                                price: *price,
+                                pair_id: *pair_id,
+                                block_number,
+                                ..Default::default()
+                            }
+                        })
+                        .collect();
+
+                    let checkpoint = Checkpoint { block_number, block_hash: Felt::from(block_number) };
+                    EventBatch { events, checkpoint: Some(checkpoint), rollback: None, timestamp: Some(timestamp) }
+                })
+                .collect()
+        }
+    }
+
+    impl EventSource for SyntheticSource {
+        fn description(&self) -> String {
+            format!("synthetic prices with seed {}", self.seed)
+        }
+
+        fn publish<'a>(
+            &'a self,
+            _checkpoint: Option<Checkpoint>,
+            tx: UnboundedSender<EventBatch>,
+            _metrics: &'a Metrics,
+        ) -> SourceFuture<'a> {
+            Box::pin(async move {
+                for batch in self.batches() {
+                    tx.send(batch).map_err(|_| "Can't publish events to channel")?;
+                }
+
+                Ok(())
+            })
+        }
+    }
+
+    pub fn synthetic(pair_ids: Vec<Felt>, seed: u64) -> SyntheticSource {
+        SyntheticSource { pair_ids, seed, blocks: 100, start: 100_000, block_time: 30, initial_price: 100_000 }
+    }
+
+    #[test]
+    fn synthetic_prices_are_deterministic() {
+        let source = synthetic(vec![Felt::ONE, Felt::TWO], 42);
+        let batches = source.batches();
+
+        assert_eq!(batches, source.batches());
+        assert_ne!(batches, synthetic(vec![Felt::ONE, Felt::TWO], 43).batches());
+
+        assert_eq!(batches.len(), 100);
+        assert_eq!(batches[99].timestamp, Some(100_000 + 99 * 30));
+        assert_eq!(batches[99].checkpoint.map(|checkpoint| checkpoint.block_number), Some(99));
+        for event in batches.iter().flat_map(|batch| batch.events.iter()) {
+            assert!(event.price > 0 && [Felt::ONE, Felt::TWO].contains(&event.pair_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn synthetic_source_publishes_every_batch() {
+        let source = synthetic(vec![Felt::ONE], 7);
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        source.publish(None, tx, &Metrics::new()).await.unwrap();
+
+        let mut batches = Vec::new();
+        while let Some(batch) = rx.recv().await {
+            batches.push(batch);
+        }
+
+        assert_eq!(batches, source.batches());
+    }
+}
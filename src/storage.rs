@@ -1,17 +1,50 @@
-use num_bigint::BigUint;
-use secp256k1::{
-    Message, Secp256k1, SecretKey,
-    ecdsa::Signature,
-    hashes::{Hash, sha256},
+use crate::{
+    accumulator::PriceAccumulator,
+    aggregation::{Activity, AggregationConfiguration, InsufficientData, JumpReference, QualityGates, Rejections},
+    attestation::{ATTESTATION_VERSION, Anchor, Attestation},
+    signing::{AttestationSignature, Signer},
 };
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
 use starknet::core::types::Felt;
-use std::{collections::HashMap, fmt::Debug};
+use std::{collections::BTreeMap, fmt::Debug, time::Duration};
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct SpotEntryEvent {
-    timestamp: u64,
-    price: u128,
+    pub timestamp: u64,
+    pub price: u128,
     pub pair_id: Felt,
+    /// Price source and publisher that submitted it, both are short strings, e.g. `BINANCE` and `PRAGMA`.
+    #[serde(default)]
+    pub source: Felt,
+    #[serde(default)]
+    pub publisher: Felt,
+    /// Block event was emitted in, so events of orphaned blocks can be rolled back.
+    #[serde(default)]
+    pub block_number: u64,
+    /// Volume traded at the price, zero if publisher doesn't report it.
+    #[serde(default)]
+    pub volume: u128,
+}
+
+/// Last block all events were fetched for. Hash is kept to check on restart that the block wasn't orphaned meanwhile.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub block_number: u64,
+    pub block_hash: Felt,
+}
+
+/// Batch of events fetched from the chain. `checkpoint` is set once all events up to that block were sent, so it can
+/// be used to resume fetching from. `rollback` is set when chain was reorganised, events from that block onwards were
+/// orphaned and should be removed before events of the batch are added. Batch is processed at `timestamp` if it is
+/// set, at the current time otherwise.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventBatch {
+    pub events: Vec<SpotEntryEvent>,
+    pub checkpoint: Option<Checkpoint>,
+    pub rollback: Option<u64>,
+    pub timestamp: Option<u64>,
 }
 
 impl TryFrom<&[Felt]> for SpotEntryEvent {
@@ -20,13 +53,26 @@ impl TryFrom<&[Felt]> for SpotEntryEvent {
     fn try_from(value: &[Felt]) -> Result<Self, Self::Error> {
         let timestamp = value[0].try_into().map_err(|_| "Can't convert timestamp for event")?;
         let price = value[3].try_into().map_err(|_| "Can't convert price for event")?;
-        Ok(SpotEntryEvent { timestamp, price, pair_id: value[4] })
+        // Volume follows pair id, events that don't have it traded nothing.
+        let volume = match value.get(5) {
+            Some(volume) => (*volume).try_into().map_err(|_| "Can't convert volume for event")?,
+            None => 0,
+        };
+        Ok(SpotEntryEvent {
+            timestamp,
+            price,
+            pair_id: value[4],
+            source: value[1],
+            publisher: value[2],
+            block_number: 0,
+            volume,
+        })
     }
 }
 
 impl PartialOrd for SpotEntryEvent {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.timestamp.cmp(&other.timestamp))
+        Some(self.cmp(other))
     }
 }
 
@@ -36,103 +82,411 @@ impl Ord for SpotEntryEvent {
     }
 }
 
+/// TWAP attestation calculated over a window along with its signature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedTwap {
+    pub attestation: Attestation,
+    pub signature: AttestationSignature,
+}
+
+/// Newly signed twap of the pair and window along with entries rejected during its calculation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TwapUpdate {
+    pub pair_id: Felt,
+    pub window: Duration,
+    pub signed: SignedTwap,
+    pub rejections: Rejections,
+    /// Quality gate the latest calculation has failed, signed twap is stale then.
+    pub insufficient: Option<InsufficientData>,
+}
+
 pub struct SpotEntryStorage {
-    secp: Secp256k1<secp256k1::All>,
-    data: HashMap<u64, SpotEntryEvent>,
-    pub twap: Option<BigUint>,
-    pub signature: Option<Signature>,
+    chain_id: Felt,
+    pair_id: Felt,
+    aggregation: AggregationConfiguration,
+    /// Entries of every publisher and source submitted at the timestamp.
+    data: BTreeMap<u64, Vec<SpotEntryEvent>>,
+    /// Entries of every second reduced to a single price, with cumulative prices.
+    prices: PriceAccumulator,
+    /// The earliest second which entries changed since prices were updated.
+    stale_from: Option<u64>,
+    /// Latest signed twap per window.
+    pub twaps: BTreeMap<Duration, SignedTwap>,
+    /// Sequence of the latest attestation per window. It outlives signed twap that was dropped on restore, so
+    /// sequence keeps increasing.
+    pub sequences: BTreeMap<Duration, u64>,
+    /// Entries rejected as outliers during latest calculation per window.
+    pub rejections: BTreeMap<Duration, Rejections>,
+    /// Quality gate latest calculation has failed per window, signed twap of such window is stale.
+    pub insufficient: BTreeMap<Duration, InsufficientData>,
 }
 
 impl SpotEntryStorage {
-    pub fn new() -> SpotEntryStorage {
-        SpotEntryStorage { secp: Secp256k1::gen_new(), data: HashMap::with_capacity(7200), twap: None, signature: None }
+    pub fn new(
+        chain_id: Felt,
+        pair_id: Felt,
+        capacity: usize,
+        aggregation: AggregationConfiguration,
+    ) -> SpotEntryStorage {
+        SpotEntryStorage {
+            chain_id,
+            pair_id,
+            prices: PriceAccumulator::new(aggregation.interpolation, aggregation.quality.max_gap, capacity),
+            aggregation,
+            data: BTreeMap::new(),
+            stale_from: None,
+            twaps: BTreeMap::new(),
+            sequences: BTreeMap::new(),
+            rejections: BTreeMap::new(),
+            insufficient: BTreeMap::new(),
+        }
     }
 
+    /// Adds entry to the storage. Entries with same timestamp are kept side by side and aggregated with configured
+    /// reducer, only entry of the same publisher and source is replaced.
     pub fn append(&mut self, event: SpotEntryEvent) {
-        // Events can have same timestamp. Should be an aggregated value. Say mean.
-        self.data.insert(event.timestamp, event);
+        self.mark_stale(event.timestamp);
+        let entries = self.data.entry(event.timestamp).or_default();
+
+        match entries.iter_mut().find(|e| e.publisher == event.publisher && e.source == event.source) {
+            Some(entry) => *entry = event,
+            None => entries.push(event),
+        }
     }
 
     pub fn clean_older_than(&mut self, timestamp: u64) {
-        let keys: Vec<u64> = self.data.keys().filter(|k| **k <= timestamp).cloned().collect();
+        self.data = self.data.split_off(&timestamp.saturating_add(1));
+        self.prices.clean_older_than(timestamp);
+    }
+
+    /// Timestamp of the last entries before `window_start`, they give opening price of the window. Those entries
+    /// should be kept when older ones are cleaned.
+    pub fn opening_timestamp(&self, window_start: u64) -> Option<u64> {
+        self.data.range(..window_start).next_back().map(|(timestamp, _)| *timestamp)
+    }
+
+    /// Timestamp of the latest stored entry.
+    pub fn last_timestamp(&self) -> Option<u64> {
+        self.data.last_key_value().map(|(timestamp, _)| *timestamp)
+    }
+
+    /// Number of entries submitted from `start` to `end` inclusive, of every publisher and source.
+    pub fn samples(&self, start: u64, end: u64) -> usize {
+        self.data.range(start..=end).map(|(_, entries)| entries.len()).sum()
+    }
 
-        for key in keys {
-            self.data.remove(&key);
+    /// Removes events emitted in `block_number` or later ones. Used when those blocks were orphaned.
+    pub fn rollback(&mut self, block_number: u64) {
+        let orphaned = self.data.iter().find(|(_, entries)| entries.iter().any(|e| e.block_number >= block_number));
+        if let Some((timestamp, _)) = orphaned {
+            self.mark_stale(*timestamp);
         }
+
+        for entries in self.data.values_mut() {
+            entries.retain(|event| event.block_number < block_number);
+        }
+
+        self.data.retain(|_, entries| !entries.is_empty());
     }
 
-    pub fn calculate_and_sign_twap(&mut self, secret_key: SecretKey) {
-        let mut events: Vec<&SpotEntryEvent> = self.data.values().collect();
-        events.sort_by_key(|e| e.timestamp);
+    /// Name of the algorithm twap of `window` is calculated with.
+    pub fn algorithm(&self, window: Duration) -> String {
+        self.aggregation.algorithm(window)
+    }
 
-        let mut last_timestamp = 0_u64;
-        let mut numenator_aggregate = BigUint::from(0_u128);
-        let mut divisor_aggregate = 0_u64;
+    /// Calculates value of `window` before `now` with aggregator configured for the window and signs attestation of
+    /// it. Result is stored per window, previous value is kept if there is not enough data to calculate new one.
+    /// Window that fails quality gates is not signed either, previous value is kept and marked stale then.
+    pub fn calculate_and_sign_twap(&mut self, window: Duration, now: u64, signer: &Signer) {
+        let window_start = now.saturating_sub(window.as_secs());
+        self.update_prices();
+        let (twap, rejections) = self.aggregate(window, window_start, now);
+        self.rejections.insert(window, rejections);
+
+        if let Err(insufficient) = self.check_quality(window_start, now, None) {
+            self.insufficient.insert(window, insufficient);
+            return;
+        }
 
-        for event in events {
-            if last_timestamp == 0 {
-                last_timestamp = event.timestamp;
-                continue;
+        self.insufficient.remove(&window);
+
+        let Some(twap) = twap else {
+            return;
+        };
+
+        let sequence = self.sequences.get(&window).map(|sequence| sequence + 1).unwrap_or(1);
+        let attestation = Attestation { sequence, ..self.attestation(window, window_start, now, twap) };
+
+        let Ok(signature) = signer.sign(&attestation) else {
+            return;
+        };
+
+        self.sequences.insert(window, sequence);
+        self.twaps.insert(window, SignedTwap { attestation, signature });
+    }
+
+    /// Calculates value of `window` ending at `end` and signs attestation of it with historical `anchor`. For `Block`
+    /// anchor `end` should be timestamp of the block, only events of that block and earlier ones are used then. Prices
+    /// after `end` are never seen, so `Time` anchor gives the value signed live at that time unless entries within the
+    /// window arrived late. Historical attestations are not stored and have zero sequence. Entries rejected within the
+    /// window are returned along with signed attestation.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there is not enough data for the window, it fails quality gates or
+    /// signing failed.
+    pub fn sign_historical(
+        &self,
+        window: Duration,
+        anchor: Anchor,
+        end: u64,
+        now: u64,
+        signer: &Signer,
+    ) -> Result<(SignedTwap, Rejections), String> {
+        let window_start = end.saturating_sub(window.as_secs());
+        self.check_quality(window_start, end, anchor.block_number()).map_err(|e| format!("Insufficient data: {e}"))?;
+
+        let (twap, rejections) = match anchor {
+            Anchor::Block(block_number) => self.recalculate_until_block(window, window_start, end, block_number),
+            Anchor::Latest | Anchor::Time(_) => self.aggregate(window, window_start, end),
+        };
+
+        let twap = twap.ok_or("Not enough data for the window")?;
+        let attestation = Attestation { timestamp: now, anchor, ..self.attestation(window, window_start, end, twap) };
+        let signature = signer.sign(&attestation)?;
+
+        Ok((SignedTwap { attestation, signature }, rejections))
+    }
+
+    /// Checks window from `window_start` to `end` against quality gates. With `block_number` only events emitted in
+    /// that block or earlier ones are checked.
+    ///
+    /// Activity of the window is accumulated along with prices, so it takes logarithmic time of stored seconds. Only
+    /// if entries changed since prices were updated or some of them are filtered out by block, they are counted.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the first gate window has failed.
+    pub fn check_quality(
+        &self,
+        window_start: u64,
+        end: u64,
+        block_number: Option<u64>,
+    ) -> Result<(), InsufficientData> {
+        if self.aggregation.quality == QualityGates::default() {
+            return Ok(());
+        }
+
+        let activity = match block_number {
+            None if self.stale_from.is_none_or(|stale_from| stale_from > end) => {
+                self.prices.activity(window_start, end)
+            }
+            _ => {
+                let until_block =
+                    |entry: &&SpotEntryEvent| block_number.is_none_or(|block| entry.block_number <= block);
+
+                let opening = self
+                    .data
+                    .range(..window_start)
+                    .rev()
+                    .any(|(_, entries)| entries.iter().any(|entry| until_block(&entry)));
+                let seconds = self
+                    .data
+                    .range(window_start..=end.max(window_start))
+                    .map(|(timestamp, entries)| (*timestamp, entries.iter().filter(until_block)));
+
+                Activity::of(seconds, opening, window_start, end)
             }
+        };
+
+        self.aggregation.quality.check(&activity, window_start, end)
+    }
 
-            let timedelta = event.timestamp - last_timestamp;
-            last_timestamp = event.timestamp;
+    /// Attestation of the latest `twap` of `window`, it has zero sequence.
+    fn attestation(&self, window: Duration, window_start: u64, window_end: u64, twap: BigUint) -> Attestation {
+        Attestation {
+            version: ATTESTATION_VERSION,
+            chain_id: self.chain_id,
+            pair_id: self.pair_id,
+            algorithm: Felt::from_bytes_be_slice(self.aggregation.algorithm(window).as_bytes()),
+            window_start,
+            window_end,
+            timestamp: window_end,
+            sequence: 0,
+            anchor: Anchor::Latest,
+            twap,
+        }
+    }
 
-            numenator_aggregate += event.price * u128::from(timedelta);
-            divisor_aggregate += timedelta;
+    /// Value of window from `window_start` to `now` calculated with aggregator of the window, along with entries
+    /// rejected within it. It is taken from cumulative prices, so arithmetic twap and vwap take logarithmic time of
+    /// stored prices, other aggregators split the window into segments in time linear to number of its prices. Jump
+    /// filter compares every price with the previous accepted one, even if it is older than the window.
+    ///
+    /// Median absolute deviation depends on all entries of the window, so with MAD cutoff value is recalculated from
+    /// them, in time linear to number of entries of the window. So it is while entries changed since prices were
+    /// updated last time, see `update_prices`.
+    pub fn aggregate(&self, window: Duration, window_start: u64, now: u64) -> (Option<BigUint>, Rejections) {
+        if self.aggregation.outliers.mad_cutoff.is_some() || self.stale_from.is_some() {
+            return self.recalculate(window, window_start, now);
         }
 
-        if divisor_aggregate == 0 {
+        let rejections = self.prices.rejections(window_start, now);
+        let twap = self.aggregation.aggregator(window).aggregate_accumulated(&self.prices, window_start, now);
+
+        (twap, rejections)
+    }
+
+    /// Value of window from `window_start` to `now` recalculated from its entries, the last price before the window is
+    /// its opening price. Outliers are rejected before time weighting. Jump filter compares prices with the previous
+    /// accepted one, so entries before the window are reduced as well to get it.
+    ///
+    /// Prices before the window are reduced without MAD, the same way they are accumulated, so opening price is taken
+    /// from cumulative prices unless they are stale. Only entries of the window are reduced then.
+    pub fn recalculate(&self, window: Duration, window_start: u64, now: u64) -> (Option<BigUint>, Rejections) {
+        let (opening, reference) = if self.stale_from.is_none_or(|stale_from| stale_from >= window_start) {
+            self.prices.opening(window_start)
+        } else {
+            let history: Vec<(u64, &[SpotEntryEvent])> =
+                self.data.range(..window_start).map(|(timestamp, entries)| (*timestamp, entries.as_slice())).collect();
+            self.aggregation.opening(&history)
+        };
+
+        let entries: Vec<(u64, &[SpotEntryEvent])> = self
+            .data
+            .range(window_start..=now.max(window_start))
+            .map(|(timestamp, entries)| (*timestamp, entries.as_slice()))
+            .collect();
+
+        self.aggregate_entries(window, opening, reference, &entries, window_start, now)
+    }
+
+    /// Same as `recalculate` for window from `window_start` to `end`, but only events emitted in `block_number` or
+    /// earlier ones are used, so result is the one that could be calculated right after that block.
+    pub fn recalculate_until_block(
+        &self,
+        window: Duration,
+        window_start: u64,
+        end: u64,
+        block_number: u64,
+    ) -> (Option<BigUint>, Rejections) {
+        let until_block = |(timestamp, entries): (&u64, &Vec<SpotEntryEvent>)| {
+            let entries: Vec<SpotEntryEvent> =
+                entries.iter().filter(|e| e.block_number <= block_number).cloned().collect();
+            (!entries.is_empty()).then_some((*timestamp, entries))
+        };
+
+        let history: Vec<(u64, Vec<SpotEntryEvent>)> =
+            self.data.range(..window_start).filter_map(until_block).collect();
+        let seconds: Vec<(u64, Vec<SpotEntryEvent>)> =
+            self.data.range(window_start..=end.max(window_start)).filter_map(until_block).collect();
+
+        let (opening, reference) = self.aggregation.opening(&slices(&history));
+        self.aggregate_entries(window, opening, reference, &slices(&seconds), window_start, end)
+    }
+
+    /// Value of window from `window_start` to `end` given its opening price, reference jumps are checked against and
+    /// entries within it.
+    fn aggregate_entries(
+        &self,
+        window: Duration,
+        opening: Option<(u64, u128)>,
+        reference: JumpReference,
+        entries: &[(u64, &[SpotEntryEvent])],
+        window_start: u64,
+        end: u64,
+    ) -> (Option<BigUint>, Rejections) {
+        let (seconds, rejections) = self.aggregation.prices(entries, reference);
+
+        let prices: Vec<(u64, u128)> =
+            opening.into_iter().chain(seconds.iter().map(|(timestamp, price, _)| (*timestamp, *price))).collect();
+        let segments = self.aggregation.interpolation.segments(&prices, window_start, end);
+
+        (self.aggregation.aggregator(window).aggregate_seconds(&segments, &seconds), rejections)
+    }
+
+    /// Reduces entries of seconds that changed since the last update into prices. Only those seconds are reduced
+    /// again, so it should be called after entries changed, before windows are aggregated.
+    pub fn update_prices(&mut self) {
+        let Some(stale_from) = self.stale_from.take() else {
             return;
-        }
+        };
+
+        self.prices.truncate(stale_from);
+
+        let mut reference = self.prices.jump_reference();
+        for (timestamp, entries) in self.data.range(stale_from..) {
+            let mut rejections = Rejections::default();
+            let second = self.aggregation.reduce_second(entries.iter().collect(), &mut reference, &mut rejections);
 
-        let twap: BigUint = (numenator_aggregate << 64) / divisor_aggregate;
-        let twap_bytes = twap.to_bytes_be();
-        self.twap = Some(twap);
+            self.prices.push(*timestamp, entries, second, rejections);
+        }
+    }
 
-        let digest = sha256::Hash::hash(twap_bytes.as_slice());
-        let message = Message::from_digest(digest.to_byte_array());
-        self.signature = Some(self.secp.sign_ecdsa(&message, &secret_key));
+    fn mark_stale(&mut self, timestamp: u64) {
+        self.stale_from = Some(self.stale_from.map_or(timestamp, |stale_from| stale_from.min(timestamp)));
     }
 }
 
+/// Entries of every second borrowed as slices.
+fn slices(seconds: &[(u64, Vec<SpotEntryEvent>)]) -> Vec<(u64, &[SpotEntryEvent])> {
+    seconds.iter().map(|(timestamp, entries)| (*timestamp, entries.as_slice())).collect()
+}
+
 #[cfg(test)]
 mod test {
     use rand::prelude::*;
-    use std::time::{Duration, SystemTime};
+    use std::time::SystemTime;
 
     use super::*;
-    use secp256k1::rand::rngs::OsRng;
+    use crate::{
+        aggregation::{Interpolation, OutlierFilter, Reducer},
+        aggregators::parse_aggregator,
+        signing::SigningScheme,
+    };
+    use secp256k1::{SecretKey, rand::rngs::OsRng};
+    use starknet::{core::utils::parse_cairo_short_string, signers::SigningKey};
+    use std::collections::HashMap;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+    const DAY: Duration = Duration::from_secs(86400);
+
+    fn signer() -> Signer {
+        Signer::new(SigningScheme::Secp256k1, SecretKey::new(&mut OsRng), SigningKey::from_random())
+    }
+
+    #[test]
+    fn events_are_decoded_with_optional_volume() {
+        let data = [Felt::from(1_000), Felt::ONE, Felt::TWO, Felt::from(100), Felt::THREE, Felt::from(25)];
+
+        let event = SpotEntryEvent::try_from(data.as_slice()).unwrap();
+        assert_eq!((event.timestamp, event.price, event.pair_id, event.volume), (1_000, 100, Felt::THREE, 25));
+        assert_eq!(SpotEntryEvent::try_from(&data[..5]).unwrap().volume, 0);
+        assert!(SpotEntryEvent::try_from([&data[..5], &[Felt::MAX]].concat().as_slice()).is_err());
+    }
 
     #[test]
     fn storage_ields_initialization() {
-        let mut storage = SpotEntryStorage::new();
-        let (secret_key, _) = storage.secp.generate_keypair(&mut OsRng);
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let signer = signer();
 
-        assert_eq!(storage.signature, None);
-        assert_eq!(storage.twap, None);
+        assert_eq!(storage.twaps.len(), 0);
         assert_eq!(storage.data.len(), 0);
 
-        storage.calculate_and_sign_twap(secret_key);
+        storage.calculate_and_sign_twap(HOUR, 0, &signer);
 
-        assert_eq!(storage.signature, None);
-        assert_eq!(storage.twap, None);
+        assert_eq!(storage.twaps.len(), 0);
         assert_eq!(storage.data.len(), 0);
     }
 
     #[test]
     fn simple_event_addition() {
-        let mut storage = SpotEntryStorage::new();
-        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, pair_id: Felt::ZERO };
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, ..Default::default() };
 
         for i in 0..10000 {
-            let ts = SystemTime::now()
-                .checked_sub(Duration::from_secs(i))
-                .unwrap()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap();
-
-            let event = event_factory(ts.as_secs(), 100_u64.into());
+            let event = event_factory(now - i, 100_u64.into());
 
             storage.append(event);
         }
@@ -142,86 +496,302 @@ mod test {
 
     #[test]
     fn event_cleaning() {
-        let mut storage = SpotEntryStorage::new();
-        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, pair_id: Felt::ZERO };
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, ..Default::default() };
 
         for i in 0..10000 {
-            let ts = SystemTime::now()
-                .checked_sub(Duration::from_secs(i))
-                .unwrap()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap();
-
-            let event = event_factory(ts.as_secs(), 100_u64.into());
+            let event = event_factory(now - i, 100_u64.into());
 
             storage.append(event);
         }
 
         assert_eq!(storage.data.len(), 10000);
 
-        let hour_ago = SystemTime::now()
-            .checked_sub(Duration::from_secs(3600))
-            .unwrap()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap();
-
-        storage.clean_older_than(hour_ago.as_secs());
+        storage.clean_older_than(now - 3600);
 
         assert_eq!(storage.data.len(), 3600);
     }
 
     #[test]
-    fn events_on_same_ts_overwrite_each_other() {
-        let mut storage = SpotEntryStorage::new();
-        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, pair_id: Felt::ZERO };
-
+    fn events_on_same_ts_are_kept_per_publisher() {
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let event_factory = |timestamp, publisher: &str| SpotEntryEvent {
+            timestamp,
+            price: 100,
+            publisher: Felt::from_bytes_be_slice(publisher.as_bytes()),
+            ..Default::default()
+        };
+
+        // Same publisher overwrites own entry
         for _ in 0..3 {
             for i in 0..100 {
-                let ts = SystemTime::now()
-                    .checked_sub(Duration::from_secs(i))
-                    .unwrap()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap();
+                storage.append(event_factory(now - i, "PRAGMA"));
+            }
+        }
 
-                let event = event_factory(ts.as_secs(), 100_u64.into());
+        assert_eq!(storage.data.len(), 100);
+        assert!(storage.data.values().all(|entries| entries.len() == 1));
 
-                storage.append(event);
+        // Other publishers are kept side by side
+        for publisher in ["AVNU", "FOURLEAF"] {
+            for i in 0..100 {
+                storage.append(event_factory(now - i, publisher));
             }
         }
 
         assert_eq!(storage.data.len(), 100);
+        assert!(storage.data.values().all(|entries| entries.len() == 3));
+        assert_eq!(storage.samples(now - 9, now), 30);
+        assert_eq!(storage.last_timestamp(), Some(now));
+    }
+
+    #[test]
+    fn same_second_entries_are_reduced() {
+        let now = 100_000_u64;
+        let event_factory = |timestamp, price, publisher: &str| SpotEntryEvent {
+            timestamp,
+            price,
+            publisher: Felt::from_bytes_be_slice(publisher.as_bytes()),
+            ..Default::default()
+        };
+
+        let twap = |reducer| {
+            let mut storage = SpotEntryStorage::new(
+                Felt::ZERO,
+                Felt::ZERO,
+                7200,
+                AggregationConfiguration { reducer, ..Default::default() },
+            );
+            for (price, publisher) in [(100, "A"), (100, "B"), (400, "C")] {
+                storage.append(event_factory(now - 10, price, publisher));
+            }
+
+            storage.calculate_and_sign_twap(HOUR, now, &signer());
+            storage.twaps[&HOUR].attestation.twap.clone() >> 64
+        };
+
+        let weights = HashMap::from([(Felt::from_bytes_be_slice("C".as_bytes()), 2)]);
+
+        assert_eq!(twap(Reducer::Median), BigUint::from(100_u64));
+        assert_eq!(twap(Reducer::Mean), BigUint::from(200_u64));
+        assert_eq!(twap(Reducer::PublisherWeighted(weights)), BigUint::from(250_u64));
+    }
+
+    #[test]
+    fn accumulated_prices_match_recalculation() {
+        let mut rng = rand::rng();
+
+        let jumps = OutlierFilter { max_jump: Some(0.2), ..Default::default() };
+        for interpolation in [Interpolation::Left, Interpolation::Right, Interpolation::Linear] {
+            for outliers in [OutlierFilter::default(), jumps.clone()] {
+                let aggregation = AggregationConfiguration { interpolation, outliers, ..Default::default() };
+                accumulate_and_recalculate(&mut rng, aggregation);
+            }
+        }
+    }
+
+    /// Appends random entries, some of them late and some rolled back, and checks that accumulated windows are equal
+    /// to recalculated ones after every block, both the latest ones and those ending earlier.
+    fn accumulate_and_recalculate(rng: &mut impl Rng, aggregation: AggregationConfiguration) {
+        let publishers = ["A", "B", "C"].map(|publisher| Felt::from_bytes_be_slice(publisher.as_bytes()));
+
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, aggregation);
+        let mut now = 100_000_u64;
+
+        for block_number in 0..100 {
+            now += rng.random_range(1..60);
+
+            // Some entries are late and land before already accumulated ones
+            for _ in 0..rng.random_range(1..5) {
+                storage.append(SpotEntryEvent {
+                    timestamp: now - rng.random_range(0..120),
+                    price: rng.random_range(1_000..2_000),
+                    publisher: publishers[rng.random_range(0..publishers.len())],
+                    block_number,
+                    ..Default::default()
+                });
+            }
+
+            if block_number % 10 == 9 {
+                storage.rollback(block_number - 2);
+            }
+            storage.update_prices();
+
+            // Windows ending before the latest prices don't see them
+            for end in [now, now - rng.random_range(1..300)] {
+                for window in [Duration::from_secs(60), Duration::from_secs(600), HOUR] {
+                    let window_start = end - window.as_secs();
+                    let expected = storage.recalculate(window, window_start, end);
+                    assert_eq!(storage.aggregate(window, window_start, end), expected, "[{window_start}, {end}]");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn jump_filter_follows_lasting_move() {
+        let outliers = OutlierFilter { max_jump: Some(0.1), ..Default::default() };
+        let aggregation = AggregationConfiguration { outliers, ..Default::default() };
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, aggregation);
+        let now = 100_000_u64;
+
+        // Price doubles halfway and stays there
+        for timestamp in (now - 2_000..=now).step_by(10) {
+            let price = if timestamp < now - 1_000 { 100 } else { 200 };
+            storage.append(SpotEntryEvent { timestamp, price, ..Default::default() });
+        }
+
+        storage.update_prices();
+        for end in (now - 1_200..=now).step_by(5) {
+            for window in [Duration::from_secs(15), Duration::from_secs(60), Duration::from_secs(600), HOUR] {
+                let window_start = end - window.as_secs();
+                let expected = storage.recalculate(window, window_start, end);
+                assert_eq!(storage.aggregate(window, window_start, end), expected, "{window:?} till {end}");
+            }
+        }
+
+        // Two seconds are rejected, the third one is the new reference
+        let (twap, rejections) = storage.aggregate(HOUR, now - 3_600, now);
+        assert_eq!(rejections.jump, 2);
+        assert_eq!(twap.unwrap() >> 64, BigUint::from(149_u64));
+        assert_eq!(storage.aggregate(HOUR, now - 600, now).0, Some(BigUint::from(200_u64) << 64));
+    }
+
+    #[test]
+    fn window_aggregator_is_used_and_signed() {
+        let now = 100_000_u64;
+        let aggregators = BTreeMap::from([(DAY, parse_aggregator("tw-median").unwrap())]);
+        let mut storage = SpotEntryStorage::new(
+            Felt::ZERO,
+            Felt::ZERO,
+            7200,
+            AggregationConfiguration { aggregators, ..Default::default() },
+        );
+
+        // 100 holds for 3000 seconds of the hour and 700 for the last 600
+        storage.append(SpotEntryEvent { timestamp: now - 3600, price: 100, ..Default::default() });
+        storage.append(SpotEntryEvent { timestamp: now - 600, price: 700, ..Default::default() });
+
+        for window in [HOUR, DAY] {
+            storage.calculate_and_sign_twap(window, now, &signer());
+        }
+
+        let algorithm = |window| parse_cairo_short_string(&storage.twaps[&window].attestation.algorithm).unwrap();
+
+        assert_eq!(storage.twaps[&HOUR].attestation.twap.clone() >> 64, BigUint::from(200_u64));
+        assert_eq!(algorithm(HOUR), "twap/left");
+        assert_eq!(storage.twaps[&DAY].attestation.twap.clone() >> 64, BigUint::from(100_u64));
+        assert_eq!(algorithm(DAY), "tw-median/left");
+    }
+
+    #[test]
+    fn vwap_weights_accepted_entries_by_volume() {
+        let now = 100_000_u64;
+        let aggregators = BTreeMap::from([(HOUR, parse_aggregator("vwap").unwrap())]);
+        let outliers = OutlierFilter { max_jump: Some(0.5), ..Default::default() };
+        let mut storage = SpotEntryStorage::new(
+            Felt::ZERO,
+            Felt::ZERO,
+            7200,
+            AggregationConfiguration { aggregators, outliers, ..Default::default() },
+        );
+
+        // Volume of the jump to 1000 is not counted, as it is rejected
+        for (offset, price, volume) in [(3000, 100, 1), (2000, 1_000, 50), (1000, 140, 3)] {
+            storage.append(SpotEntryEvent { timestamp: now - offset, price, volume, ..Default::default() });
+        }
+        storage.update_prices();
+
+        // (100 * 1 + 140 * 3) / 4
+        let expected = Some(BigUint::from(130_u8) << 64);
+        assert_eq!(storage.aggregate(HOUR, now - 3600, now).0, expected);
+        assert_eq!(storage.recalculate(HOUR, now - 3600, now).0, expected);
+        assert_eq!(storage.aggregate(HOUR, now - 900, now).0, None);
+    }
+
+    #[test]
+    fn historical_twaps_are_signed_with_anchor() {
+        let now = 100_000_u64;
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let signer = signer();
+
+        for (block_number, (offset, price)) in [(3600, 100), (1800, 300), (600, 700)].into_iter().enumerate() {
+            let block_number = block_number as u64;
+            storage.append(SpotEntryEvent { timestamp: now - offset, price, block_number, ..Default::default() });
+        }
+
+        // Window ending 600 seconds ago: 100 holds for 1800 seconds and 300 for 1200
+        let anchor = Anchor::Time(now - 600);
+        let (signed, _) = storage.sign_historical(Duration::from_secs(3000), anchor, now - 600, now, &signer).unwrap();
+
+        assert_eq!(signed.attestation.twap.clone() >> 64, BigUint::from(180_u64));
+        assert_eq!((signed.attestation.window_start, signed.attestation.window_end), (now - 3600, now - 600));
+        assert_eq!((signed.attestation.timestamp, signed.attestation.sequence), (now, 0));
+        assert_eq!(signed.attestation.anchor, anchor);
+        assert!(signer.verify(&signed.attestation, &signed.signature));
+
+        // Entries of later blocks are not seen as of block 1
+        let (signed, _) = storage.sign_historical(HOUR, Anchor::Block(1), now, now, &signer).unwrap();
+
+        assert_eq!(signed.attestation.twap.clone() >> 64, BigUint::from(200_u64));
+        assert_eq!(signed.attestation.anchor, Anchor::Block(1));
+
+        // Historical attestations are not stored
+        assert!(storage.twaps.is_empty());
+        assert!(storage.sign_historical(HOUR, Anchor::Time(now - 7200), now - 7200, now, &signer).is_err());
+    }
+
+    #[test]
+    fn historical_twaps_match_live_ones() {
+        let end = 100_000_u64;
+        let signer = signer();
+
+        for interpolation in [Interpolation::Left, Interpolation::Right, Interpolation::Linear] {
+            let aggregation = AggregationConfiguration { interpolation, ..Default::default() };
+            let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, aggregation);
+
+            for (timestamp, price) in [(end - 3600, 100), (end - 1800, 300)] {
+                storage.append(SpotEntryEvent { timestamp, price, ..Default::default() });
+            }
+            storage.calculate_and_sign_twap(HOUR, end, &signer);
+            let live = storage.twaps[&HOUR].attestation.twap.clone();
+
+            // Price after the window doesn't change it
+            storage.append(SpotEntryEvent { timestamp: end + 600, price: 3_400, ..Default::default() });
+            storage.update_prices();
+
+            let (signed, _) = storage.sign_historical(HOUR, Anchor::Time(end), end, end + 1200, &signer).unwrap();
+            assert_eq!(signed.attestation.twap, live, "{interpolation}");
+        }
     }
 
     #[test]
     fn test_naive_twap_calculation() {
-        let mut storage = SpotEntryStorage::new();
-        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, pair_id: Felt::ZERO };
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, ..Default::default() };
 
         for i in 0..100 {
-            let ts = SystemTime::now()
-                .checked_sub(Duration::from_secs(i))
-                .unwrap()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap();
-
-            let event = event_factory(ts.as_secs(), 100_u128);
+            let event = event_factory(now - i, 100_u128);
 
             storage.append(event);
         }
 
         assert_eq!(storage.data.len(), 100);
 
-        let (secret_key, _) = storage.secp.generate_keypair(&mut OsRng);
-        storage.calculate_and_sign_twap(secret_key);
+        let signer = signer();
+        storage.calculate_and_sign_twap(HOUR, now, &signer);
 
-        assert!(storage.twap.is_some());
-        assert_eq!(storage.twap.unwrap() >> 64, BigUint::from(100_u64));
+        assert!(storage.twaps.contains_key(&HOUR));
+        assert_eq!(storage.twaps[&HOUR].attestation.twap.clone() >> 64, BigUint::from(100_u64));
     }
 
     #[test]
     fn test_complex_twap_calculation() {
-        let mut storage = SpotEntryStorage::new();
-        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, pair_id: Felt::ZERO };
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, ..Default::default() };
 
         let mut ts = SystemTime::now()
             .checked_sub(Duration::from_secs(3600))
@@ -236,7 +806,9 @@ mod test {
 
         let mut numenator_aggregate = 0_u128;
         let mut divisor_aggregate = 0_u128;
+        let mut previous_price = 100_u128;
 
+        // Every price holds until the next one
         for _ in 0..99 {
             let timedelta = rng.random::<u8>() / 2 + 10;
             ts = ts.checked_add(Duration::from_secs(timedelta.into())).unwrap();
@@ -245,18 +817,171 @@ mod test {
             let event = event_factory(ts.as_secs(), price.into());
 
             storage.append(event);
-            numenator_aggregate += u128::from(timedelta) * u128::from(price);
+            numenator_aggregate += u128::from(timedelta) * previous_price;
             divisor_aggregate += u128::from(timedelta);
+            previous_price = price.into();
         }
 
         let twap = numenator_aggregate / divisor_aggregate;
 
         assert_eq!(storage.data.len(), 100);
 
-        let (secret_key, _) = storage.secp.generate_keypair(&mut OsRng);
-        storage.calculate_and_sign_twap(secret_key);
+        let signer = signer();
+        storage.calculate_and_sign_twap(DAY, ts.as_secs(), &signer);
+
+        assert!(storage.twaps.contains_key(&DAY));
+        assert_eq!(storage.twaps[&DAY].attestation.twap.clone() >> 64, BigUint::from(twap));
+    }
+
+    #[test]
+    fn windows_are_calculated_separately() {
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, ..Default::default() };
+        let now = 100_000_u64;
+        let five_minutes = Duration::from_secs(300);
+
+        // Price is 100 during the hour and jumps to 200 in last five minutes
+        for i in (0..3600).step_by(10) {
+            let price = if i <= 300 { 200_u128 } else { 100_u128 };
+            storage.append(event_factory(now - i, price));
+        }
+
+        let signer = signer();
+        storage.calculate_and_sign_twap(five_minutes, now, &signer);
+        storage.calculate_and_sign_twap(HOUR, now, &signer);
+
+        assert_eq!(storage.twaps.len(), 2);
+        assert_eq!(storage.twaps[&five_minutes].attestation.twap.clone() >> 64, BigUint::from(200_u64));
+        assert!(storage.twaps[&HOUR].attestation.twap.clone() >> 64 < BigUint::from(200_u64));
+        assert_ne!(storage.twaps[&five_minutes].signature, storage.twaps[&HOUR].signature);
+    }
+
+    #[test]
+    fn attestation_sequence_increases() {
+        let mut storage = SpotEntryStorage::new(Felt::ONE, Felt::TWO, 7200, AggregationConfiguration::default());
+        let event_factory =
+            |timestamp, price| SpotEntryEvent { timestamp, price, pair_id: Felt::TWO, ..Default::default() };
+        let now = 100_000_u64;
+
+        storage.append(event_factory(now - 20, 100));
+        storage.append(event_factory(now - 10, 100));
+
+        let signer = signer();
+        storage.calculate_and_sign_twap(HOUR, now, &signer);
+        storage.calculate_and_sign_twap(HOUR, now + 1, &signer);
+
+        let signed = &storage.twaps[&HOUR];
+        assert_eq!(signed.attestation.sequence, 2);
+        assert_eq!(signed.attestation.chain_id, Felt::ONE);
+        assert_eq!(signed.attestation.pair_id, Felt::TWO);
+        assert_eq!(signed.attestation.window_start, now + 1 - 3600);
+        assert_eq!(signed.attestation.window_end, now + 1);
+
+        assert!(signer.verify(&signed.attestation, &signed.signature));
+    }
+
+    #[test]
+    fn orphaned_events_are_rolled_back() {
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let now = 100_000_u64;
+
+        // One event per block, price of orphaned blocks is way off
+        for block_number in 0..10 {
+            let price = if block_number < 5 { 100_u128 } else { 1_000_u128 };
+            storage.append(SpotEntryEvent {
+                timestamp: now - 100 + block_number * 10,
+                price,
+                block_number,
+                ..Default::default()
+            });
+        }
+
+        let signer = signer();
+        storage.calculate_and_sign_twap(HOUR, now, &signer);
+        assert!(storage.twaps[&HOUR].attestation.twap.clone() >> 64 > BigUint::from(100_u64));
+
+        storage.rollback(5);
+        storage.calculate_and_sign_twap(HOUR, now, &signer);
+
+        assert_eq!(storage.data.len(), 5);
+        assert_eq!(storage.twaps[&HOUR].attestation.twap.clone() >> 64, BigUint::from(100_u64));
+        assert_eq!(storage.twaps[&HOUR].attestation.sequence, 2);
+    }
+
+    #[test]
+    fn outliers_are_rejected_and_counted() {
+        let outliers = OutlierFilter { max_jump: Some(0.1), ..Default::default() };
+        let aggregation = AggregationConfiguration { outliers, ..Default::default() };
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, aggregation);
+        let now = 100_000_u64;
+
+        for i in 0..10 {
+            storage.append(SpotEntryEvent { timestamp: now - 100 + i * 10, price: 100, ..Default::default() });
+        }
+
+        // Single bad submission in the middle of the window
+        storage.append(SpotEntryEvent {
+            timestamp: now - 50,
+            price: 100_000,
+            publisher: Felt::from_bytes_be_slice("BAD".as_bytes()),
+            ..Default::default()
+        });
 
-        assert!(storage.twap.is_some());
-        assert_eq!(storage.twap.unwrap() >> 64, BigUint::from(twap));
+        storage.calculate_and_sign_twap(HOUR, now, &signer());
+
+        assert_eq!(storage.twaps[&HOUR].attestation.twap.clone() >> 64, BigUint::from(100_u64));
+        assert_eq!(storage.rejections[&HOUR].jump, 1);
+        assert_eq!(storage.rejections[&HOUR].publishers[&Felt::from_bytes_be_slice("BAD".as_bytes())], 1);
+    }
+
+    #[test]
+    fn window_is_anchored_to_opening_price() {
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, AggregationConfiguration::default());
+        let event_factory = |timestamp, price| SpotEntryEvent { timestamp, price, ..Default::default() };
+        let now = 100_000_u64;
+
+        // Price set two hours ago holds during first half of the window, the last one holds till its end
+        storage.append(event_factory(now - 7200, 50));
+        storage.append(event_factory(now - 1800, 150));
+
+        storage.calculate_and_sign_twap(HOUR, now, &signer());
+
+        assert_eq!(storage.twaps[&HOUR].attestation.twap.clone() >> 64, BigUint::from(100_u64));
+        assert_eq!(storage.opening_timestamp(now - 3600), Some(now - 7200));
+    }
+
+    #[test]
+    fn window_failing_quality_gates_keeps_stale_twap() {
+        let quality = QualityGates { min_samples: Some(2), min_coverage: Some(0.5), ..Default::default() };
+        let aggregation = AggregationConfiguration { quality, ..Default::default() };
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, aggregation);
+        let event_factory =
+            |timestamp, price, block_number| SpotEntryEvent { timestamp, price, block_number, ..Default::default() };
+        let now = 100_000_u64;
+
+        storage.append(event_factory(now - 3000, 100, 1));
+        storage.append(event_factory(now - 2000, 200, 2));
+        storage.calculate_and_sign_twap(HOUR, now, &signer());
+        assert_eq!(storage.twaps[&HOUR].attestation.sequence, 1);
+        assert!(storage.insufficient.is_empty());
+
+        // Both entries have left the window, only the opening price is there
+        storage.calculate_and_sign_twap(HOUR, now + 3000, &signer());
+        assert_eq!(storage.twaps[&HOUR].attestation.sequence, 1);
+        assert_eq!(storage.insufficient[&HOUR], InsufficientData::Samples { samples: 0, min: 2 });
+
+        // Two entries in the last seconds of the window without opening price cover too little of it
+        let mut storage = SpotEntryStorage::new(Felt::ZERO, Felt::ZERO, 7200, storage.aggregation.clone());
+        storage.append(event_factory(now - 30, 100, 1));
+        storage.append(event_factory(now - 10, 200, 2));
+        storage.calculate_and_sign_twap(HOUR, now, &signer());
+        assert!(storage.twaps.is_empty());
+        assert!(matches!(storage.insufficient[&HOUR], InsufficientData::Coverage { .. }));
+
+        // Historical windows are checked as of the block
+        storage.append(event_factory(now - 3500, 50, 3));
+        assert!(storage.sign_historical(HOUR, Anchor::Block(3), now, now, &signer()).is_ok());
+        let error = storage.sign_historical(HOUR, Anchor::Block(2), now, now, &signer()).unwrap_err();
+        assert!(error.starts_with("Insufficient data"));
     }
 }
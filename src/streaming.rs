@@ -0,0 +1,391 @@
+use crate::{
+    Data, RejectedInfo, configuration::ApplicationConfiguration, data, error_response, settings::parse_duration,
+    storage::TwapUpdate,
+};
+use axum::{
+    extract::{
+        Query, State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    http::StatusCode,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, Sse},
+    },
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use starknet::core::types::Felt;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::broadcast::{Receiver, error::RecvError},
+    time::{Instant, Interval, interval_at},
+};
+
+#[derive(Deserialize)]
+pub struct StreamParams {
+    /// Comma separated pair names, every tracked pair is streamed if omitted.
+    pairs: Option<String>,
+    /// Comma separated windows, every window of streamed pairs is streamed if omitted.
+    windows: Option<String>,
+}
+
+/// Message pushed to streaming clients.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StreamMessage {
+    /// Newly signed twap, the same as `/data` returns.
+    Twap { data: Box<Data> },
+    /// Sent every heartbeat interval, so clients can tell idle stream from a dead one.
+    Heartbeat { timestamp: u64 },
+}
+
+/// Pairs and windows client subscribed to, in order of configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Subscription {
+    windows: Vec<(Felt, Duration)>,
+}
+
+impl Subscription {
+    /// Builds subscription out of request parameters.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any of requested pairs is not tracked, or if there are no tracked
+    /// windows left among requested ones.
+    fn new(state: &ApplicationConfiguration, params: &StreamParams) -> Result<Subscription, String> {
+        let pairs = match params.pairs.as_deref() {
+            Some(names) => split(names)
+                .map(|name| state.find_pair(name).ok_or(format!("Unknown pair: {name}")))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => state.pairs.iter().collect(),
+        };
+
+        let windows = match params.windows.as_deref() {
+            Some(windows) => Some(
+                split(windows)
+                    .map(|window| parse_duration(window).map(Duration::from_secs))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+
+        let windows: Vec<_> = pairs
+            .iter()
+            .flat_map(|pair| pair.windows.iter().map(|window| (pair.id, *window)))
+            .filter(|(_, window)| windows.as_ref().is_none_or(|windows| windows.contains(window)))
+            .collect();
+
+        if windows.is_empty() {
+            return Err("Unknown window".to_string());
+        }
+
+        Ok(Subscription { windows })
+    }
+
+    fn contains(&self, pair_id: Felt, window: Duration) -> bool {
+        self.windows.contains(&(pair_id, window))
+    }
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+/// Yields signed twaps of subscribed pairs and windows as they are signed, with heartbeats in between. Latest ones are
+/// yielded first, so clients don't wait a full cycle for data. Client that lagged behind gets latest values instead
+/// of missed ones, and values older than already yielded ones are skipped. Value is yielded again once it goes stale.
+struct Subscriber {
+    state: Arc<ApplicationConfiguration>,
+    subscription: Subscription,
+    updates: Receiver<TwapUpdate>,
+    pending: VecDeque<TwapUpdate>,
+    /// Sequence of the last twap yielded per pair and window, and whether it was stale.
+    sequences: HashMap<(Felt, Duration), (u64, bool)>,
+    heartbeats: Interval,
+}
+
+impl Subscriber {
+    fn new(state: Arc<ApplicationConfiguration>, subscription: Subscription) -> Subscriber {
+        // Subscribing before latest values are taken, so nothing is signed in between unnoticed.
+        let updates = state.updates.subscribe();
+        let heartbeats = interval_at(Instant::now() + state.heartbeat, state.heartbeat);
+        let mut subscriber = Subscriber {
+            state,
+            subscription,
+            updates,
+            pending: VecDeque::new(),
+            sequences: HashMap::new(),
+            heartbeats,
+        };
+
+        subscriber.replay();
+        subscriber
+    }
+
+    /// Queues latest signed twaps of every subscribed pair and window.
+    ///
+    /// # Panics
+    ///
+    /// Panics if can't acqure storage read lock.
+    fn replay(&mut self) {
+        for (pair_id, window) in self.subscription.windows.iter() {
+            let storage = self.state.storage[pair_id].read().unwrap();
+
+            if let Some(signed) = storage.twaps.get(window) {
+                self.pending.push_back(TwapUpdate {
+                    pair_id: *pair_id,
+                    window: *window,
+                    signed: signed.clone(),
+                    rejections: storage.rejections.get(window).cloned().unwrap_or_default(),
+                    insufficient: storage.insufficient.get(window).cloned(),
+                });
+            }
+        }
+    }
+
+    /// Next message for the client, `None` once updates are not published anymore. Cancel safe: update received
+    /// before cancellation stays queued.
+    async fn next(&mut self) -> Option<StreamMessage> {
+        loop {
+            while let Some(update) = self.pending.pop_front() {
+                let (key, sequence) = ((update.pair_id, update.window), update.signed.attestation.sequence);
+                let stale = update.insufficient.is_some();
+                if self.sequences.get(&key).is_some_and(|last| last.0 > sequence || *last == (sequence, stale)) {
+                    continue;
+                }
+
+                self.sequences.insert(key, (sequence, stale));
+
+                let Some(pair) = self.state.pairs.iter().find(|pair| pair.id == update.pair_id) else {
+                    continue;
+                };
+
+                let rejected = RejectedInfo::from(&update.rejections);
+                let insufficient = update.insufficient.as_ref();
+                let data = data(&self.state, pair, update.window, update.signed, rejected, insufficient);
+                return Some(StreamMessage::Twap { data: Box::new(data) });
+            }
+
+            tokio::select! {
+                received = self.updates.recv() => match received {
+                    Ok(update) if self.subscription.contains(update.pair_id, update.window) => {
+                        self.pending.push_back(update);
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => self.replay(),
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = self.heartbeats.tick() => {
+                    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                    return Some(StreamMessage::Heartbeat { timestamp: now.as_secs() });
+                }
+            }
+        }
+    }
+}
+
+/// Streams signed twaps as server-sent events: `twap` events carry the same data `/data` returns and `heartbeat`
+/// events carry current timestamp.
+pub async fn sse_handler(
+    State(state): State<Arc<ApplicationConfiguration>>,
+    Query(params): Query<StreamParams>,
+) -> Response {
+    let subscription = match Subscription::new(&state, &params) {
+        Ok(subscription) => subscription,
+        Err(message) => return error_response(StatusCode::NOT_FOUND, &message).into_response(),
+    };
+
+    let events = stream::unfold(Subscriber::new(state, subscription), |mut subscriber| async move {
+        let event = match subscriber.next().await? {
+            StreamMessage::Twap { data } => Event::default().event("twap").json_data(data),
+            StreamMessage::Heartbeat { timestamp } => {
+                Ok(Event::default().event("heartbeat").data(timestamp.to_string()))
+            }
+        };
+
+        Some((event, subscriber))
+    });
+
+    Sse::new(events).into_response()
+}
+
+/// Streams signed twaps over WebSocket as JSON text messages tagged with `event` field. Messages from the client are
+/// ignored, subscription is set with query parameters on connect.
+pub async fn websocket_handler(
+    upgrade: WebSocketUpgrade,
+    State(state): State<Arc<ApplicationConfiguration>>,
+    Query(params): Query<StreamParams>,
+) -> Response {
+    let subscription = match Subscription::new(&state, &params) {
+        Ok(subscription) => subscription,
+        Err(message) => return error_response(StatusCode::NOT_FOUND, &message).into_response(),
+    };
+
+    let subscriber = Subscriber::new(state, subscription);
+    upgrade.on_upgrade(|socket| stream_to_socket(socket, subscriber))
+}
+
+async fn stream_to_socket(mut socket: WebSocket, mut subscriber: Subscriber) {
+    loop {
+        let message = tokio::select! {
+            message = subscriber.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        let Ok(text) = serde_json::to_string(&message) else {
+            break;
+        };
+
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{aggregation::InsufficientData, settings::Settings};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+    const DAY: Duration = Duration::from_secs(86400);
+
+    fn state() -> Arc<ApplicationConfiguration> {
+        let settings = Settings::from_toml(
+            r#"
+            [server]
+            heartbeat = "1h"
+
+            [[pairs]]
+            name = "BTC/USD"
+            windows = ["1h", "24h"]
+
+            [[pairs]]
+            name = "ETH/USD"
+            windows = ["1h"]
+            "#,
+        )
+        .unwrap();
+
+        Arc::new(ApplicationConfiguration::new(settings).unwrap())
+    }
+
+    fn params(pairs: Option<&str>, windows: Option<&str>) -> StreamParams {
+        StreamParams { pairs: pairs.map(str::to_string), windows: windows.map(str::to_string) }
+    }
+
+    /// Signs twap of the window as processor does and publishes it.
+    fn sign(state: &ApplicationConfiguration, pair_id: Felt, window: Duration, now: u64) {
+        let mut storage = state.storage[&pair_id].write().unwrap();
+        storage.append(crate::storage::SpotEntryEvent {
+            timestamp: now - 10,
+            price: 100,
+            pair_id,
+            ..Default::default()
+        });
+        storage.calculate_and_sign_twap(window, now, &state.signer);
+
+        let signed = storage.twaps[&window].clone();
+        let update = TwapUpdate { pair_id, window, signed, rejections: Default::default(), insufficient: None };
+        let _ = state.updates.send(update);
+    }
+
+    fn sequence(message: Option<StreamMessage>) -> (String, u64, u64) {
+        match message {
+            Some(StreamMessage::Twap { data }) => (data.pair, data.window, data.sequence),
+            _ => panic!("Twap is expected"),
+        }
+    }
+
+    #[test]
+    fn subscription_is_built_from_params() {
+        let state = state();
+        let (btc, eth) = (state.pairs[0].id, state.pairs[1].id);
+
+        let subscription = Subscription::new(&state, &params(None, None)).unwrap();
+        assert_eq!(subscription.windows, vec![(btc, HOUR), (btc, DAY), (eth, HOUR)]);
+
+        let subscription = Subscription::new(&state, &params(Some("eth/usd, BTC/USD"), Some("24h"))).unwrap();
+        assert_eq!(subscription.windows, vec![(btc, DAY)]);
+
+        assert!(Subscription::new(&state, &params(Some("DOGE/USD"), None)).is_err());
+        assert!(Subscription::new(&state, &params(Some("ETH/USD"), Some("24h"))).is_err());
+        assert!(Subscription::new(&state, &params(None, Some("soon"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn latest_twaps_are_replayed_and_new_ones_are_streamed() {
+        let state = state();
+        let (btc, eth) = (state.pairs[0].id, state.pairs[1].id);
+        let now = 100_000;
+
+        sign(&state, btc, HOUR, now);
+        sign(&state, btc, DAY, now);
+
+        let subscription = Subscription::new(&state, &params(None, Some("1h"))).unwrap();
+        let mut subscriber = Subscriber::new(state.clone(), subscription);
+
+        // Latest value of subscribed window is replayed on connect
+        assert_eq!(sequence(subscriber.next().await), ("BTC/USD".to_string(), 3600, 1));
+
+        sign(&state, btc, DAY, now + 1);
+        sign(&state, eth, HOUR, now + 1);
+        sign(&state, btc, HOUR, now + 1);
+
+        assert_eq!(sequence(subscriber.next().await), ("ETH/USD".to_string(), 3600, 1));
+        assert_eq!(sequence(subscriber.next().await), ("BTC/USD".to_string(), 3600, 2));
+        assert!(subscriber.pending.is_empty() && subscriber.updates.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_are_sent_while_idle() {
+        let state = state();
+        let subscription = Subscription::new(&state, &params(None, None)).unwrap();
+        let mut subscriber = Subscriber::new(state, subscription);
+
+        assert!(matches!(subscriber.next().await, Some(StreamMessage::Heartbeat { .. })));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn twap_is_streamed_again_once_stale() {
+        let state = state();
+        let btc = state.pairs[0].id;
+        let now = 100_000;
+
+        sign(&state, btc, HOUR, now);
+
+        let subscription = Subscription::new(&state, &params(Some("BTC/USD"), Some("1h"))).unwrap();
+        let mut subscriber = Subscriber::new(state.clone(), subscription);
+        assert_eq!(sequence(subscriber.next().await), ("BTC/USD".to_string(), 3600, 1));
+
+        // Processor publishes the same twap once window has too few samples, and keeps it stale
+        let signed = state.storage[&btc].read().unwrap().twaps[&HOUR].clone();
+        let insufficient = Some(InsufficientData::Samples { samples: 0, min: 2 });
+        for _ in 0..2 {
+            let (signed, insufficient) = (signed.clone(), insufficient.clone());
+            let update =
+                TwapUpdate { pair_id: btc, window: HOUR, signed, rejections: Default::default(), insufficient };
+            let _ = state.updates.send(update);
+        }
+
+        match subscriber.next().await {
+            Some(StreamMessage::Twap { data }) => assert!(data.stale && data.sequence == 1),
+            _ => panic!("Twap is expected"),
+        }
+
+        // Nothing but heartbeat is left
+        assert!(tokio::time::timeout(Duration::from_secs(1), subscriber.next()).await.is_err());
+        assert!(subscriber.pending.is_empty() && subscriber.updates.is_empty());
+    }
+}
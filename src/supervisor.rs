@@ -0,0 +1,231 @@
+use crate::{configuration::ServiceStatus, metrics::Metrics};
+use secp256k1::rand::{Rng, thread_rng};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+/// Restarts that are kept per worker, older ones are dropped. Failure budget should be less than that, failures are
+/// counted within kept restarts.
+pub const MAX_RESTART_HISTORY: usize = 100;
+
+/// How failed workers are restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Delay before the first restart, it doubles with every next failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Failures tolerated within `budget_period`, worker is reported failed and isn't restarted anymore after that.
+    pub failure_budget: u32,
+    pub budget_period: Duration,
+}
+
+impl RestartPolicy {
+    /// Delay before restart after `failures` recent failures: exponential backoff capped at `max_backoff`, randomly
+    /// shortened by up to a half, so workers failing together don't hammer the node at the same moment.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        let backoff = self.initial_backoff.saturating_mul(1 << exponent).min(self.max_backoff);
+
+        backoff.mul_f64(thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Worker failure that led to restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Restart {
+    /// Unix timestamp of the failure.
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// The latest restarts of a worker, from the oldest to the newest.
+#[derive(Debug, Default)]
+pub struct RestartHistory {
+    pub restarts: VecDeque<Restart>,
+    /// Restarts since start, dropped ones included.
+    pub total: usize,
+}
+
+impl RestartHistory {
+    pub fn record(&mut self, restart: Restart) {
+        if self.restarts.len() == MAX_RESTART_HISTORY {
+            self.restarts.pop_front();
+        }
+
+        self.restarts.push_back(restart);
+        self.total += 1;
+    }
+
+    /// Number of failures at or after `timestamp`.
+    pub fn failures_since(&self, timestamp: u64) -> u32 {
+        self.restarts.iter().rev().take_while(|restart| restart.timestamp >= timestamp).count() as u32
+    }
+}
+
+/// Runs worker and restarts it with jittered exponential backoff every time it fails. Worker that has finished its job,
+/// e.g. source that is exhausted, isn't restarted. Every failure is recorded to `history`, once failures within budget
+/// period exceed failure budget worker is reported as failed with the last error and isn't restarted anymore.
+///
+/// # Panics
+///
+/// Panics if can't acqure status or history write lock.
+pub async fn supervise<F>(
+    name: &str,
+    policy: RestartPolicy,
+    status: &RwLock<ServiceStatus>,
+    history: &RwLock<RestartHistory>,
+    metrics: &Metrics,
+    mut worker: impl FnMut() -> F,
+) where
+    F: Future<Output = Result<(), String>>,
+{
+    loop {
+        let Err(message) = worker().await else {
+            println!("Worker {name} has finished");
+            return;
+        };
+
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let failures = {
+            let mut history = history.write().unwrap();
+            history.record(Restart { timestamp, message: message.clone() });
+            history.failures_since(timestamp.saturating_sub(policy.budget_period.as_secs()))
+        };
+
+        if failures > policy.failure_budget {
+            println!(
+                "Worker {name} failed {failures} times within {}s, giving up: {message}",
+                policy.budget_period.as_secs()
+            );
+            *status.write().unwrap() = ServiceStatus::Failed { message };
+            return;
+        }
+
+        let backoff = policy.backoff(failures);
+        metrics.worker_restarts.with_label_values(&[name]).inc();
+        println!("Worker {name} failed: {message}, restarting in {}ms", backoff.as_millis());
+
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            failure_budget: 3,
+            budget_period: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_with_jitter() {
+        let policy = policy();
+
+        for (failures, expected) in [(1, 1), (2, 2), (4, 8), (7, 60), (100, 60)] {
+            let backoff = policy.backoff(failures);
+            let expected = Duration::from_secs(expected);
+
+            assert!(backoff >= expected / 2 && backoff <= expected, "{failures}: {backoff:?}");
+        }
+    }
+
+    #[test]
+    fn failures_are_counted_within_period() {
+        let mut history = RestartHistory::default();
+        for timestamp in [100, 200, 300] {
+            history.record(Restart { timestamp, message: String::new() });
+        }
+
+        assert_eq!(history.failures_since(150), 2);
+        assert_eq!(history.failures_since(0), 3);
+
+        for timestamp in 0..MAX_RESTART_HISTORY as u64 {
+            history.record(Restart { timestamp: 1_000 + timestamp, message: String::new() });
+        }
+
+        assert_eq!(history.restarts.len(), MAX_RESTART_HISTORY);
+        assert_eq!(history.restarts.front().unwrap().timestamp, 1_000);
+        assert_eq!(history.total, MAX_RESTART_HISTORY + 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn worker_is_restarted_until_budget_is_exhausted() {
+        let (status, history, metrics) = (RwLock::new(ServiceStatus::Running), RwLock::default(), Metrics::new());
+        let mut runs = 0;
+
+        supervise("fetcher", policy(), &status, &history, &metrics, || {
+            runs += 1;
+            std::future::ready(Err(format!("Failure {runs}")))
+        })
+        .await;
+
+        assert_eq!(runs, 4);
+        assert_eq!(history.read().unwrap().restarts.len(), 4);
+        assert_eq!(metrics.worker_restarts.with_label_values(&["fetcher"]).get(), 3);
+        assert!(matches!(&*status.read().unwrap(), ServiceStatus::Failed { message } if message == "Failure 4"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_largest_budget_is_exhausted() {
+        let (status, history, metrics) = (RwLock::new(ServiceStatus::Running), RwLock::default(), Metrics::new());
+        let policy = RestartPolicy { failure_budget: MAX_RESTART_HISTORY as u32 - 1, ..policy() };
+        let mut runs = 0;
+
+        supervise("fetcher", policy, &status, &history, &metrics, || {
+            runs += 1;
+            std::future::ready(Err(format!("Failure {runs}")))
+        })
+        .await;
+
+        assert_eq!(runs, MAX_RESTART_HISTORY);
+        assert!(matches!(&*status.read().unwrap(), ServiceStatus::Failed { .. }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finished_worker_is_not_restarted() {
+        let (status, history, metrics) = (RwLock::new(ServiceStatus::Running), RwLock::default(), Metrics::new());
+        let mut runs = 0;
+
+        supervise("fetcher", policy(), &status, &history, &metrics, || {
+            runs += 1;
+            std::future::ready(if runs < 2 { Err("Transient failure".to_string()) } else { Ok(()) })
+        })
+        .await;
+
+        assert_eq!(runs, 2);
+        assert_eq!(history.read().unwrap().restarts.len(), 1);
+        assert!(matches!(&*status.read().unwrap(), ServiceStatus::Running));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn worker_recovered_within_budget_keeps_running() {
+        let (status, history, metrics) = (RwLock::new(ServiceStatus::Running), RwLock::default(), Metrics::new());
+        let mut runs = 0;
+
+        let supervised = supervise("processor", policy(), &status, &history, &metrics, || {
+            runs += 1;
+            let transient = runs < 3;
+
+            async move {
+                if transient {
+                    return Err("Transient failure".to_string());
+                }
+
+                std::future::pending().await
+            }
+        });
+
+        assert!(tokio::time::timeout(Duration::from_secs(3600), supervised).await.is_err());
+        assert_eq!(history.read().unwrap().restarts.len(), 2);
+        assert!(matches!(&*status.read().unwrap(), ServiceStatus::Running));
+    }
+}
@@ -0,0 +1,258 @@
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use starknet::{
+    core::types::{EmittedEvent, Felt},
+    providers::Url,
+};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+};
+use tokio_tungstenite::{WebSocketStream, client_async, tungstenite::Message};
+
+trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+/// Notification pushed by node to subscriber.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    /// New block accepted on L2.
+    NewHead { block_number: u64, block_hash: Felt, parent_hash: Felt },
+    /// Event matching subscription filter.
+    Event(EmittedEvent),
+    /// Blocks starting from `block_number` were orphaned.
+    Reorg { block_number: u64 },
+}
+
+/// Subscription to new blocks and oracle events of Starknet node over WebSocket, as JSON-RPC specification describes
+/// it since v0.8.
+pub struct ChainSubscription {
+    socket: WebSocketStream<Box<dyn Socket>>,
+}
+
+impl ChainSubscription {
+    /// Connects to node and subscribes to new heads and to events of `address` with `selector` key. Both
+    /// subscriptions start at `from_block_number`, so blocks that were produced before subscribing are not missed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if node can't be connected or subscription requests can't be sent.
+    pub async fn connect(
+        url: &Url,
+        address: Felt,
+        selector: Felt,
+        from_block_number: u64,
+    ) -> Result<ChainSubscription, String> {
+        let (mut socket, _) = client_async(url.as_str(), connect(url).await?)
+            .await
+            .map_err(|e| format!("Can't connect to {url}: {e}"))?;
+
+        let block_id = json!({ "block_number": from_block_number });
+        let requests = [
+            ("starknet_subscribeNewHeads", json!({ "block_id": block_id })),
+            (
+                "starknet_subscribeEvents",
+                json!({ "from_address": address, "keys": [[selector]], "block_id": block_id }),
+            ),
+        ];
+
+        for (id, (method, params)) in requests.into_iter().enumerate() {
+            let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+            socket
+                .send(Message::Text(request.to_string().into()))
+                .await
+                .map_err(|e| format!("Can't subscribe: {e}"))?;
+        }
+
+        Ok(ChainSubscription { socket })
+    }
+
+    /// Next notification, subscription confirmations are skipped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if socket is closed, node responded with error or notification can't be
+    /// parsed.
+    pub async fn next(&mut self) -> Result<Notification, String> {
+        loop {
+            let message = match self.socket.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | None => return Err("Socket is closed".to_string()),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(format!("Socket failed: {e}")),
+            };
+
+            let message: Value = serde_json::from_str(&message).map_err(|e| format!("Invalid message: {e}"))?;
+            if let Some(error) = message.get("error") {
+                return Err(format!("Subscription failed: {error}"));
+            }
+
+            let result = &message["params"]["result"];
+            return match message["method"].as_str() {
+                Some("starknet_subscriptionNewHeads") => Ok(Notification::NewHead {
+                    block_number: number(&result["block_number"])?,
+                    block_hash: felt(&result["block_hash"])?,
+                    parent_hash: felt(&result["parent_hash"])?,
+                }),
+                Some("starknet_subscriptionEvents") => serde_json::from_value(result.clone())
+                    .map(Notification::Event)
+                    .map_err(|e| format!("Invalid event: {e}")),
+                Some("starknet_subscriptionReorg") => {
+                    Ok(Notification::Reorg { block_number: number(&result["starting_block_number"])? })
+                }
+                // Subscription confirmation
+                _ => continue,
+            };
+        }
+    }
+}
+
+/// Opens connection to the host of `url`, with TLS for `wss` scheme.
+async fn connect(url: &Url) -> Result<Box<dyn Socket>, String> {
+    let host = url.host_str().ok_or(format!("Url has no host: {url}"))?;
+    let port = url.port_or_known_default().ok_or(format!("Url has no port: {url}"))?;
+    let stream = TcpStream::connect((host, port)).await.map_err(|e| format!("Can't connect to {url}: {e}"))?;
+
+    if url.scheme() != "wss" {
+        return Ok(Box::new(stream));
+    }
+
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+
+    let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+    let server_name = ServerName::try_from(host).map_err(|_| format!("Invalid host: {host}"))?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| format!("Can't connect to {url}: {e}"))?;
+
+    Ok(Box::new(stream))
+}
+
+fn number(value: &Value) -> Result<u64, String> {
+    value.as_u64().ok_or(format!("Invalid number: {value}"))
+}
+
+fn felt(value: &Value) -> Result<Felt, String> {
+    value.as_str().and_then(|value| Felt::from_hex(value).ok()).ok_or(format!("Invalid felt: {value}"))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use axum::{
+        Router,
+        extract::{WebSocketUpgrade, ws},
+        routing::get,
+    };
+
+    pub fn head(block_number: u64, block_hash: u64, parent_hash: u64) -> Value {
+        let result = json!({
+            "block_number": block_number,
+            "block_hash": Felt::from(block_hash),
+            "parent_hash": Felt::from(parent_hash),
+        });
+
+        let params = json!({ "subscription_id": "1", "result": result });
+        json!({ "jsonrpc": "2.0", "method": "starknet_subscriptionNewHeads", "params": params })
+    }
+
+    /// Notification of event with `data` emitted in the block.
+    pub fn event(block_number: u64, block_hash: u64, data: &[Felt]) -> Value {
+        let result = json!({
+            "from_address": "0x1",
+            "keys": ["0x2"],
+            "data": data,
+            "block_hash": Felt::from(block_hash),
+            "block_number": block_number,
+            "transaction_hash": "0x3",
+        });
+
+        let params = json!({ "subscription_id": "2", "result": result });
+        json!({ "jsonrpc": "2.0", "method": "starknet_subscriptionEvents", "params": params })
+    }
+
+    /// Local WebSocket node that confirms both subscriptions, pushes `notifications` and closes the socket.
+    pub async fn node(notifications: Vec<Value>) -> Url {
+        serve(notifications, true).await
+    }
+
+    /// Same as `node`, but socket is left open without any more messages, the way half-open connection looks like.
+    pub async fn silent_node(notifications: Vec<Value>) -> Url {
+        serve(notifications, false).await
+    }
+
+    async fn serve(notifications: Vec<Value>, close: bool) -> Url {
+        let handler = move |upgrade: WebSocketUpgrade| async move {
+            upgrade.on_upgrade(move |mut socket| async move {
+                for id in 0..2 {
+                    let Some(Ok(ws::Message::Text(request))) = socket.recv().await else {
+                        return;
+                    };
+
+                    let request: Value = serde_json::from_str(&request).unwrap();
+                    assert_eq!(request["id"], id);
+                    assert_eq!(request["params"]["block_id"]["block_number"], 5);
+
+                    let response = json!({ "jsonrpc": "2.0", "id": id, "result": id.to_string() });
+                    socket.send(ws::Message::Text(response.to_string().into())).await.unwrap();
+                }
+
+                for notification in notifications {
+                    socket.send(ws::Message::Text(notification.to_string().into())).await.unwrap();
+                }
+
+                if close {
+                    socket.send(ws::Message::Close(None)).await.ok();
+                } else {
+                    std::future::pending::<()>().await;
+                }
+            })
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/ws", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, Router::new().route("/ws", get(handler))).await });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn notifications_are_parsed() {
+        let reorg = json!({
+            "jsonrpc": "2.0",
+            "method": "starknet_subscriptionReorg",
+            "params": { "subscription_id": "1", "result": { "starting_block_number": 6, "starting_block_hash": "0x6" } }
+        });
+        let url = node(vec![head(5, 0x5, 0x4), event(5, 0x5, &[Felt::ONE]), reorg]).await;
+
+        let mut subscription = ChainSubscription::connect(&url, Felt::ONE, Felt::TWO, 5).await.unwrap();
+
+        let head = Notification::NewHead { block_number: 5, block_hash: Felt::from(5), parent_hash: Felt::from(4) };
+        assert_eq!(subscription.next().await, Ok(head));
+        assert!(matches!(
+            subscription.next().await,
+            Ok(Notification::Event(event)) if event.block_number == Some(5) && event.data == vec![Felt::ONE]
+        ));
+        assert_eq!(subscription.next().await, Ok(Notification::Reorg { block_number: 6 }));
+        assert!(subscription.next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unreachable_node_is_reported() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        assert!(ChainSubscription::connect(&url, Felt::ONE, Felt::TWO, 5).await.is_err());
+    }
+}
@@ -1,67 +1,159 @@
-use crate::{ServiceStatus, configuration::ApplicationConfiguration, storage::SpotEntryEvent};
-use starknet::{
-    core::{
-        types::{BlockId, EventFilter, Felt, MaybePendingBlockWithTxHashes},
-        utils::starknet_keccak,
-    },
-    providers::{
-        Provider, Url,
-        jsonrpc::{HttpTransport, JsonRpcClient},
-    },
+use crate::{
+    backfill::backfill,
+    configuration::{ApplicationConfiguration, Finality, NetworkConfiguration},
+    metrics::{Metrics, fixed_point_to_f64},
+    provider::RpcProvider,
+    storage::{Checkpoint, EventBatch, SpotEntryEvent, TwapUpdate},
+    supervisor::supervise,
+    websocket::{ChainSubscription, Notification},
+};
+use starknet::core::types::{BlockId, BlockStatus, EmittedEvent, EventFilter, Felt};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
 };
-use std::sync::Arc;
 
-use std::time::{Duration, SystemTime};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{
+    Mutex,
+    mpsc::{UnboundedReceiver, UnboundedSender},
+};
 
-const BLOCKS_IN_1_HOUR: u8 = 120;
-const EVENT_CHUNK_SIZE: u64 = 1000;
+pub const EVENT_CHUNK_SIZE: u64 = 1000;
 const JSON_RPC_POLL_TIMEOUT: u64 = 15000;
-const ONE_HOUR: Duration = Duration::from_secs(3600);
+/// Block times without notifications after which subscription is considered dropped, e.g. on half-open connection.
+const SUBSCRIPTION_SILENCE_BLOCKS: u64 = 3;
 
-/// This worker connects to Starknet node using JSON-RPC and queries for events from Pragma price oracle and send
-/// batches to the channel it get as argument.
+/// This worker connects to Starknet nodes of configured network using JSON-RPC and queries for events from Pragma
+/// price oracle and send batches to the channel it get as argument. Only events for pairs from `pair_ids` are
+/// published. Scanning starts from the block that is roughly `window` behind the latest one, where `window` is the
+/// longest one among tracked pairs along with retention period, or from the block after `checkpoint` if it is more
+/// recent.
+///
+/// Blocks behind the chain head on start are backfilled in chunks fetched concurrently.
+///
+/// Hashes of processed blocks are tracked, and parent hash of every new block is checked against the last processed
+/// one. On mismatch chain was reorganised: worker looks for the latest tracked block that is still canonical, sends
+/// rollback of everything after it and scans again from there. With `AcceptedOnL1` finality only blocks verified on L1
+/// are scanned.
+///
+/// With WebSocket url configured, once worker has caught up with the chain it subscribes to new blocks instead of
+/// polling for them, and falls back to polling whenever subscription drops.
 ///
 /// # Errors
 ///
 /// This function will return an error if:
-/// - JSON RPC url is invalid.
 /// - In case of any RPC errors
 /// - If publishing channel is closed.
-async fn fetch_events(tx: UnboundedSender<Vec<SpotEntryEvent>>) -> Result<(), String> {
-    let starknet_sepolia_url: Url = Url::parse("https://starknet-sepolia.public.blastapi.io/rpc/v0_7")
-        .map_err(|_| "Fetcher can't parse Node Url")?;
-    let provider = JsonRpcClient::new(HttpTransport::new(starknet_sepolia_url));
+pub async fn fetch_events(
+    network: &NetworkConfiguration,
+    provider: &RpcProvider,
+    window: Duration,
+    checkpoint: Option<Checkpoint>,
+    pair_ids: HashSet<Felt>,
+    tx: UnboundedSender<EventBatch>,
+    metrics: &Metrics,
+) -> Result<(), String> {
+    let oracle_contract_address = Some(network.oracle_address);
+    let submitted_spot_entry_event_keys = vec![vec![network.event_selector]];
+
+    // Initial scanning parameters, we take latest finalised block and start as many blocks before as fit into window
+    let mut to_block_number = latest_block_number(provider, network.finality, 0, metrics).await?;
+
+    let blocks_in_window = window.as_secs().div_ceil(network.block_time);
+    let start_block_number = to_block_number.saturating_sub(blocks_in_window);
+
+    // Hashes of blocks that had events or were checkpoints, enough to find where canonical chain diverged.
+    let mut block_hashes: BTreeMap<u64, Felt> = BTreeMap::new();
+    let mut from_block_number = match checkpoint {
+        Some(checkpoint) if checkpoint.block_number >= start_block_number => {
+            block_hashes.insert(checkpoint.block_number, checkpoint.block_hash);
+            checkpoint.block_number + 1
+        }
+        _ => start_block_number,
+    };
 
-    let btc_usd_pair_id: Felt = Felt::from_bytes_be_slice("BTC/USD".as_bytes());
-    let oracle_contract_address =
-        Some(Felt::from_hex_unchecked("0x36031daa264c24520b11d93af622c848b2499b66b41d611bac95e13cfca131a"));
-    let submitted_spot_entry_event_keys = vec![vec![starknet_keccak("SubmittedSpotEntry".as_bytes())]];
+    let block = provider.get_block(from_block_number.min(to_block_number), metrics).await?;
+    let time_diff = SystemTime::now()
+        .checked_sub(Duration::from_secs(block.timestamp))
+        .ok_or("Can't calculate diff between current and block.timestamp")?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| "Can't calculate duration for till first block")?
+        .as_secs();
 
-    // Initial scanning parameters, we take latest finalised block and start 120 blocks before (30s per block is needed
-    // for production)
-    let mut to_block_number = provider.block_number().await.map_err(|_| "Can't fetch latest block number")?;
+    println!("Starting at block: {from_block_number:#?} with timestamp {time_diff:#?}s ago");
 
-    let mut from_block_number = to_block_number - u64::from(BLOCKS_IN_1_HOUR);
+    // Blocks behind the chain head are fetched in chunks concurrently, new ones are fetched as they come.
+    if from_block_number <= to_block_number {
+        let blocks = from_block_number..=to_block_number;
+        block_hashes.extend(backfill(network, provider, blocks, &pair_ids, &tx, metrics).await?);
 
-    let block = provider
-        .get_block_with_tx_hashes(BlockId::Number(from_block_number))
-        .await
-        .map_err(|_| "Can't get block with_tx_hashes")?;
+        let block_hash = provider.get_block(to_block_number, metrics).await?.block_hash;
+        block_hashes.insert(to_block_number, block_hash);
 
-    if let MaybePendingBlockWithTxHashes::Block(block) = block {
-        let time_diff = SystemTime::now()
-            .checked_sub(Duration::from_secs(block.timestamp))
-            .ok_or("Can't calculate diff between current and block.timestamp")?
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .map_err(|_| "Can't calculate duration for till first block")?
-            .as_secs();
+        let checkpoint = Some(Checkpoint { block_number: to_block_number, block_hash });
+        tx.send(EventBatch { events: Vec::new(), checkpoint, rollback: None, timestamp: None })
+            .map_err(|_| "Can't publish events to channel")?;
 
-        println!("Starting at block: {from_block_number:#?} with timestamp {time_diff:#?}s ago");
+        from_block_number = to_block_number + 1;
+        block_hashes = block_hashes.split_off(&to_block_number.saturating_sub(blocks_in_window));
     }
 
     let mut continuation_token = None;
     loop {
+        if from_block_number > to_block_number {
+            if network.ws_url.is_some() {
+                let (next_block_number, reason) = stream_events(
+                    network,
+                    blocks_in_window,
+                    from_block_number,
+                    &mut block_hashes,
+                    &pair_ids,
+                    &tx,
+                    metrics,
+                )
+                .await?;
+
+                println!("Subscription stopped at block {next_block_number}: {reason}, falling back to polling");
+                from_block_number = next_block_number;
+            }
+
+            tokio::time::sleep(Duration::from_millis(JSON_RPC_POLL_TIMEOUT)).await;
+
+            let latest_block_number = latest_block_number(provider, network.finality, to_block_number, metrics).await?;
+            if latest_block_number < from_block_number {
+                continue;
+            }
+
+            // Parent of the first new block should be the last processed one, otherwise chain was reorganised.
+            let parent_hash = provider.get_block(from_block_number, metrics).await?.parent_hash;
+            let last_processed_hash = from_block_number.checked_sub(1).and_then(|n| block_hashes.get(&n));
+
+            if last_processed_hash.is_some_and(|hash| *hash != parent_hash) {
+                let orphaned_block_number = match find_common_ancestor(provider, &block_hashes, metrics).await? {
+                    Some(block_number) => block_number + 1,
+                    // Chain diverged before any tracked block, so the whole window is scanned again.
+                    None => start_block_number,
+                };
+
+                println!("Chain reorganisation detected, rolling back blocks from {orphaned_block_number}");
+
+                block_hashes.split_off(&orphaned_block_number);
+                tx.send(EventBatch {
+                    events: Vec::new(),
+                    checkpoint: None,
+                    rollback: Some(orphaned_block_number),
+                    timestamp: None,
+                })
+                .map_err(|_| "Can't publish events to channel")?;
+
+                from_block_number = orphaned_block_number;
+            }
+
+            to_block_number = latest_block_number;
+            continue;
+        }
+
         let filter = EventFilter {
             address: oracle_contract_address,
             keys: Some(submitted_spot_entry_event_keys.clone()),
@@ -69,36 +161,218 @@ async fn fetch_events(tx: UnboundedSender<Vec<SpotEntryEvent>>) -> Result<(), St
             to_block: Some(BlockId::Number(to_block_number)),
         };
 
-        if from_block_number == to_block_number {
-            tokio::time::sleep(Duration::from_millis(JSON_RPC_POLL_TIMEOUT)).await;
-            to_block_number = provider.block_number().await.map_err(|_| "Can't fetch latest block number")?;
+        let event_page = provider.get_events(filter, continuation_token, EVENT_CHUNK_SIZE, metrics).await?;
+
+        for event in event_page.events.iter() {
+            if let (Some(block_number), Some(block_hash)) = (event.block_number, event.block_hash) {
+                block_hashes.insert(block_number, block_hash);
+            }
         }
 
-        let event_page = provider
-            .get_events(filter.clone(), continuation_token, EVENT_CHUNK_SIZE)
-            .await
-            .map_err(|_| "Can't fetch events")?;
+        let events = spot_entries(&event_page.events, &pair_ids);
 
-        let events: Vec<SpotEntryEvent> = event_page
-            .events
-            .iter()
-            .map(|event| SpotEntryEvent::try_from(event.data.as_slice()))
-            .filter_map(|res| res.ok())
-            .filter(|event| event.pair_id == btc_usd_pair_id)
-            .collect();
+        continuation_token = event_page.continuation_token;
 
-        tx.send(events).map_err(|_| "Can't publish events to channel")?;
+        let checkpoint = if continuation_token.is_none() {
+            let block_hash = provider.get_block(to_block_number, metrics).await?.block_hash;
+            block_hashes.insert(to_block_number, block_hash);
 
-        continuation_token = event_page.continuation_token;
+            Some(Checkpoint { block_number: to_block_number, block_hash })
+        } else {
+            None
+        };
+
+        tx.send(EventBatch { events, checkpoint, rollback: None, timestamp: None })
+            .map_err(|_| "Can't publish events to channel")?;
 
         if continuation_token.is_none() {
-            // advance blocks
-            from_block_number = to_block_number;
+            // advance blocks, hashes of blocks that are out of window are not needed anymore
+            from_block_number = to_block_number + 1;
+            block_hashes = block_hashes.split_off(&to_block_number.saturating_sub(blocks_in_window));
+        }
+    }
+}
+
+/// Ingests blocks node pushes over WebSocket subscription starting from `from_block_number`, until subscription drops,
+/// it is silent for `SUBSCRIPTION_SILENCE_BLOCKS` block times, a block is missed or chain is reorganised. Events are
+/// published as soon as they are received, and block is checkpointed once the next one arrives, so its events that
+/// arrive after its head are not missed. On return events of blocks that were not checkpointed are rolled back, polling
+/// fetches them again.
+///
+/// Returns the first block that is not checkpointed along with the reason subscription stopped.
+///
+/// # Errors
+///
+/// This function will return an error if publishing channel is closed.
+async fn stream_events(
+    network: &NetworkConfiguration,
+    blocks_in_window: u64,
+    from_block_number: u64,
+    block_hashes: &mut BTreeMap<u64, Felt>,
+    pair_ids: &HashSet<Felt>,
+    tx: &UnboundedSender<EventBatch>,
+    metrics: &Metrics,
+) -> Result<(u64, String), String> {
+    let Some(ws_url) = network.ws_url.as_ref() else {
+        return Ok((from_block_number, "WebSocket url is not set".to_string()));
+    };
+
+    let (address, selector) = (network.oracle_address, network.event_selector);
+    let mut subscription = match ChainSubscription::connect(ws_url, address, selector, from_block_number).await {
+        Ok(subscription) => subscription,
+        Err(message) => return Ok((from_block_number, message)),
+    };
+
+    println!("Subscribed to new blocks from {from_block_number} at {ws_url}");
+
+    let mut next_block_number = from_block_number;
+    // The latest block received, its events may still be arriving.
+    let mut head: Option<(u64, Felt)> = None;
+    let mut last_event_block_number: Option<u64> = None;
+    let silence = Duration::from_secs(network.block_time * SUBSCRIPTION_SILENCE_BLOCKS);
+
+    let reason = loop {
+        let notification = match tokio::time::timeout(silence, subscription.next()).await {
+            Ok(Ok(notification)) => notification,
+            Ok(Err(message)) => break message,
+            Err(_) => break format!("Socket is closed: no notifications for {}s", silence.as_secs()),
+        };
+
+        match notification {
+            Notification::Event(event) => {
+                // Events of checkpointed blocks were ingested already.
+                let Some(block_number) = event.block_number.filter(|block_number| *block_number >= next_block_number)
+                else {
+                    continue;
+                };
+
+                let events = spot_entries(&[event], pair_ids);
+                if events.is_empty() {
+                    continue;
+                }
+
+                last_event_block_number = Some(block_number);
+                tx.send(EventBatch { events, checkpoint: None, rollback: None, timestamp: None })
+                    .map_err(|_| "Can't publish events to channel")?;
+            }
+            Notification::NewHead { block_number, block_hash, parent_hash } => {
+                let expected_block_number = head.map_or(next_block_number, |(block_number, _)| block_number + 1);
+                if block_number != expected_block_number {
+                    break format!("Block {expected_block_number} is missed");
+                }
+
+                let last_hash = block_number.checked_sub(1).and_then(|block_number| block_hashes.get(&block_number));
+                if last_hash.is_some_and(|hash| *hash != parent_hash) {
+                    break "Chain reorganisation detected".to_string();
+                }
+
+                if let Some((block_number, block_hash)) = head {
+                    let checkpoint = Checkpoint { block_number, block_hash };
+                    tx.send(EventBatch {
+                        events: Vec::new(),
+                        checkpoint: Some(checkpoint),
+                        rollback: None,
+                        timestamp: None,
+                    })
+                    .map_err(|_| "Can't publish events to channel")?;
+
+                    next_block_number = block_number + 1;
+                    *block_hashes = block_hashes.split_off(&block_number.saturating_sub(blocks_in_window));
+                }
+
+                metrics.chain_head.set(block_number as i64);
+                block_hashes.insert(block_number, block_hash);
+                head = Some((block_number, block_hash));
+            }
+            Notification::Reorg { block_number } => break format!("Chain reorganisation from block {block_number}"),
+        }
+    };
+
+    block_hashes.split_off(&next_block_number);
+    if last_event_block_number.is_some_and(|block_number| block_number >= next_block_number) {
+        tx.send(EventBatch {
+            events: Vec::new(),
+            checkpoint: None,
+            rollback: Some(next_block_number),
+            timestamp: None,
+        })
+        .map_err(|_| "Can't publish events to channel")?;
+    }
+
+    Ok((next_block_number, reason))
+}
+
+/// Spot entries of oracle events that belong to tracked pairs, along with block they were emitted in.
+pub fn spot_entries(events: &[EmittedEvent], pair_ids: &HashSet<Felt>) -> Vec<SpotEntryEvent> {
+    events
+        .iter()
+        .map(|event| {
+            SpotEntryEvent::try_from(event.data.as_slice())
+                .map(|entry| SpotEntryEvent { block_number: event.block_number.unwrap_or_default(), ..entry })
+        })
+        .filter_map(|res| res.ok())
+        .filter(|event| pair_ids.contains(&event.pair_id))
+        .collect()
+}
+
+/// Latest block that satisfies finality. For `AcceptedOnL1` it is found with binary search over block statuses
+/// between `known_block_number`, that should be accepted on L1 already, and the latest block. Result is reported as
+/// chain head to metrics.
+async fn latest_block_number(
+    provider: &RpcProvider,
+    finality: Finality,
+    known_block_number: u64,
+    metrics: &Metrics,
+) -> Result<u64, String> {
+    let latest_block_number = provider.block_number(metrics).await?;
+
+    if finality == Finality::Latest {
+        metrics.chain_head.set(latest_block_number as i64);
+        return Ok(latest_block_number);
+    }
+
+    let is_accepted_on_l1 = async |block_number| {
+        provider.get_block(block_number, metrics).await.map(|b| b.status == BlockStatus::AcceptedOnL1)
+    };
+
+    if is_accepted_on_l1(latest_block_number).await? {
+        metrics.chain_head.set(latest_block_number as i64);
+        return Ok(latest_block_number);
+    }
+
+    let (mut low, mut high) = (known_block_number, latest_block_number);
+    while low + 1 < high {
+        let middle = low + (high - low) / 2;
+        if is_accepted_on_l1(middle).await? {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    metrics.chain_head.set(low as i64);
+    Ok(low)
+}
+
+/// Latest tracked block which hash still matches the one on chain.
+async fn find_common_ancestor(
+    provider: &RpcProvider,
+    block_hashes: &BTreeMap<u64, Felt>,
+    metrics: &Metrics,
+) -> Result<Option<u64>, String> {
+    for (block_number, block_hash) in block_hashes.iter().rev() {
+        if provider.get_block(*block_number, metrics).await?.block_hash == *block_hash {
+            return Ok(Some(*block_number));
         }
     }
+
+    Ok(None)
 }
 
-/// This worker receives events in batches store them into storage and trigger twap recalculations.
+/// This worker receives events in batches store them into storage of corresponding pair and trigger twap
+/// recalculations for every tracked pair. Events of orphaned blocks are rolled back before new ones are added. Events,
+/// signed twaps and block checkpoint are saved to persistence backend. Newly signed twaps are published to streaming
+/// clients.
 ///
 /// # Panics
 ///
@@ -106,56 +380,332 @@ async fn fetch_events(tx: UnboundedSender<Vec<SpotEntryEvent>>) -> Result<(), St
 ///
 /// # Errors
 ///
-/// This function will return an error if datetime calculations failed
+/// This function will return an error if:
+/// - Datetime calculations failed
+/// - Persistence backend failed
 async fn process_events(
     state: Arc<ApplicationConfiguration>,
-    mut rx: UnboundedReceiver<Vec<SpotEntryEvent>>,
+    rx: &mut UnboundedReceiver<EventBatch>,
 ) -> Result<(), String> {
     loop {
-        let hour_ago = SystemTime::now().checked_sub(ONE_HOUR).ok_or("Can't calculate now - hour")?;
+        let Some(batch) = rx.recv().await else {
+            return Err("Events channel is closed".to_string());
+        };
 
-        let duration_since_hour_ago =
-            hour_ago.duration_since(SystemTime::UNIX_EPOCH).map_err(|_| "Can't calculate duration")?;
+        // Replayed batches are processed at the time they were recorded at.
+        let now = match batch.timestamp {
+            Some(timestamp) => Duration::from_secs(timestamp),
+            None => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|_| "Can't calculate duration")?,
+        };
+        let oldest_window_start = now.checked_sub(state.history()).ok_or("Can't calculate window start")?.as_secs();
 
-        if let Some(events) = rx.recv().await {
+        let mut batches: HashMap<Felt, Vec<SpotEntryEvent>> = HashMap::new();
+        for event in batch.events {
+            batches.entry(event.pair_id).or_default().push(event);
+        }
+
+        for pair in state.pairs.iter() {
             // Storage changes in that block
-            let mut storage = state.storage.write().unwrap();
+            let mut storage = state.storage[&pair.id].write().unwrap();
+            if let Some(block_number) = batch.rollback {
+                storage.rollback(block_number);
+                state.persistence.rollback_events(pair.id, block_number)?;
+            }
+
+            let events = batches.remove(&pair.id).unwrap_or_default();
+            state.persistence.store_events(pair.id, &events)?;
+            state.metrics.events.with_label_values(&[&pair.name]).inc_by(events.len() as u64);
             for event in events {
                 storage.append(event);
             }
-            storage.clean_older_than(duration_since_hour_ago.as_secs());
-            storage.calculate_and_sign_twap(state.secret_key);
+            // Entries right before the oldest window are kept, they are its opening price.
+            if let Some(opening_timestamp) = storage.opening_timestamp(oldest_window_start) {
+                storage.clean_older_than(opening_timestamp.saturating_sub(1));
+                state.persistence.clean_events_older_than(pair.id, opening_timestamp.saturating_sub(1))?;
+            }
+
+            for window in pair.windows.iter() {
+                let window_label = window.as_secs().to_string();
+                let labels = [pair.name.as_str(), &window_label];
+                let sequence = storage.twaps.get(window).map(|signed| signed.attestation.sequence);
+                let was_insufficient = storage.insufficient.contains_key(window);
+
+                let started = Instant::now();
+                storage.calculate_and_sign_twap(*window, now.as_secs(), &state.signer);
+                state.metrics.twap_duration.with_label_values(&labels).observe(started.elapsed().as_secs_f64());
+
+                let rejections = storage.rejections.get(window).cloned().unwrap_or_default();
+                for (filter, count) in [
+                    ("mad", rejections.mad),
+                    ("jump", rejections.jump),
+                    ("publisher_deviation", rejections.publisher_deviation),
+                ] {
+                    let labels = [pair.name.as_str(), &window_label, filter];
+                    state.metrics.rejected.with_label_values(&labels).set(count as i64);
+                }
+
+                let Some(signed) = storage.twaps.get(window) else {
+                    continue;
+                };
+
+                // Only newly signed twap is saved, the stored one is the same otherwise.
+                let signed_anew = sequence != Some(signed.attestation.sequence);
+                if signed_anew {
+                    state.persistence.store_signed_twap(pair.id, *window, signed)?;
+
+                    let signature_labels = [pair.name.as_str(), &window_label, "latest"];
+                    state.metrics.signatures.with_label_values(&signature_labels).inc();
+                    state.metrics.twap.with_label_values(&labels).set(fixed_point_to_f64(&signed.attestation.twap));
+                }
+
+                // Clients learn that twap went stale right away, not with the next signed one.
+                let insufficient = storage.insufficient.get(window).cloned();
+                if signed_anew || was_insufficient != insufficient.is_some() {
+                    let update = TwapUpdate {
+                        pair_id: pair.id,
+                        window: *window,
+                        signed: signed.clone(),
+                        rejections,
+                        insufficient,
+                    };
+
+                    // Nobody is subscribed if sending failed, that is fine.
+                    let _ = state.updates.send(update);
+                }
+            }
+        }
+
+        if let Some(checkpoint) = batch.checkpoint {
+            state.persistence.store_checkpoint(&checkpoint)?;
+            state.metrics.processed_block.set(checkpoint.block_number as i64);
+            *state.checkpoint.write().unwrap() = Some(checkpoint);
         }
     }
 }
 
 pub trait WorkerRunner {
-    async fn start_fetcher(self, tx: UnboundedSender<Vec<SpotEntryEvent>>) -> Result<(), String>;
-    async fn start_processor(self, rx: UnboundedReceiver<Vec<SpotEntryEvent>>) -> Result<(), String>;
+    async fn start_fetcher(self, tx: UnboundedSender<EventBatch>) -> Result<(), String>;
+    async fn start_processor(self, rx: UnboundedReceiver<EventBatch>) -> Result<(), String>;
 }
 
 impl WorkerRunner for Arc<ApplicationConfiguration> {
-    async fn start_fetcher(self, tx: UnboundedSender<Vec<SpotEntryEvent>>) -> Result<(), String> {
-        let result = fetch_events(tx).await;
+    async fn start_fetcher(self, tx: UnboundedSender<EventBatch>) -> Result<(), String> {
+        let fetcher = || {
+            let (state, tx) = (self.clone(), tx.clone());
 
-        if let Err(message) = result {
-            *self.fetcher_status.write().unwrap() = ServiceStatus::Failed { message: message.to_string() };
-        } else {
-            *self.fetcher_status.write().unwrap() = ServiceStatus::Failed { message: "Unknown reason".to_string() };
+            async move {
+                // Fetching resumes after the last processed block, events that were fetched but not processed yet
+                // are fetched again, appending them twice is harmless.
+                let checkpoint = *state.checkpoint.read().unwrap();
+                state.source.publish(checkpoint, tx, &state.metrics).await
+            }
         };
 
+        let (status, restarts) = (&self.fetcher_status, &self.fetcher_restarts);
+        supervise("fetcher", self.restart_policy, status, restarts, &self.metrics, fetcher).await;
+
         Ok(())
     }
 
-    async fn start_processor(self, rx: UnboundedReceiver<Vec<SpotEntryEvent>>) -> Result<(), String> {
-        let result = process_events(self.clone(), rx).await;
-
-        if let Err(message) = result {
-            *self.processor_status.write().unwrap() = ServiceStatus::Failed { message: message.to_string() };
-        } else {
-            *self.processor_status.write().unwrap() = ServiceStatus::Failed { message: "Unknown reason".to_string() };
+    async fn start_processor(self, rx: UnboundedReceiver<EventBatch>) -> Result<(), String> {
+        // Receiver outlives restarts of the processor, batches that were not processed yet are not lost.
+        let rx = Arc::new(Mutex::new(rx));
+        let processor = || {
+            let (state, rx) = (self.clone(), rx.clone());
+            async move { process_events(state, &mut *rx.lock().await).await }
         };
 
+        let (status, restarts) = (&self.processor_status, &self.processor_restarts);
+        supervise("processor", self.restart_policy, status, restarts, &self.metrics, processor).await;
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aggregation::InsufficientData,
+        settings::Settings,
+        sources::test::synthetic,
+        websocket::test::{event, head, node, silent_node},
+    };
+    use num_bigint::BigUint;
+    use starknet::providers::Url;
+
+    fn network(ws_url: Url) -> NetworkConfiguration {
+        NetworkConfiguration { ws_url: Some(ws_url), ..NetworkConfiguration::mainnet() }
+    }
+
+    fn entry(block_number: u64, pair_id: Felt) -> Vec<Felt> {
+        vec![Felt::from(1_000 + block_number), Felt::ONE, Felt::TWO, Felt::from(100), pair_id]
+    }
+
+    #[tokio::test]
+    async fn streamed_blocks_are_checkpointed_once_next_head_arrives() {
+        let pair_id = Felt::from(7);
+        let url = node(vec![
+            head(5, 0x5, 0x4),
+            event(5, 0x5, &entry(5, pair_id)),
+            event(5, 0x5, &entry(5, Felt::from(8))),
+            head(6, 0x6, 0x5),
+            event(6, 0x6, &entry(6, pair_id)),
+        ])
+        .await;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut block_hashes = BTreeMap::from([(4, Felt::from(4))]);
+        let pair_ids = HashSet::from([pair_id]);
+        let metrics = Metrics::new();
+
+        let (next_block_number, reason) =
+            stream_events(&network(url), 10, 5, &mut block_hashes, &pair_ids, &tx, &metrics).await.unwrap();
+        assert_eq!((next_block_number, reason.as_str()), (6, "Socket is closed"));
+        assert_eq!(block_hashes, BTreeMap::from([(4, Felt::from(4)), (5, Felt::from(5))]));
+        assert_eq!(metrics.chain_head.get(), 6);
+
+        let spot_entry = |block_number| SpotEntryEvent {
+            timestamp: 1_000 + block_number,
+            source: Felt::ONE,
+            publisher: Felt::TWO,
+            price: 100,
+            pair_id,
+            block_number,
+            volume: 0,
+        };
+        let checkpoint = Checkpoint { block_number: 5, block_hash: Felt::from(5) };
+
+        drop(tx);
+        let mut batches = Vec::new();
+        while let Some(batch) = rx.recv().await {
+            batches.push(batch);
+        }
+
+        assert_eq!(
+            batches,
+            vec![
+                EventBatch { events: vec![spot_entry(5)], ..Default::default() },
+                EventBatch { checkpoint: Some(checkpoint), ..Default::default() },
+                EventBatch { events: vec![spot_entry(6)], ..Default::default() },
+                EventBatch { rollback: Some(6), ..Default::default() },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn silent_subscription_is_dropped() {
+        let pair_id = Felt::from(7);
+        let url = silent_node(vec![head(5, 0x5, 0x4), event(5, 0x5, &entry(5, pair_id))]).await;
+        let network = NetworkConfiguration { block_time: 1, ..network(url) };
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut block_hashes = BTreeMap::from([(4, Felt::from(4))]);
+        let pair_ids = HashSet::from([pair_id]);
+
+        let (next_block_number, reason) =
+            stream_events(&network, 10, 5, &mut block_hashes, &pair_ids, &tx, &Metrics::new()).await.unwrap();
+        assert_eq!((next_block_number, reason.as_str()), (5, "Socket is closed: no notifications for 3s"));
+
+        // Events of the block that wasn't checkpointed are rolled back, polling fetches them again
+        assert_eq!(rx.try_recv().unwrap().events.len(), 1);
+        assert_eq!(rx.try_recv().unwrap(), EventBatch { rollback: Some(5), ..Default::default() });
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn orphaned_head_stops_subscription() {
+        let url = node(vec![head(5, 0x5, 0x3), head(6, 0x6, 0x5)]).await;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut block_hashes = BTreeMap::from([(4, Felt::from(4))]);
+
+        let (next_block_number, reason) =
+            stream_events(&network(url), 10, 5, &mut block_hashes, &HashSet::new(), &tx, &Metrics::new())
+                .await
+                .unwrap();
+        assert_eq!((next_block_number, reason.as_str()), (5, "Chain reorganisation detected"));
+        assert_eq!(block_hashes, BTreeMap::from([(4, Felt::from(4))]));
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// Twaps of every window signed after every block of synthetic source with `seed` was processed.
+    async fn process_synthetic(seed: u64) -> Vec<BigUint> {
+        let settings = Settings::from_toml(r#"pairs = [{ name = "BTC/USD", windows = ["5m", "30m"] }]"#).unwrap();
+        let state = ApplicationConfiguration::new(settings).unwrap();
+        let pair_id = state.pairs[0].id;
+        let state = Arc::new(ApplicationConfiguration { source: Box::new(synthetic(vec![pair_id], seed)), ..state });
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let processor = tokio::spawn(state.clone().start_processor(rx));
+        state.clone().start_fetcher(tx).await.unwrap();
+
+        let processed = async {
+            while state.checkpoint.read().unwrap().map(|checkpoint| checkpoint.block_number) != Some(99) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), processed).await.unwrap();
+        processor.abort();
+
+        assert!(state.fetcher_restarts.read().unwrap().restarts.is_empty());
+
+        let storage = state.storage[&pair_id].read().unwrap();
+        state.pairs[0]
+            .windows
+            .iter()
+            .map(|window| {
+                let attestation = &storage.twaps[window].attestation;
+                // Batches are processed at time of their blocks
+                assert_eq!(attestation.window_end, 100_000 + 99 * 30);
+                attestation.twap.clone()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn synthetic_source_is_processed_deterministically() {
+        let twaps = process_synthetic(42).await;
+
+        assert_eq!(twaps.len(), 2);
+        assert_eq!(twaps, process_synthetic(42).await);
+        assert_ne!(twaps, process_synthetic(43).await);
+    }
+
+    #[tokio::test]
+    async fn window_turning_insufficient_is_published() {
+        let settings = Settings::from_toml(
+            r#"
+            pairs = [{ name = "BTC/USD", windows = ["5m"] }]
+            quality = { min_samples = 2 }
+            "#,
+        )
+        .unwrap();
+        let state = Arc::new(ApplicationConfiguration::new(settings).unwrap());
+        let pair_id = state.pairs[0].id;
+        let mut updates = state.updates.subscribe();
+
+        let now = 100_000;
+        let events = [now - 10, now - 5]
+            .map(|timestamp| SpotEntryEvent { timestamp, price: 100, pair_id, ..Default::default() })
+            .to_vec();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tx.send(EventBatch { events, timestamp: Some(now), ..Default::default() }).unwrap();
+        // Window has no entries anymore, then it stays so
+        tx.send(EventBatch { timestamp: Some(now + 600), ..Default::default() }).unwrap();
+        tx.send(EventBatch { timestamp: Some(now + 601), ..Default::default() }).unwrap();
+        drop(tx);
+
+        assert_eq!(process_events(state.clone(), &mut rx).await, Err("Events channel is closed".to_string()));
+
+        let update = updates.try_recv().unwrap();
+        assert_eq!((update.signed.attestation.sequence, update.insufficient), (1, None));
+
+        let update = updates.try_recv().unwrap();
+        let insufficient = Some(InsufficientData::Samples { samples: 0, min: 2 });
+        assert_eq!((update.signed.attestation.sequence, update.insufficient), (1, insufficient));
+
+        assert!(updates.try_recv().is_err());
+    }
+}